@@ -0,0 +1,279 @@
+// Staff roles and who may read a patient's record
+use crate::break_glass::has_emergency_access;
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::{check_required, Validator, MAX_NAME_LENGTH};
+use crate::{
+    ensure_admin, get_doctor, Error, DIAGNOSIS_STORAGE, DOCTOR_STORAGE, PORTAL_LINKS, STAFF,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, is_controller, time};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//What a member of staff does, which decides the records they can read
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum StaffRole {
+    Clinician { doctor_id: u64 }, //Reads the records of the patients they treat
+    Receptionist,                 //Registers patients and reads every record
+    BillingClerk,                 //Reads every record, for billing and insurance
+    ComplianceOfficer,            //Reviews emergency access, reads no records
+    Interface,                    //Lab analyser or admission system sending HL7 messages
+}
+
+//Define our StaffMember struct
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StaffMember {
+    principal: Principal,
+    name: String,
+    role: StaffRole,
+    registered_on: u64,
+}
+
+impl Storable for StaffMember {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StaffMember {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Represents payload for registering a member of staff
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct StaffPayload {
+    name: String,
+    role: StaffRole,
+}
+
+//Lets a principal be used as a stable map key
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct PrincipalKey(pub(crate) Principal);
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+pub(crate) fn staff_role(principal: Principal) -> Option<StaffRole> {
+    STAFF
+        .with(|staff| staff.borrow().get(&PrincipalKey(principal)))
+        .map(|member| member.role)
+}
+
+//A doctor treats a patient while the patient is assigned to them or once they diagnosed them
+fn treats(doctor_id: u64, patient_id: u64) -> bool {
+    let is_current_patient = DOCTOR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&doctor_id)
+            .is_some_and(|doctor| doctor.current_patient == patient_id)
+    });
+
+    is_current_patient
+        || DIAGNOSIS_STORAGE.with(|storage| {
+            storage.borrow().iter().any(|(_, diagnosis)| {
+                diagnosis.doctor_id == doctor_id && diagnosis.patient_id == patient_id
+            })
+        })
+}
+
+//Tells whether the principal may read the record of the patient
+pub(crate) fn can_read_patient(principal: Principal, patient_id: u64) -> bool {
+    if is_controller(&principal) {
+        return true;
+    }
+
+    match staff_role(principal) {
+        Some(StaffRole::Receptionist | StaffRole::BillingClerk) => true,
+        Some(StaffRole::Clinician { doctor_id }) => {
+            treats(doctor_id, patient_id) || has_emergency_access(principal, patient_id)
+        }
+        Some(StaffRole::ComplianceOfficer | StaffRole::Interface) | None => false,
+    }
+}
+
+//Refuses to go on unless the caller may read the record of the patient
+pub(crate) fn ensure_can_read_patient(patient_id: u64) -> Result<(), Error> {
+    if !can_read_patient(caller(), patient_id) {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "You may not read the record of patient with ID {}",
+                patient_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+//Refuses to go on unless the caller may change the record of the patient: reception, or a
+//clinician treating the patient
+pub(crate) fn ensure_can_write_patient(patient_id: u64) -> Result<(), Error> {
+    let caller = caller();
+    if is_controller(&caller) {
+        return Ok(());
+    }
+
+    let can_write = match staff_role(caller) {
+        Some(StaffRole::Receptionist) => true,
+        Some(StaffRole::Clinician { doctor_id }) => treats(doctor_id, patient_id),
+        _ => false,
+    };
+    if !can_write {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "You may not change the record of patient with ID {}",
+                patient_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+//Only reception and admins register, archive and assign patients
+pub(crate) fn ensure_receptionist() -> Result<(), Error> {
+    let caller = caller();
+    if is_controller(&caller) || staff_role(caller) == Some(StaffRole::Receptionist) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: "Only reception can perform this action".to_string(),
+    })
+}
+
+//Only the doctor themselves, as a clinician, and admins record a diagnosis in a doctor's name
+pub(crate) fn ensure_can_diagnose(doctor_id: u64) -> Result<(), Error> {
+    let caller = caller();
+    if is_controller(&caller) || staff_role(caller) == Some(StaffRole::Clinician { doctor_id }) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: format!(
+            "Only the doctor with ID {} can record a diagnosis in their name",
+            doctor_id
+        ),
+    })
+}
+
+//Tells whether the principal is enrolled in the patient portal as the patient
+pub(crate) fn is_patient(principal: Principal, patient_id: u64) -> bool {
+    PORTAL_LINKS.with(|links| links.borrow().get(&PrincipalKey(principal))) == Some(patient_id)
+}
+
+//Refuses to go on unless the caller may record or revoke the patient's consents: reception,
+//a clinician treating the patient, or the patient themselves through the portal
+pub(crate) fn ensure_can_manage_consents(patient_id: u64) -> Result<(), Error> {
+    let caller = caller();
+    if is_controller(&caller) || is_patient(caller, patient_id) {
+        return Ok(());
+    }
+
+    let can_manage = match staff_role(caller) {
+        Some(StaffRole::Receptionist) => true,
+        Some(StaffRole::Clinician { doctor_id }) => treats(doctor_id, patient_id),
+        _ => false,
+    };
+    if !can_manage {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "You may not manage the consents of patient with ID {}",
+                patient_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+//Registers a member of staff, or changes their role
+#[ic_cdk::update]
+fn register_staff(principal: Principal, payload: StaffPayload) -> Result<StaffMember, Error> {
+    observe("register_staff", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        Validator::default()
+            .check("name", check_required(&payload.name))
+            .limit("name", &payload.name, MAX_NAME_LENGTH)
+            .finish()?;
+        if let StaffRole::Clinician { doctor_id } = payload.role {
+            let _doctor = get_doctor(doctor_id)?;
+        }
+
+        let member = StaffMember {
+            principal,
+            name: payload.name,
+            role: payload.role,
+            registered_on: time(),
+        };
+        STAFF.with(|staff| {
+            staff
+                .borrow_mut()
+                .insert(PrincipalKey(principal), member.clone())
+        });
+        Ok(member)
+    })
+}
+
+//Removes a member of staff, taking away the access their role gave them
+#[ic_cdk::update]
+fn remove_staff(principal: Principal) -> Result<(), Error> {
+    observe("remove_staff", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        STAFF
+            .with(|staff| staff.borrow_mut().remove(&PrincipalKey(principal)))
+            .map(|_| ())
+            .ok_or(Error::NotFound {
+                msg: format!("Staff member {} not found", principal),
+            })
+    })
+}
+
+//Lists every registered member of staff
+#[ic_cdk::query]
+fn get_staff() -> Result<Vec<StaffMember>, Error> {
+    ensure_admin()?;
+
+    Ok(STAFF.with(|staff| staff.borrow().iter().map(|(_, member)| member).collect()))
+}
+
+//Retrieves the role of the caller, if they are a member of staff
+#[ic_cdk::query]
+fn get_my_staff_role() -> Option<StaffRole> {
+    staff_role(caller())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_staff_member_with_the_longest_name_fits_when_stored() {
+        let member = StaffMember {
+            principal: Principal::from_slice(&[0xff; 29]),
+            name: "x".repeat(MAX_NAME_LENGTH),
+            role: StaffRole::Clinician {
+                doctor_id: u64::MAX,
+            },
+            registered_on: u64::MAX,
+        };
+        assert!(member.to_bytes().len() <= StaffMember::MAX_SIZE as usize);
+    }
+}
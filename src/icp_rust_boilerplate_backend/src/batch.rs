@@ -0,0 +1,335 @@
+// Batches of operations that run in one message and are rolled back together.
+//
+// The IC only rolls back the state changes of a message when it traps, so a batch whose
+// operation returns an Error traps with that error instead of returning it. Everything that can
+// be checked before anything runs (the payloads, the references, that the records referred to by
+// ID exist and that the caller may change them) is checked first, and those errors are returned
+// as usual. Only a failure that depends on an earlier operation of the batch, such as assigning a
+// doctor who was just given a patient, traps
+use crate::access::{ensure_can_diagnose, ensure_can_write_patient, ensure_receptionist};
+use crate::contacts::{add_contact, RelatedPerson, RelatedPersonPayload};
+use crate::duplicates::PatientRegistration;
+use crate::identifiers::{
+    add_patient_identifier, identifier_owner, validate_identifier_payload, ExternalIdentifier,
+    IdentifierKey, IdentifierPayload,
+};
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::{
+    invalid_field, nest_fields, validate_clinical_text, validate_contact_payload,
+    validate_doctor_payload, validate_patient_payload, validate_room_payload,
+};
+use crate::{
+    add_diagnosis, add_doctor, add_patient, add_room, assign_doctor_a_room,
+    assign_patient_a_doctor, ensure_admin, get_doctor, get_patient, get_room, Diagnosis,
+    DiagnosisPayload, Doctor, DoctorPayLoad, Error, PatientPayLoad, Room, RoomPayload,
+};
+use candid::CandidType;
+use std::collections::BTreeSet;
+
+//Most operations accepted in one batch, so that a batch fits in the instruction limit
+const MAX_OPERATIONS: usize = 50;
+
+//Points at a record that exists, or at the one created by an earlier operation of the batch
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize)]
+pub(crate) enum Reference {
+    Id(u64),
+    Operation(u32), //Index of the operation in the batch, from 0
+}
+
+//A diagnosis whose patient and doctor may be created by the same batch
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct BatchDiagnosisPayload {
+    patient: Reference,
+    doctor: Reference,
+    treatment: String,
+    medication: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum BatchOperation {
+    AddPatient(PatientPayLoad),
+    AddDoctor(DoctorPayLoad),
+    AddRoom(RoomPayload),
+    AddDiagnosis(BatchDiagnosisPayload),
+    AssignPatientADoctor {
+        patient: Reference,
+        doctor: Reference,
+    },
+    AssignDoctorARoom {
+        doctor: Reference,
+        room: Reference,
+    },
+    AddContact {
+        patient: Reference,
+        contact: RelatedPersonPayload,
+    },
+    AddPatientIdentifier {
+        patient: Reference,
+        identifier: IdentifierPayload,
+    },
+}
+
+//What each operation of a batch returned, in the order of the operations
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) enum BatchResult {
+    Patient(Box<PatientRegistration>),
+    Doctor(Doctor),
+    Room(Room),
+    Diagnosis(Diagnosis),
+    Assigned,
+    Contact(RelatedPerson),
+    Identifier(ExternalIdentifier),
+}
+
+//The kinds of records an operation can be referenced for
+#[derive(Clone, Copy, PartialEq)]
+enum Creates {
+    Patient,
+    Doctor,
+    Room,
+    Nothing,
+}
+
+impl BatchOperation {
+    fn creates(&self) -> Creates {
+        match self {
+            BatchOperation::AddPatient(_) => Creates::Patient,
+            BatchOperation::AddDoctor(_) => Creates::Doctor,
+            BatchOperation::AddRoom(_) => Creates::Room,
+            _ => Creates::Nothing,
+        }
+    }
+
+    //The references of the operation, with the kind of record each must point at
+    fn references(&self) -> Vec<(&'static str, Reference, Creates)> {
+        match self {
+            BatchOperation::AddPatient(_)
+            | BatchOperation::AddDoctor(_)
+            | BatchOperation::AddRoom(_) => Vec::new(),
+            BatchOperation::AddDiagnosis(payload) => vec![
+                ("patient", payload.patient, Creates::Patient),
+                ("doctor", payload.doctor, Creates::Doctor),
+            ],
+            BatchOperation::AssignPatientADoctor { patient, doctor } => vec![
+                ("patient", *patient, Creates::Patient),
+                ("doctor", *doctor, Creates::Doctor),
+            ],
+            BatchOperation::AssignDoctorARoom { doctor, room } => vec![
+                ("doctor", *doctor, Creates::Doctor),
+                ("room", *room, Creates::Room),
+            ],
+            BatchOperation::AddContact { patient, .. }
+            | BatchOperation::AddPatientIdentifier { patient, .. } => {
+                vec![("patient", *patient, Creates::Patient)]
+            }
+        }
+    }
+}
+
+impl BatchResult {
+    //The ID of the record the operation created, for the operations that can be referenced
+    fn created_id(&self) -> Option<u64> {
+        match self {
+            BatchResult::Patient(registration) => Some(registration.patient_id()),
+            BatchResult::Doctor(doctor) => Some(doctor.id),
+            BatchResult::Room(room) => Some(room.id),
+            _ => None,
+        }
+    }
+}
+
+//Checks that every reference points at an earlier operation creating the right kind of record
+fn validate_references(operations: &[BatchOperation]) -> Result<(), Error> {
+    for (index, operation) in operations.iter().enumerate() {
+        for (field, reference, expected) in operation.references() {
+            let Reference::Operation(target) = reference else {
+                continue;
+            };
+            let target = target as usize;
+            if target >= index {
+                return Err(invalid_field(
+                    &format!("operations[{}].{}", index, field),
+                    "Must refer to an earlier operation",
+                ));
+            }
+            if operations[target].creates() != expected {
+                return Err(invalid_field(
+                    &format!("operations[{}].{}", index, field),
+                    &format!("Operation {} does not create a {}", target, field),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+//Checks that a record referred to by ID exists
+fn ensure_exists(field: &str, id: u64, kind: Creates) -> Result<(), Error> {
+    let exists = match kind {
+        Creates::Patient => get_patient(id).is_ok(),
+        Creates::Doctor => get_doctor(id).is_ok(),
+        Creates::Room => get_room(id).is_ok(),
+        Creates::Nothing => true,
+    };
+    if exists {
+        return Ok(());
+    }
+    Err(invalid_field(
+        field,
+        &format!("There is no {} with ID {}", field, id),
+    ))
+}
+
+//Patients created by the batch are added by reception, who may change any patient
+fn ensure_can_write(patient: Reference) -> Result<(), Error> {
+    match patient {
+        Reference::Id(id) => ensure_can_write_patient(get_patient(id)?.id),
+        Reference::Operation(_) => Ok(()),
+    }
+}
+
+//Runs the checks of the endpoint behind the operation that do not depend on earlier operations.
+//Identifiers already linked by the batch are collected, as they must be unique too
+fn validate_operation(
+    operation: &BatchOperation,
+    identifiers: &mut BTreeSet<IdentifierKey>,
+) -> Result<(), Error> {
+    for (field, reference, kind) in operation.references() {
+        if let Reference::Id(id) = reference {
+            ensure_exists(field, id, kind)?;
+        }
+    }
+
+    match operation {
+        BatchOperation::AddPatient(payload) => {
+            ensure_receptionist()?;
+            validate_patient_payload(payload)
+        }
+        BatchOperation::AddDoctor(payload) => {
+            ensure_receptionist()?;
+            validate_doctor_payload(payload)
+        }
+        BatchOperation::AddRoom(payload) => {
+            ensure_receptionist()?;
+            validate_room_payload(payload)
+        }
+        BatchOperation::AddDiagnosis(payload) => {
+            match payload.doctor {
+                Reference::Id(doctor_id) => ensure_can_diagnose(doctor_id)?,
+                //A doctor added by the batch is not yet linked to a clinician
+                Reference::Operation(_) => ensure_admin()?,
+            }
+            validate_clinical_text(&payload.treatment, &payload.medication)
+        }
+        BatchOperation::AssignPatientADoctor { .. } | BatchOperation::AssignDoctorARoom { .. } => {
+            ensure_receptionist()
+        }
+        BatchOperation::AddContact { patient, contact } => {
+            ensure_can_write(*patient)?;
+            validate_contact_payload(contact)
+        }
+        BatchOperation::AddPatientIdentifier {
+            patient,
+            identifier,
+        } => {
+            ensure_can_write(*patient)?;
+            validate_identifier_payload(identifier)?;
+            let key = identifier.key();
+            if identifier_owner(&key).is_some() || !identifiers.insert(key) {
+                return Err(invalid_field(
+                    "identifier.value",
+                    "This identifier is already linked to a patient",
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn validate_operations(operations: &[BatchOperation]) -> Result<(), Error> {
+    let mut identifiers = BTreeSet::new();
+    for (index, operation) in operations.iter().enumerate() {
+        validate_operation(operation, &mut identifiers)
+            .map_err(|error| nest_fields(&format!("operations[{}]", index), error))?;
+    }
+    Ok(())
+}
+
+fn resolve(reference: Reference, results: &[BatchResult]) -> u64 {
+    match reference {
+        Reference::Id(id) => id,
+        Reference::Operation(index) => results[index as usize]
+            .created_id()
+            .expect("References are validated before the batch runs"),
+    }
+}
+
+fn run(operation: BatchOperation, results: &[BatchResult]) -> Result<BatchResult, Error> {
+    match operation {
+        BatchOperation::AddPatient(payload) => add_patient(payload, None)
+            .map(|registration| BatchResult::Patient(Box::new(registration))),
+        BatchOperation::AddDoctor(payload) => add_doctor(payload, None).map(BatchResult::Doctor),
+        BatchOperation::AddRoom(payload) => add_room(payload, None).map(BatchResult::Room),
+        BatchOperation::AddDiagnosis(payload) => {
+            let payload = DiagnosisPayload {
+                patient_id: resolve(payload.patient, results),
+                doctor_id: resolve(payload.doctor, results),
+                treatment: payload.treatment,
+                medication: payload.medication,
+            };
+            add_diagnosis(payload, None).map(BatchResult::Diagnosis)
+        }
+        BatchOperation::AssignPatientADoctor { patient, doctor } => {
+            assign_patient_a_doctor(resolve(patient, results), resolve(doctor, results))
+                .map(|_| BatchResult::Assigned)
+        }
+        BatchOperation::AssignDoctorARoom { doctor, room } => {
+            assign_doctor_a_room(resolve(doctor, results), resolve(room, results))
+                .map(|_| BatchResult::Assigned)
+        }
+        BatchOperation::AddContact { patient, contact } => {
+            add_contact(resolve(patient, results), contact).map(BatchResult::Contact)
+        }
+        BatchOperation::AddPatientIdentifier {
+            patient,
+            identifier,
+        } => add_patient_identifier(resolve(patient, results), identifier)
+            .map(BatchResult::Identifier),
+    }
+}
+
+//Runs the operations in order. Invalid operations are reported before anything runs. If an
+//operation still fails, every change of the batch is rolled back and the call is rejected with the
+//failing operation's error
+#[ic_cdk::update]
+fn batch(operations: Vec<BatchOperation>) -> Result<Vec<BatchResult>, Error> {
+    observe("batch", || {
+        ensure_writes_allowed()?;
+
+        if operations.is_empty() || operations.len() > MAX_OPERATIONS {
+            return Err(invalid_field(
+                "operations",
+                &format!(
+                    "A batch must have between 1 and {} operations",
+                    MAX_OPERATIONS
+                ),
+            ));
+        }
+        validate_references(&operations)?;
+        validate_operations(&operations)?;
+
+        let mut results = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.into_iter().enumerate() {
+            match run(operation, &results) {
+                Ok(result) => results.push(result),
+                Err(error) => ic_cdk::trap(&format!(
+                    "Operation {} failed, so the batch was rolled back: {}",
+                    index,
+                    error.message()
+                )),
+            }
+        }
+        Ok(results)
+    })
+}
@@ -0,0 +1,238 @@
+// Emergency ("break-glass") access to records a clinician may not normally read
+use crate::access::{staff_role, StaffRole};
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::{check_length, check_required, invalid_field, Validator};
+use crate::{
+    ensure_admin, get_patient, next_id, Error, BREAK_GLASS_ACCESS, BREAK_GLASS_DURATION,
+    BREAK_GLASS_ID_COUNTER,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, is_controller, time};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//Shortest reason accepted for breaking the glass
+const MIN_REASON_LENGTH: usize = 10;
+
+//Longest reason and review note, in bytes. Both together still fit in BreakGlassAccess::MAX_SIZE
+const MAX_REASON_LENGTH: usize = 1000;
+const MAX_NOTE_LENGTH: usize = 500;
+
+//Records who reviewed an emergency access and what they concluded
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Acknowledgement {
+    acknowledged_by: Principal,
+    acknowledged_on: u64,
+    note: String,
+}
+
+//Temporary read access to one patient, kept as an alert until compliance acknowledges it
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BreakGlassAccess {
+    id: u64,
+    principal: Principal,
+    patient_id: u64,
+    reason: String,
+    granted_on: u64,
+    expires_on: u64,
+    acknowledged: Option<Acknowledgement>,
+}
+
+impl Storable for BreakGlassAccess {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BreakGlassAccess {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Tells whether the principal currently has emergency access to the patient
+pub(crate) fn has_emergency_access(principal: Principal, patient_id: u64) -> bool {
+    let now = time();
+    BREAK_GLASS_ACCESS.with(|access| {
+        access.borrow().iter().any(|(_, access)| {
+            access.principal == principal
+                && access.patient_id == patient_id
+                && now < access.expires_on
+        })
+    })
+}
+
+//Every emergency access that was given to the patient's record
+pub(crate) fn accesses_to(patient_id: u64) -> Vec<BreakGlassAccess> {
+    BREAK_GLASS_ACCESS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, access)| access)
+            .filter(|access| access.patient_id == patient_id)
+            .collect()
+    })
+}
+
+//Only compliance officers and admins review emergency access
+fn ensure_compliance_officer() -> Result<(), Error> {
+    let caller = caller();
+    if is_controller(&caller) || staff_role(caller) == Some(StaffRole::ComplianceOfficer) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: "Only a compliance officer can review emergency access".to_string(),
+    })
+}
+
+fn check_reason(reason: &str) -> Result<(), String> {
+    check_required(reason)?;
+    if reason.trim().chars().count() < MIN_REASON_LENGTH {
+        return Err(format!(
+            "The reason must be at least {} characters long",
+            MIN_REASON_LENGTH
+        ));
+    }
+    check_length(reason, MAX_REASON_LENGTH)
+}
+
+//Gives the calling clinician temporary read access to a patient in an emergency
+#[ic_cdk::update]
+fn break_glass(patient_id: u64, reason: String) -> Result<BreakGlassAccess, Error> {
+    observe("break_glass", || {
+        ensure_writes_allowed()?;
+
+        let caller = caller();
+        let Some(StaffRole::Clinician { .. }) = staff_role(caller) else {
+            return Err(Error::Unauthorized {
+                msg: "Only a clinician can request emergency access".to_string(),
+            });
+        };
+        Validator::default()
+            .check("reason", check_reason(&reason))
+            .finish()?;
+
+        let patient = get_patient(patient_id)?;
+
+        let granted_on = time();
+        let duration = BREAK_GLASS_DURATION.with(|duration| *duration.borrow().get());
+        let access = BreakGlassAccess {
+            id: next_id(&BREAK_GLASS_ID_COUNTER),
+            principal: caller,
+            patient_id: patient.id,
+            reason: reason.trim().to_string(),
+            granted_on,
+            expires_on: granted_on.saturating_add(duration),
+            acknowledged: None,
+        };
+
+        BREAK_GLASS_ACCESS.with(|storage| storage.borrow_mut().insert(access.id, access.clone()));
+        Ok(access)
+    })
+}
+
+//Lists emergency accesses for review, oldest first
+#[ic_cdk::query]
+fn get_break_glass_alerts(include_acknowledged: bool) -> Result<Vec<BreakGlassAccess>, Error> {
+    ensure_compliance_officer()?;
+
+    Ok(BREAK_GLASS_ACCESS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, access)| access)
+            .filter(|access| include_acknowledged || access.acknowledged.is_none())
+            .collect()
+    }))
+}
+
+//Marks an emergency access as reviewed
+#[ic_cdk::update]
+fn acknowledge_break_glass(id: u64, note: String) -> Result<BreakGlassAccess, Error> {
+    observe("acknowledge_break_glass", || {
+        ensure_writes_allowed()?;
+        ensure_compliance_officer()?;
+
+        let mut access = BREAK_GLASS_ACCESS
+            .with(|storage| storage.borrow().get(&id))
+            .ok_or(Error::NotFound {
+                msg: format!("Emergency access with ID {} not found", id),
+            })?;
+        if access.acknowledged.is_some() {
+            return Err(invalid_field(
+                "id",
+                "This emergency access has already been acknowledged",
+            ));
+        }
+        Validator::default()
+            .limit("note", &note, MAX_NOTE_LENGTH)
+            .finish()?;
+
+        access.acknowledged = Some(Acknowledgement {
+            acknowledged_by: caller(),
+            acknowledged_on: time(),
+            note,
+        });
+        BREAK_GLASS_ACCESS.with(|storage| storage.borrow_mut().insert(id, access.clone()));
+        Ok(access)
+    })
+}
+
+//Sets how long emergency access lasts, in nanoseconds
+#[ic_cdk::update]
+fn set_break_glass_duration(duration: u64) -> Result<(), Error> {
+    observe("set_break_glass_duration", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        if duration == 0 {
+            return Err(invalid_field(
+                "duration",
+                "Emergency access must last longer than 0 nanoseconds",
+            ));
+        }
+        BREAK_GLASS_DURATION
+            .with(|cell| cell.borrow_mut().set(duration))
+            .expect("Cannot set the emergency access duration");
+        Ok(())
+    })
+}
+
+//Retrieves how long emergency access lasts, in nanoseconds
+#[ic_cdk::query]
+fn get_break_glass_duration() -> u64 {
+    BREAK_GLASS_DURATION.with(|duration| *duration.borrow().get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_acknowledged_access_with_the_longest_texts_fits_when_stored() {
+        let access = BreakGlassAccess {
+            id: u64::MAX,
+            principal: Principal::from_slice(&[0xff; 29]),
+            patient_id: u64::MAX,
+            reason: "x".repeat(MAX_REASON_LENGTH),
+            granted_on: u64::MAX,
+            expires_on: u64::MAX,
+            acknowledged: Some(Acknowledgement {
+                acknowledged_by: Principal::from_slice(&[0xff; 29]),
+                acknowledged_on: u64::MAX,
+                note: "x".repeat(MAX_NOTE_LENGTH),
+            }),
+        };
+        assert!(access.to_bytes().len() <= BreakGlassAccess::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn rejects_a_reason_that_is_too_long() {
+        assert!(check_reason(&"x".repeat(MAX_REASON_LENGTH)).is_ok());
+        assert!(check_reason(&"x".repeat(MAX_REASON_LENGTH + 1)).is_err());
+    }
+}
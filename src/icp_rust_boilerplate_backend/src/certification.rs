@@ -0,0 +1,397 @@
+// Certified variables: a hash tree over the stored patients, doctors and rooms, whose root hash is
+// set as the canister's certified data so that clients can verify query responses.
+//
+// The tree is laid out as /<collection>/<bucket>/<id>/<field>, where bucket is id / BUCKET_SIZE and
+// both are 8-byte big-endian labels. Each field is a leaf holding its value as JSON. Fields the
+// caller's role may not see are pruned from the witness
+use crate::access::ensure_can_read_patient;
+use crate::redaction::{caller_audience, hidden_fields, project, RecordKind};
+use crate::{
+    get_doctor, get_patient, get_room, Doctor, Error, Patient, Room, DOCTOR_STORAGE,
+    PATIENT_STORAGE, ROOM_STORAGE,
+};
+use candid::CandidType;
+use ic_cdk::api::{data_certificate, set_certified_data};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+//How many records share a bucket. Bounds the hashing needed when a record changes
+const BUCKET_SIZE: u64 = 256;
+
+//CBOR tag that marks the witness as CBOR (self-described CBOR)
+const CBOR_SELF_DESCRIBE_TAG: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+type Hash = [u8; 32];
+
+//The certified collections
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Collection {
+    Doctors,
+    Patients,
+    Rooms,
+}
+
+impl Collection {
+    const ALL: [Collection; 3] = [Collection::Doctors, Collection::Patients, Collection::Rooms];
+
+    fn label(self) -> &'static [u8] {
+        match self {
+            Collection::Doctors => b"doctors",
+            Collection::Patients => b"patients",
+            Collection::Rooms => b"rooms",
+        }
+    }
+
+    //Fields that are derived when the record is read, and so are not certified
+    fn derived_fields(self) -> &'static [&'static str] {
+        match self {
+            Collection::Patients => &["age"],
+            Collection::Doctors | Collection::Rooms => &[],
+        }
+    }
+}
+
+//A hash tree as defined by the IC interface specification
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned(Hash),
+}
+
+fn domain_hasher(domain: &str) -> Sha256 {
+    let mut hasher = Sha256::new();
+    hasher.update([domain.len() as u8]);
+    hasher.update(domain.as_bytes());
+    hasher
+}
+
+impl HashTree {
+    fn digest(&self) -> Hash {
+        match self {
+            HashTree::Empty => domain_hasher("ic-hashtree-empty").finalize().into(),
+            HashTree::Fork(left, right) => {
+                let mut hasher = domain_hasher("ic-hashtree-fork");
+                hasher.update(left.digest());
+                hasher.update(right.digest());
+                hasher.finalize().into()
+            }
+            HashTree::Labeled(label, tree) => {
+                let mut hasher = domain_hasher("ic-hashtree-labeled");
+                hasher.update(label);
+                hasher.update(tree.digest());
+                hasher.finalize().into()
+            }
+            HashTree::Leaf(value) => {
+                let mut hasher = domain_hasher("ic-hashtree-leaf");
+                hasher.update(value);
+                hasher.finalize().into()
+            }
+            HashTree::Pruned(hash) => *hash,
+        }
+    }
+
+    fn labeled(label: &[u8], tree: HashTree) -> HashTree {
+        HashTree::Labeled(label.to_vec(), Box::new(tree))
+    }
+
+    //Encodes the tree in CBOR, as clients read witnesses
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => out.extend([0x81, 0x00]),
+            HashTree::Fork(left, right) => {
+                out.extend([0x83, 0x01]);
+                left.encode(out);
+                right.encode(out);
+            }
+            HashTree::Labeled(label, tree) => {
+                out.extend([0x83, 0x02]);
+                encode_bytes(label, out);
+                tree.encode(out);
+            }
+            HashTree::Leaf(value) => {
+                out.extend([0x82, 0x03]);
+                encode_bytes(value, out);
+            }
+            HashTree::Pruned(hash) => {
+                out.extend([0x82, 0x04]);
+                encode_bytes(hash, out);
+            }
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    let len = bytes.len();
+    match len {
+        0..=23 => out.push(0x40 | len as u8),
+        24..=0xff => out.extend([0x58, len as u8]),
+        0x100..=0xffff => {
+            out.push(0x59);
+            out.extend((len as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0x5a);
+            out.extend((len as u32).to_be_bytes());
+        }
+    }
+    out.extend(bytes);
+}
+
+//Joins sorted subtrees into a balanced tree. Parts without revealed leaves are pruned to their hash
+fn fork(mut nodes: Vec<HashTree>) -> HashTree {
+    match nodes.len() {
+        0 => HashTree::Empty,
+        1 => nodes.remove(0),
+        len => {
+            let right = fork(nodes.split_off(len / 2));
+            let left = fork(nodes);
+            let tree = HashTree::Fork(Box::new(left), Box::new(right));
+            match &tree {
+                HashTree::Fork(left, right)
+                    if matches!(**left, HashTree::Pruned(_))
+                        && matches!(**right, HashTree::Pruned(_)) =>
+                {
+                    HashTree::Pruned(tree.digest())
+                }
+                _ => tree,
+            }
+        }
+    }
+}
+
+//The record as a labeled tree of its fields, revealing only the fields in `revealed`
+fn record_tree<T: Serialize>(
+    collection: Collection,
+    id: u64,
+    record: &T,
+    revealed: impl Fn(&str) -> bool,
+) -> HashTree {
+    let fields = match serde_json::to_value(record).expect("Cannot serialize the record") {
+        serde_json::Value::Object(fields) => fields,
+        _ => serde_json::Map::new(),
+    };
+    let mut fields: Vec<(String, serde_json::Value)> = fields
+        .into_iter()
+        .filter(|(name, _)| !collection.derived_fields().contains(&name.as_str()))
+        .collect();
+    fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let fields = fields
+        .into_iter()
+        .map(|(name, value)| {
+            let leaf = HashTree::labeled(
+                name.as_bytes(),
+                HashTree::Leaf(value.to_string().into_bytes()),
+            );
+            if revealed(&name) {
+                leaf
+            } else {
+                HashTree::Pruned(leaf.digest())
+            }
+        })
+        .collect();
+    HashTree::labeled(&id.to_be_bytes(), fork(fields))
+}
+
+#[derive(Default)]
+struct Bucket {
+    records: BTreeMap<u64, Hash>,
+    hash: Hash,
+}
+
+impl Bucket {
+    fn tree(&self, number: u64, revealed: Option<(u64, HashTree)>) -> HashTree {
+        let mut revealed = revealed;
+        let records = self
+            .records
+            .iter()
+            .map(
+                |(id, hash)| match revealed.take_if(|(revealed_id, _)| *revealed_id == *id) {
+                    Some((_, tree)) => tree,
+                    None => HashTree::Pruned(*hash),
+                },
+            )
+            .collect();
+        HashTree::labeled(&number.to_be_bytes(), fork(records))
+    }
+}
+
+//The hashes of the certified records, kept on the heap and rebuilt after an upgrade
+#[derive(Default)]
+struct CertifiedTree {
+    buckets: BTreeMap<(Collection, u64), Bucket>,
+}
+
+impl CertifiedTree {
+    fn set(&mut self, collection: Collection, id: u64, hash: Option<Hash>) {
+        let number = id / BUCKET_SIZE;
+        let bucket = self.buckets.entry((collection, number)).or_default();
+        match hash {
+            Some(hash) => bucket.records.insert(id, hash),
+            None => bucket.records.remove(&id),
+        };
+
+        if bucket.records.is_empty() {
+            self.buckets.remove(&(collection, number));
+        } else {
+            bucket.hash = bucket.tree(number, None).digest();
+        }
+    }
+
+    //The whole tree, revealing the path to the given record
+    fn tree(&self, revealed: Option<(Collection, u64, HashTree)>) -> HashTree {
+        let mut revealed = revealed;
+        let collections = Collection::ALL
+            .iter()
+            .map(|collection| {
+                let buckets = self
+                    .buckets
+                    .range((*collection, 0)..=(*collection, u64::MAX))
+                    .map(|((_, number), bucket)| {
+                        match revealed.take_if(|(revealed_collection, id, _)| {
+                            *revealed_collection == *collection && *id / BUCKET_SIZE == *number
+                        }) {
+                            Some((_, id, tree)) => bucket.tree(*number, Some((id, tree))),
+                            None => HashTree::Pruned(bucket.hash),
+                        }
+                    })
+                    .collect();
+                HashTree::labeled(collection.label(), fork(buckets))
+            })
+            .collect();
+        fork(collections)
+    }
+}
+
+thread_local! {
+    static CERTIFIED_TREE: RefCell<CertifiedTree> = RefCell::default();
+}
+
+fn update_tree(collection: Collection, id: u64, hash: Option<Hash>) {
+    let root_hash = CERTIFIED_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.set(collection, id, hash);
+        tree.tree(None).digest()
+    });
+    set_certified_data(&root_hash);
+}
+
+//Certifies the stored version of a record. Call after every change to a patient, doctor or room
+pub(crate) fn certify<T: Serialize>(collection: Collection, id: u64, record: &T) {
+    let hash = record_tree(collection, id, record, |_| false).digest();
+    update_tree(collection, id, Some(hash));
+}
+
+//Removes a purged record from the certified data
+pub(crate) fn uncertify(collection: Collection, id: u64) {
+    update_tree(collection, id, None);
+}
+
+//Rebuilds the tree from stable memory, which is needed after every upgrade
+pub(crate) fn certify_all() {
+    let mut tree = CertifiedTree::default();
+    PATIENT_STORAGE.with(|storage| {
+        for (id, patient) in storage.borrow().iter() {
+            let hash = record_tree(Collection::Patients, id, &patient, |_| false).digest();
+            tree.set(Collection::Patients, id, Some(hash));
+        }
+    });
+    DOCTOR_STORAGE.with(|storage| {
+        for (id, doctor) in storage.borrow().iter() {
+            let hash = record_tree(Collection::Doctors, id, &doctor, |_| false).digest();
+            tree.set(Collection::Doctors, id, Some(hash));
+        }
+    });
+    ROOM_STORAGE.with(|storage| {
+        for (id, room) in storage.borrow().iter() {
+            let hash = record_tree(Collection::Rooms, id, &room, |_| false).digest();
+            tree.set(Collection::Rooms, id, Some(hash));
+        }
+    });
+
+    let root_hash = tree.tree(None).digest();
+    CERTIFIED_TREE.with(|certified| *certified.borrow_mut() = tree);
+    set_certified_data(&root_hash);
+}
+
+//Builds the CBOR witness for a record, revealing the fields the caller may see
+fn witness<T: Serialize>(
+    collection: Collection,
+    id: u64,
+    record: &T,
+    hidden: &[String],
+) -> Vec<u8> {
+    let record = record_tree(collection, id, record, |field| {
+        !hidden.iter().any(|hidden| hidden == field)
+    });
+    let tree = CERTIFIED_TREE.with(|tree| tree.borrow().tree(Some((collection, id, record))));
+
+    let mut witness = CBOR_SELF_DESCRIBE_TAG.to_vec();
+    tree.encode(&mut witness);
+    witness
+}
+
+//A patient with the certificate and witness a client needs to verify it.
+//The certificate is only available to query calls, so it is None when called as an update
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedPatient {
+    patient: Patient,
+    certificate: Option<Vec<u8>>,
+    witness: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedDoctor {
+    doctor: Doctor,
+    certificate: Option<Vec<u8>>,
+    witness: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct CertifiedRoom {
+    room: Room,
+    certificate: Option<Vec<u8>>,
+    witness: Vec<u8>,
+}
+
+//Retrieves a patient the caller may read, with a certificate over the fields they may see
+#[ic_cdk::query]
+fn get_certified_patient(id: u64) -> Result<CertifiedPatient, Error> {
+    let patient = get_patient(id)?;
+    ensure_can_read_patient(patient.id)?;
+
+    let hidden = hidden_fields(caller_audience(), RecordKind::Patient);
+    Ok(CertifiedPatient {
+        witness: witness(Collection::Patients, patient.id, &patient, &hidden),
+        certificate: data_certificate(),
+        patient: project(patient),
+    })
+}
+
+//Retrieves a doctor, with a certificate over all their fields
+#[ic_cdk::query]
+fn get_certified_doctor(id: u64) -> Result<CertifiedDoctor, Error> {
+    let doctor = get_doctor(id)?;
+
+    Ok(CertifiedDoctor {
+        witness: witness(Collection::Doctors, doctor.id, &doctor, &[]),
+        certificate: data_certificate(),
+        doctor,
+    })
+}
+
+//Retrieves a room, with a certificate over all its fields
+#[ic_cdk::query]
+fn get_certified_room(id: u64) -> Result<CertifiedRoom, Error> {
+    let room = get_room(id)?;
+
+    Ok(CertifiedRoom {
+        witness: witness(Collection::Rooms, room.id, &room, &[]),
+        certificate: data_certificate(),
+        room,
+    })
+}
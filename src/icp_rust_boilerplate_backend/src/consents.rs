@@ -0,0 +1,282 @@
+// What each patient has consented to, and the checks that features needing consent run
+use crate::access::{ensure_can_manage_consents, ensure_can_read_patient};
+use crate::contacts::contacts_of;
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::{invalid_field, Validator};
+use crate::{get_patient, next_id, Error, CONSENT_ID_COUNTER, CONSENT_STORAGE};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//Longest revocation reason, in bytes, so that a revoked consent fits in Consent::MAX_SIZE
+const MAX_REASON_LENGTH: usize = 500;
+
+//What a consent allows
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub(crate) enum ConsentScope {
+    Treatment,
+    DataSharing, //Sharing the record with other facilities, including exports
+    Research,
+    SmsContact,
+    EmailContact,
+}
+
+//Who gave the consent: the patient, or one of their contacts (e.g. a guardian)
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum Grantor {
+    Patient,
+    Contact { contact_id: u64 },
+}
+
+//Records who revoked a consent, when and why
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Revocation {
+    revoked_by: Principal,
+    revoked_on: u64,
+    reason: String,
+}
+
+//Define our Consent struct
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Consent {
+    id: u64,
+    patient_id: u64,
+    scope: ConsentScope,
+    grantor: Grantor,
+    recorded_by: Principal,
+    valid_from: u64,
+    valid_until: Option<u64>, //Valid until revoked when not set
+    revoked: Option<Revocation>,
+}
+
+impl Storable for Consent {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Consent {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Consent {
+    fn is_active(&self, now: u64) -> bool {
+        self.revoked.is_none()
+            && self.valid_from <= now
+            && self.valid_until.is_none_or(|valid_until| now < valid_until)
+    }
+}
+
+//Represents payload for granting a consent
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct ConsentPayload {
+    scope: ConsentScope,
+    grantor: Grantor,
+    valid_from: Option<u64>, //Defaults to now
+    valid_until: Option<u64>,
+}
+
+pub(crate) fn consents_of(patient_id: u64) -> Vec<Consent> {
+    CONSENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range((patient_id, 0)..=(patient_id, u64::MAX))
+            .map(|(_, consent)| consent)
+            .collect()
+    })
+}
+
+//Refuses to go on unless the patient currently has a consent with the given scope
+pub(crate) fn ensure_consent(patient_id: u64, scope: ConsentScope) -> Result<(), Error> {
+    let now = time();
+    if consents_of(patient_id)
+        .iter()
+        .any(|consent| consent.scope == scope && consent.is_active(now))
+    {
+        return Ok(());
+    }
+    Err(Error::ConsentRequired {
+        msg: format!(
+            "Patient with ID {} has not consented to {:?}",
+            patient_id, scope
+        ),
+        scope,
+    })
+}
+
+pub(crate) fn remove_consents(patient_id: u64) {
+    for consent in consents_of(patient_id) {
+        CONSENT_STORAGE.with(|storage| storage.borrow_mut().remove(&(patient_id, consent.id)));
+    }
+}
+
+//Moves the given consents (or all of them) to another patient
+pub(crate) fn move_consents(from: u64, to: u64, consent_ids: Option<&[u64]>) -> Vec<u64> {
+    CONSENT_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let moved: Vec<Consent> = storage
+            .range((from, 0)..=(from, u64::MAX))
+            .map(|(_, consent)| consent)
+            .filter(|consent| consent_ids.is_none_or(|ids| ids.contains(&consent.id)))
+            .collect();
+
+        for mut consent in moved.iter().cloned() {
+            storage.remove(&(from, consent.id));
+            consent.patient_id = to;
+            storage.insert((to, consent.id), consent);
+        }
+        moved.iter().map(|consent| consent.id).collect()
+    })
+}
+
+//Retrieves every consent of a patient, including expired and revoked ones
+#[ic_cdk::query]
+fn get_patient_consents(patient_id: u64) -> Result<Vec<Consent>, Error> {
+    let patient = get_patient(patient_id)?;
+    ensure_can_read_patient(patient.id)?;
+
+    Ok(consents_of(patient.id))
+}
+
+//Records a consent given by the patient or one of their contacts
+#[ic_cdk::update]
+fn grant_consent(patient_id: u64, payload: ConsentPayload) -> Result<Consent, Error> {
+    observe("grant_consent", || {
+        ensure_writes_allowed()?;
+
+        let patient = get_patient(patient_id)?;
+        ensure_can_manage_consents(patient.id)?;
+
+        let valid_from = payload.valid_from.unwrap_or_else(time);
+        let grantor_is_known = match payload.grantor {
+            Grantor::Patient => true,
+            Grantor::Contact { contact_id } => contacts_of(patient.id)
+                .iter()
+                .any(|contact| contact.id == contact_id),
+        };
+        Validator::default()
+            .check(
+                "grantor",
+                if grantor_is_known {
+                    Ok(())
+                } else {
+                    Err("The grantor must be one of the patient's contacts".to_string())
+                },
+            )
+            .check(
+                "valid_until",
+                match payload.valid_until {
+                    Some(valid_until) if valid_until <= valid_from => {
+                        Err("The consent must end after it starts".to_string())
+                    }
+                    _ => Ok(()),
+                },
+            )
+            .finish()?;
+
+        let id = next_id(&CONSENT_ID_COUNTER);
+        let consent = Consent {
+            id,
+            patient_id: patient.id,
+            scope: payload.scope,
+            grantor: payload.grantor,
+            recorded_by: caller(),
+            valid_from,
+            valid_until: payload.valid_until,
+            revoked: None,
+        };
+
+        CONSENT_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert((patient.id, id), consent.clone())
+        });
+        Ok(consent)
+    })
+}
+
+//Revokes a consent. It is kept so that what was allowed at the time can still be checked
+#[ic_cdk::update]
+fn revoke_consent(patient_id: u64, consent_id: u64, reason: String) -> Result<Consent, Error> {
+    observe("revoke_consent", || {
+        ensure_writes_allowed()?;
+
+        //Validation Logic
+        Validator::default()
+            .limit("reason", &reason, MAX_REASON_LENGTH)
+            .finish()?;
+
+        let patient = get_patient(patient_id)?;
+        ensure_can_manage_consents(patient.id)?;
+
+        let mut consent = CONSENT_STORAGE
+            .with(|storage| storage.borrow().get(&(patient.id, consent_id)))
+            .ok_or(Error::NotFound {
+                msg: format!(
+                    "Consent with ID {} not found for patient with ID {}",
+                    consent_id, patient_id
+                ),
+            })?;
+        if consent.revoked.is_some() {
+            return Err(invalid_field(
+                "consent_id",
+                "This consent has already been revoked",
+            ));
+        }
+
+        consent.revoked = Some(Revocation {
+            revoked_by: caller(),
+            revoked_on: time(),
+            reason,
+        });
+        CONSENT_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert((patient.id, consent_id), consent.clone())
+        });
+        Ok(consent)
+    })
+}
+
+//Tells whether the patient currently has a consent with the given scope. Only callers who may
+//read the patient can ask, as the answer reveals what they consented to
+#[ic_cdk::query]
+fn has_consent(patient_id: u64, scope: ConsentScope) -> Result<bool, Error> {
+    let patient = get_patient(patient_id)?;
+    ensure_can_read_patient(patient.id)?;
+
+    Ok(ensure_consent(patient.id, scope).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_revoked_consent_with_the_longest_reason_fits_when_stored() {
+        let consent = Consent {
+            id: u64::MAX,
+            patient_id: u64::MAX,
+            scope: ConsentScope::DataSharing,
+            grantor: Grantor::Contact {
+                contact_id: u64::MAX,
+            },
+            recorded_by: Principal::from_slice(&[0xff; 29]),
+            valid_from: u64::MAX,
+            valid_until: Some(u64::MAX),
+            revoked: Some(Revocation {
+                revoked_by: Principal::from_slice(&[0xff; 29]),
+                revoked_on: u64::MAX,
+                reason: "x".repeat(MAX_REASON_LENGTH),
+            }),
+        };
+        assert!(consent.to_bytes().len() <= Consent::MAX_SIZE as usize);
+    }
+}
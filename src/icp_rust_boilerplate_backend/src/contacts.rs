@@ -0,0 +1,258 @@
+// Related people (next of kin, emergency contacts) kept for each patient
+use crate::access::{ensure_can_read_patient, ensure_can_write_patient};
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::redaction::project;
+use crate::validation::{invalid_field, validate_contact_payload};
+use crate::{get_patient, next_id, Error, CONTACT_ID_COUNTER, CONTACT_STORAGE};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//How a related person is related to the patient
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub(crate) enum Relationship {
+    Spouse,
+    Partner,
+    Parent,
+    Child,
+    Sibling,
+    Guardian,
+    Friend,
+    NextOfKin, //Relationship not specified
+    #[default]
+    Other,
+}
+
+//Define our RelatedPerson struct
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct RelatedPerson {
+    pub(crate) id: u64,
+    pub(crate) patient_id: u64,
+    pub(crate) name: String,
+    pub(crate) relationship: Relationship,
+    pub(crate) phone_number: String,
+    pub(crate) email: String,             //Optional
+    pub(crate) priority: u32,             //1 is the first to be called in an emergency
+    pub(crate) has_medical_consent: bool, //May receive medical information about the patient
+}
+
+impl Storable for RelatedPerson {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RelatedPerson {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Represents payload for adding or updating a related person
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct RelatedPersonPayload {
+    pub(crate) name: String,
+    pub(crate) relationship: Relationship,
+    pub(crate) phone_number: String,
+    pub(crate) email: String, //Optional
+    pub(crate) has_medical_consent: bool,
+}
+
+//All contacts of a patient, in order of emergency-contact priority
+pub(crate) fn contacts_of(patient_id: u64) -> Vec<RelatedPerson> {
+    let mut contacts: Vec<RelatedPerson> = CONTACT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range((patient_id, 0)..=(patient_id, u64::MAX))
+            .map(|(_, contact)| contact)
+            .collect()
+    });
+    contacts.sort_by_key(|contact| contact.priority);
+    contacts
+}
+
+pub(crate) fn remove_contacts(patient_id: u64) {
+    for contact in contacts_of(patient_id) {
+        CONTACT_STORAGE.with(|storage| storage.borrow_mut().remove(&(patient_id, contact.id)));
+    }
+}
+
+//Moves the given contacts (or all of them) to another patient, after that patient's own contacts
+pub(crate) fn move_contacts(from: u64, to: u64, contact_ids: Option<&[u64]>) -> Vec<u64> {
+    let (moved, mut kept): (Vec<RelatedPerson>, Vec<RelatedPerson>) = contacts_of(from)
+        .into_iter()
+        .partition(|contact| contact_ids.is_none_or(|ids| ids.contains(&contact.id)));
+    let moved_ids = moved.iter().map(|contact| contact.id).collect();
+
+    CONTACT_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for contact in &moved {
+            storage.remove(&(from, contact.id));
+        }
+    });
+
+    let mut contacts = contacts_of(to);
+    contacts.extend(moved.into_iter().map(|mut contact| {
+        contact.patient_id = to;
+        contact
+    }));
+    save_in_order(&mut contacts);
+    save_in_order(&mut kept);
+
+    moved_ids
+}
+
+//Saves the contacts with priorities following their order, starting at 1
+fn save_in_order(contacts: &mut [RelatedPerson]) {
+    CONTACT_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for (index, contact) in contacts.iter_mut().enumerate() {
+            contact.priority = index as u32 + 1;
+            storage.insert((contact.patient_id, contact.id), contact.clone());
+        }
+    });
+}
+
+fn get_contact(patient_id: u64, contact_id: u64) -> Result<RelatedPerson, Error> {
+    CONTACT_STORAGE
+        .with(|storage| storage.borrow().get(&(patient_id, contact_id)))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Contact with ID {} not found for patient with ID {}",
+                contact_id, patient_id
+            ),
+        })
+}
+
+//Retrieves the contacts of a patient in order of priority
+#[ic_cdk::query]
+fn get_patient_contacts(patient_id: u64) -> Result<Vec<RelatedPerson>, Error> {
+    let patient_id = get_patient(patient_id)?.id;
+    ensure_can_read_patient(patient_id)?;
+
+    Ok(contacts_of(patient_id).into_iter().map(project).collect())
+}
+
+//Adds a contact to a patient, with the lowest priority
+#[ic_cdk::update]
+pub(crate) fn add_contact(
+    patient_id: u64,
+    payload: RelatedPersonPayload,
+) -> Result<RelatedPerson, Error> {
+    observe("add_contact", || {
+        ensure_writes_allowed()?;
+
+        //Validation Logic
+        validate_contact_payload(&payload)?;
+
+        // Check if the patient exists. A merged duplicate redirects to the survivor
+        let patient_id = get_patient(patient_id)?.id;
+        ensure_can_write_patient(patient_id)?;
+
+        let id = next_id(&CONTACT_ID_COUNTER);
+
+        let contact = RelatedPerson {
+            id,
+            patient_id,
+            name: payload.name,
+            relationship: payload.relationship,
+            phone_number: payload.phone_number,
+            email: payload.email,
+            priority: 0, // Set when saved in order
+            has_medical_consent: payload.has_medical_consent,
+        };
+
+        let mut contacts = contacts_of(patient_id);
+        contacts.push(contact);
+        save_in_order(&mut contacts);
+
+        Ok(contacts.pop().expect("The new contact was just added"))
+    })
+}
+
+//Updates the details of a contact, keeping their priority
+#[ic_cdk::update]
+fn update_contact(
+    patient_id: u64,
+    contact_id: u64,
+    payload: RelatedPersonPayload,
+) -> Result<RelatedPerson, Error> {
+    observe("update_contact", || {
+        ensure_writes_allowed()?;
+
+        //Validation Logic
+        validate_contact_payload(&payload)?;
+
+        let patient_id = get_patient(patient_id)?.id;
+        ensure_can_write_patient(patient_id)?;
+        let mut contact = get_contact(patient_id, contact_id)?;
+
+        contact.name = payload.name;
+        contact.relationship = payload.relationship;
+        contact.phone_number = payload.phone_number;
+        contact.email = payload.email;
+        contact.has_medical_consent = payload.has_medical_consent;
+
+        CONTACT_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert((patient_id, contact_id), contact.clone())
+        });
+        Ok(contact)
+    })
+}
+
+//Sets the emergency-contact priority of every contact from the given order
+#[ic_cdk::update]
+fn reorder_contacts(patient_id: u64, contact_ids: Vec<u64>) -> Result<Vec<RelatedPerson>, Error> {
+    observe("reorder_contacts", || {
+        ensure_writes_allowed()?;
+
+        let patient_id = get_patient(patient_id)?.id;
+        ensure_can_write_patient(patient_id)?;
+        let mut contacts = contacts_of(patient_id);
+
+        // The new order must list every contact of the patient exactly once
+        let mut current_ids: Vec<u64> = contacts.iter().map(|contact| contact.id).collect();
+        let mut requested_ids = contact_ids.clone();
+        current_ids.sort_unstable();
+        requested_ids.sort_unstable();
+        if current_ids != requested_ids {
+            return Err(invalid_field(
+                "contact_ids",
+                "The new order must list every contact of the patient exactly once",
+            ));
+        }
+
+        contacts.sort_by_key(|contact| {
+            contact_ids
+                .iter()
+                .position(|id| *id == contact.id)
+                .unwrap_or_default()
+        });
+        save_in_order(&mut contacts);
+
+        Ok(contacts)
+    })
+}
+
+//Removes a contact and moves the ones after it up in priority
+#[ic_cdk::update]
+fn remove_contact(patient_id: u64, contact_id: u64) -> Result<(), Error> {
+    observe("remove_contact", || {
+        ensure_writes_allowed()?;
+
+        let patient_id = get_patient(patient_id)?.id;
+        ensure_can_write_patient(patient_id)?;
+        let _contact = get_contact(patient_id, contact_id)?;
+
+        CONTACT_STORAGE.with(|storage| storage.borrow_mut().remove(&(patient_id, contact_id)));
+        save_in_order(&mut contacts_of(patient_id));
+
+        Ok(())
+    })
+}
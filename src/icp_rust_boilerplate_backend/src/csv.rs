@@ -0,0 +1,531 @@
+// Bulk import and export of patients, doctors and rooms as CSV (RFC 4180) text
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::{
+    check_equipment, invalid_field, validate_doctor_payload, validate_patient_payload,
+    validate_room_payload, FieldError, Validator,
+};
+use crate::{
+    ensure_admin, register_doctor, register_patient, register_room, Address, AdministrativeGender,
+    DoctorPayLoad, Error, PatientPayLoad, RoomPayload, DOCTOR_STORAGE, PATIENT_STORAGE,
+    ROOM_STORAGE,
+};
+use candid::CandidType;
+
+const PATIENT_COLUMNS: [&str; 11] = [
+    "name",
+    "date_of_birth",
+    "gender",
+    "ethnicity",
+    "street",
+    "city",
+    "region",
+    "postal_code",
+    "country",
+    "phone_number",
+    "email",
+];
+const DOCTOR_COLUMNS: [&str; 4] = ["name", "email", "phone_number", "speciality"];
+const ROOM_COLUMNS: [&str; 3] = ["name", "location", "equipment"];
+
+//Columns that may be left out of an imported file
+const OPTIONAL_COLUMNS: [&str; 4] = ["region", "postal_code", "email", "equipment"];
+
+//Separates the items of a room's equipment within its CSV column
+const EQUIPMENT_SEPARATOR: char = ';';
+
+//How an import is committed. Large files are sent in chunks of whole rows, each with the header
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct CsvImportOptions {
+    all_or_nothing: bool,   //When set, nothing is stored unless every row is valid
+    first_row: Option<u64>, //Number of the chunk's first row in the whole file, for the report. Defaults to 1
+}
+
+//A row that was stored, with the ID of its new record
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct ImportedRow {
+    row: u64,
+    id: u64,
+}
+
+//A row that was not stored, with every field that is not valid
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct RowError {
+    row: u64,
+    msg: String,
+    fields: Vec<FieldError>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct CsvImportReport {
+    rows: u64,
+    imported: Vec<ImportedRow>,
+    errors: Vec<RowError>,
+}
+
+//Splits CSV text into records of fields. Quoted fields may contain commas, quotes ("") and line breaks
+fn parse(text: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, in_quotes) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            ('"', true) => in_quotes = false,
+            ('"', false) if field.is_empty() => in_quotes = true,
+            (',', false) => record.push(std::mem::take(&mut field)),
+            ('\r', false) if chars.peek() == Some(&'\n') => {}
+            ('\n' | '\r', false) => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (c, _) => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(invalid_field("csv", "A quoted field is not closed"));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    // Blank lines are ignored
+    records.retain(|record| !(record.len() == 1 && record[0].trim().is_empty()));
+    Ok(records)
+}
+
+//Quotes a field when it contains a comma, a quote or a line break
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write(columns: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut text = columns.join(",");
+    text.push_str("\r\n");
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|field| quote(field)).collect();
+        text.push_str(&fields.join(","));
+        text.push_str("\r\n");
+    }
+    text
+}
+
+//A data row, read by column name
+struct Row<'a> {
+    columns: &'a [String],
+    fields: Vec<String>,
+}
+
+impl Row<'_> {
+    fn get(&self, column: &str) -> String {
+        self.columns
+            .iter()
+            .position(|name| name == column)
+            .and_then(|index| self.fields.get(index))
+            .map(|field| field.trim().to_string())
+            .unwrap_or_default()
+    }
+}
+
+fn merge_errors(errors: Vec<Result<(), Error>>) -> Result<(), Error> {
+    let fields: Vec<FieldError> = errors
+        .into_iter()
+        .filter_map(|result| match result {
+            Err(Error::ValidationFailed { fields, .. }) => Some(fields),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    if fields.is_empty() {
+        return Ok(());
+    }
+    Err(Error::ValidationFailed {
+        msg: "Some of the fields are not valid".to_string(),
+        fields,
+    })
+}
+
+//Validates every row, then stores the valid ones (or, when all_or_nothing is set, all or none of them)
+fn import<T>(
+    text: &str,
+    options: CsvImportOptions,
+    expected_columns: &[&str],
+    read: impl Fn(&Row) -> Result<T, Error>,
+    store: impl Fn(T) -> u64,
+) -> Result<CsvImportReport, Error> {
+    ensure_admin()?;
+
+    let mut records = parse(text)?.into_iter();
+    let columns: Vec<String> = records
+        .next()
+        .ok_or_else(|| invalid_field("csv", "The header row is missing"))?
+        .iter()
+        .map(|column| column.trim().to_lowercase())
+        .collect();
+    let missing: Vec<&str> = expected_columns
+        .iter()
+        .filter(|column| {
+            !OPTIONAL_COLUMNS.contains(column) && !columns.iter().any(|name| name == *column)
+        })
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        return Err(invalid_field(
+            "csv",
+            &format!(
+                "The header row is missing the columns {}",
+                missing.join(", ")
+            ),
+        ));
+    }
+
+    let first_row = options.first_row.unwrap_or(1);
+    let mut valid = Vec::new();
+    let mut errors = Vec::new();
+    for (index, fields) in records.enumerate() {
+        let row = first_row + index as u64;
+        if fields.len() != columns.len() {
+            errors.push(RowError {
+                row,
+                msg: format!(
+                    "The row has {} fields, but the header has {} columns",
+                    fields.len(),
+                    columns.len()
+                ),
+                fields: Vec::new(),
+            });
+            continue;
+        }
+
+        match read(&Row {
+            columns: &columns,
+            fields,
+        }) {
+            Ok(record) => valid.push((row, record)),
+            Err(Error::ValidationFailed { msg, fields }) => {
+                errors.push(RowError { row, msg, fields })
+            }
+            Err(error) => errors.push(RowError {
+                row,
+                msg: error.message(),
+                fields: Vec::new(),
+            }),
+        }
+    }
+
+    let rows = (valid.len() + errors.len()) as u64;
+    let imported = if options.all_or_nothing && !errors.is_empty() {
+        Vec::new()
+    } else {
+        valid
+            .into_iter()
+            .map(|(row, record)| ImportedRow {
+                row,
+                id: store(record),
+            })
+            .collect()
+    };
+    Ok(CsvImportReport {
+        rows,
+        imported,
+        errors,
+    })
+}
+
+fn parse_gender(value: &str) -> Result<AdministrativeGender, String> {
+    match value.to_lowercase().as_str() {
+        "male" => Ok(AdministrativeGender::Male),
+        "female" => Ok(AdministrativeGender::Female),
+        "other" => Ok(AdministrativeGender::Other),
+        "unknown" | "" => Ok(AdministrativeGender::Unknown),
+        _ => Err("Gender must be Male, Female, Other or Unknown".to_string()),
+    }
+}
+
+fn gender_name(gender: AdministrativeGender) -> &'static str {
+    match gender {
+        AdministrativeGender::Male => "Male",
+        AdministrativeGender::Female => "Female",
+        AdministrativeGender::Other => "Other",
+        AdministrativeGender::Unknown => "Unknown",
+    }
+}
+
+fn read_patient(row: &Row) -> Result<PatientPayLoad, Error> {
+    let gender = parse_gender(&row.get("gender"));
+    let payload = PatientPayLoad {
+        name: row.get("name"),
+        date_of_birth: row.get("date_of_birth"),
+        gender: gender.clone().unwrap_or_default(),
+        ethnicity: row.get("ethnicity"),
+        address: Address {
+            street: row.get("street"),
+            city: row.get("city"),
+            region: row.get("region"),
+            postal_code: row.get("postal_code"),
+            country: row.get("country"),
+        },
+        phone_number: row.get("phone_number"),
+        email: row.get("email"),
+    };
+    merge_errors(vec![
+        Validator::default()
+            .check("gender", gender.map(|_| ()))
+            .finish(),
+        validate_patient_payload(&payload),
+    ])?;
+    Ok(payload)
+}
+
+fn read_doctor(row: &Row) -> Result<DoctorPayLoad, Error> {
+    let payload = DoctorPayLoad {
+        name: row.get("name"),
+        email: row.get("email"),
+        phone_number: row.get("phone_number"),
+        speciality: row.get("speciality"),
+    };
+    validate_doctor_payload(&payload)?;
+    Ok(payload)
+}
+
+fn read_room(row: &Row) -> Result<(RoomPayload, Vec<String>), Error> {
+    let payload = RoomPayload {
+        name: row.get("name"),
+        location: row.get("location"),
+    };
+    let equipment: Vec<String> = row
+        .get("equipment")
+        .split(EQUIPMENT_SEPARATOR)
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect();
+    merge_errors(vec![
+        validate_room_payload(&payload),
+        Validator::default()
+            .check("equipment", check_equipment(&equipment))
+            .finish(),
+    ])?;
+    Ok((payload, equipment))
+}
+
+//Registers a patient for every valid row, with the same rules as add_patient
+#[ic_cdk::update]
+fn import_patients_csv(csv: String, options: CsvImportOptions) -> Result<CsvImportReport, Error> {
+    observe("import_patients_csv", || {
+        ensure_writes_allowed()?;
+
+        import(&csv, options, &PATIENT_COLUMNS, read_patient, |payload| {
+            register_patient(payload, Vec::new()).id
+        })
+    })
+}
+
+//Adds a doctor for every valid row, with the same rules as add_doctor
+#[ic_cdk::update]
+fn import_doctors_csv(csv: String, options: CsvImportOptions) -> Result<CsvImportReport, Error> {
+    observe("import_doctors_csv", || {
+        ensure_writes_allowed()?;
+
+        import(&csv, options, &DOCTOR_COLUMNS, read_doctor, |payload| {
+            register_doctor(payload).id
+        })
+    })
+}
+
+//Adds a room for every valid row, with the same rules as add_room
+#[ic_cdk::update]
+fn import_rooms_csv(csv: String, options: CsvImportOptions) -> Result<CsvImportReport, Error> {
+    observe("import_rooms_csv", || {
+        ensure_writes_allowed()?;
+
+        import(
+            &csv,
+            options,
+            &ROOM_COLUMNS,
+            read_room,
+            |(payload, equipment)| register_room(payload, equipment).id,
+        )
+    })
+}
+
+//Exports every active patient, in the columns the import reads plus their ID and MRN
+#[ic_cdk::query]
+fn export_patients_csv() -> Result<String, Error> {
+    ensure_admin()?;
+
+    let rows = PATIENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, patient)| patient)
+            .filter(|patient| patient.archived.is_none() && patient.merged_into.is_none())
+            .map(|patient| {
+                vec![
+                    patient.id.to_string(),
+                    patient.mrn.unwrap_or_default(),
+                    patient.name,
+                    patient.date_of_birth,
+                    gender_name(patient.gender).to_string(),
+                    patient.ethnicity,
+                    patient.address.street,
+                    patient.address.city,
+                    patient.address.region,
+                    patient.address.postal_code,
+                    patient.address.country,
+                    patient.phone_number,
+                    patient.email,
+                ]
+            })
+            .collect()
+    });
+    Ok(write(
+        &[&["id", "mrn"], &PATIENT_COLUMNS[..]].concat(),
+        rows,
+    ))
+}
+
+//Exports every active doctor, in the columns the import reads plus their ID
+#[ic_cdk::query]
+fn export_doctors_csv() -> Result<String, Error> {
+    ensure_admin()?;
+
+    let rows = DOCTOR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, doctor)| doctor)
+            .filter(|doctor| doctor.archived.is_none())
+            .map(|doctor| {
+                vec![
+                    doctor.id.to_string(),
+                    doctor.name,
+                    doctor.email,
+                    doctor.phone_number,
+                    doctor.speciality,
+                ]
+            })
+            .collect()
+    });
+    Ok(write(&[&["id"], &DOCTOR_COLUMNS[..]].concat(), rows))
+}
+
+//Exports every active room, in the columns the import reads plus their ID
+#[ic_cdk::query]
+fn export_rooms_csv() -> Result<String, Error> {
+    ensure_admin()?;
+
+    let rows = ROOM_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, room)| room)
+            .filter(|room| room.archived.is_none())
+            .map(|room| {
+                vec![
+                    room.id.to_string(),
+                    room.name,
+                    room.location,
+                    room.equipment.join(&format!("{} ", EQUIPMENT_SEPARATOR)),
+                ]
+            })
+            .collect()
+    });
+    Ok(write(&[&["id"], &ROOM_COLUMNS[..]].concat(), rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{MAX_EQUIPMENT_LENGTH, MAX_NAME_LENGTH, MAX_TEXT_LENGTH};
+
+    fn records(text: &str) -> Vec<Vec<String>> {
+        parse(text).ok().expect("The CSV could not be parsed")
+    }
+
+    #[test]
+    fn quotes_only_fields_that_need_it() {
+        assert_eq!(quote("Nairobi"), "Nairobi");
+        assert_eq!(quote("Smith, John"), "\"Smith, John\"");
+        assert_eq!(quote("The \"Annex\""), "\"The \"\"Annex\"\"\"");
+        assert_eq!(quote("Line 1\nLine 2"), "\"Line 1\nLine 2\"");
+    }
+
+    #[test]
+    fn written_fields_round_trip() {
+        let row = vec![
+            "Smith, John".to_string(),
+            "The \"Annex\"".to_string(),
+            "Line 1\r\nLine 2".to_string(),
+            String::new(),
+            "plain".to_string(),
+        ];
+        let text = write(&["a", "b", "c", "d", "e"], vec![row.clone()]);
+
+        let parsed = records(&text);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0], ["a", "b", "c", "d", "e"]);
+        assert_eq!(parsed[1], row);
+    }
+
+    #[test]
+    fn parses_crlf_and_skips_blank_lines() {
+        assert_eq!(
+            records("name,city\r\n\r\nAna,Nairobi\nBen,\"Mombasa\""),
+            vec![
+                vec!["name", "city"],
+                vec!["Ana", "Nairobi"],
+                vec!["Ben", "Mombasa"],
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_an_unclosed_quote() {
+        assert!(parse("name\n\"Ana").is_err());
+    }
+
+    #[test]
+    fn reports_rows_with_fields_too_long_to_store() {
+        let columns: Vec<String> = ROOM_COLUMNS
+            .iter()
+            .map(|column| column.to_string())
+            .collect();
+        let room = |name: &str, equipment: &str| Row {
+            columns: &columns,
+            fields: vec![
+                name.to_string(),
+                "Wing A".to_string(),
+                equipment.to_string(),
+            ],
+        };
+        assert!(read_room(&room("Theatre 1", "Monitor; Ventilator")).is_ok());
+        assert!(read_room(&room(&"x".repeat(MAX_NAME_LENGTH + 1), "Monitor")).is_err());
+        assert!(read_room(&room("Theatre 1", &"x".repeat(MAX_EQUIPMENT_LENGTH + 1))).is_err());
+
+        let columns: Vec<String> = DOCTOR_COLUMNS
+            .iter()
+            .map(|column| column.to_string())
+            .collect();
+        let doctor = Row {
+            columns: &columns,
+            fields: vec![
+                "Dr Smith".to_string(),
+                "smith@example.com".to_string(),
+                "+447700900123".to_string(),
+                "x".repeat(MAX_TEXT_LENGTH + 1),
+            ],
+        };
+        assert!(read_doctor(&doctor).is_err());
+    }
+}
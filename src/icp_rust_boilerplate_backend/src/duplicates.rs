@@ -0,0 +1,570 @@
+// Finds patients that were registered more than once and merges their records
+use crate::access::{can_read_patient, ensure_can_read_patient};
+use crate::consents::{consents_of, move_consents};
+use crate::contacts::{contacts_of, move_contacts};
+use crate::identifiers::{
+    identifiers_of, move_identifiers, IdentifierKey, IdentifierKind, MAX_IDENTIFIERS,
+};
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::invalid_field;
+use crate::{
+    ensure_admin, get_patient, save_doctor, save_patient, Error, Patient, PatientPayLoad,
+    DIAGNOSIS_STORAGE, DOCTOR_STORAGE, MERGES, PATIENT_STORAGE,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//How long a merge can still be undone (30 days, in nanoseconds)
+const MERGE_GRACE_PERIOD: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+//Patients whose match weight reaches this are reported as possible duplicates
+const DUPLICATE_THRESHOLD: f64 = 8.0;
+
+//Names at least this similar (Jaro-Winkler) count as the same name
+const NAME_SIMILARITY: f64 = 0.92;
+
+//Most possible duplicates reported for one patient
+const MAX_CANDIDATES: usize = 10;
+
+//Most diagnoses, doctor assignments, contacts and consents one merge can move, so that the
+//record of the merge fits in Merge::MAX_SIZE. The identifiers are bounded by MAX_IDENTIFIERS,
+//which a merge may not take the survivor over
+const MAX_MERGED_RECORDS: usize = 400;
+
+//How likely a field is to agree for two records of the same person (m)
+//and for two records of different people (u)
+struct FieldModel {
+    field: &'static str,
+    m: f64,
+    u: f64,
+}
+
+const NAME: FieldModel = FieldModel {
+    field: "name",
+    m: 0.95,
+    u: 0.01,
+};
+const DATE_OF_BIRTH: FieldModel = FieldModel {
+    field: "date_of_birth",
+    m: 0.97,
+    u: 0.003,
+};
+const PHONE_NUMBER: FieldModel = FieldModel {
+    field: "phone_number",
+    m: 0.9,
+    u: 0.001,
+};
+const EMAIL: FieldModel = FieldModel {
+    field: "email",
+    m: 0.85,
+    u: 0.001,
+};
+
+impl FieldModel {
+    //Fellegi-Sunter weight of the comparison. `None` means one of the records has no value
+    fn weight(&self, agrees: Option<bool>) -> f64 {
+        match agrees {
+            Some(true) => (self.m / self.u).log2(),
+            Some(false) => ((1.0 - self.m) / (1.0 - self.u)).log2(),
+            None => 0.0,
+        }
+    }
+}
+
+//A patient that is probably the same person as the one being checked
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DuplicateCandidate {
+    patient_id: u64,
+    name: String,
+    mrn: Option<String>,
+    score: f64, //Sum of the field weights, higher is more likely
+    matching_fields: Vec<String>,
+}
+
+//Returned when a patient is registered, with the patients they may duplicate
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct PatientRegistration {
+    patient: Patient,
+    possible_duplicates: Vec<DuplicateCandidate>,
+}
+
+impl PatientRegistration {
+    pub(crate) fn new(patient: Patient, possible_duplicates: Vec<DuplicateCandidate>) -> Self {
+        PatientRegistration {
+            patient,
+            possible_duplicates,
+        }
+    }
+
+    pub(crate) fn patient_id(&self) -> u64 {
+        self.patient.id
+    }
+
+    //Tells whether the registration holds personal data of the patient
+    pub(crate) fn mentions(&self, patient_id: u64) -> bool {
+        self.patient.id == patient_id
+            || self
+                .possible_duplicates
+                .iter()
+                .any(|candidate| candidate.patient_id == patient_id)
+    }
+}
+
+//Records a merge so that the duplicate redirects to the survivor and the merge can be undone
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Merge {
+    pub(crate) survivor_id: u64,
+    pub(crate) duplicate_id: u64,
+    merged_by: Principal,
+    merged_on: u64,
+    diagnosis_ids: Vec<u64>, //Diagnoses moved to the survivor
+    doctor_ids: Vec<u64>,    //Doctors whose current patient was the duplicate
+    contact_ids: Vec<u64>,
+    consent_ids: Vec<u64>,
+    identifiers: Vec<IdentifierKey>,
+}
+
+impl Storable for Merge {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Merge {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//The fields that duplicate detection compares
+pub(crate) struct Demographics<'a> {
+    name: &'a str,
+    date_of_birth: &'a str,
+    phone_number: &'a str,
+    email: &'a str,
+}
+
+impl<'a> From<&'a PatientPayLoad> for Demographics<'a> {
+    fn from(payload: &'a PatientPayLoad) -> Self {
+        Demographics {
+            name: &payload.name,
+            date_of_birth: &payload.date_of_birth,
+            phone_number: &payload.phone_number,
+            email: &payload.email,
+        }
+    }
+}
+
+impl<'a> From<&'a Patient> for Demographics<'a> {
+    fn from(patient: &'a Patient) -> Self {
+        Demographics {
+            name: &patient.name,
+            date_of_birth: &patient.date_of_birth,
+            phone_number: &patient.phone_number,
+            email: &patient.email,
+        }
+    }
+}
+
+//Lowercase name parts in alphabetical order, so "Doe, John" matches "John Doe"
+fn normalize_name(name: &str) -> String {
+    let mut parts: Vec<String> = name
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|part| !part.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    parts.sort();
+    parts.join(" ")
+}
+
+fn jaro(a: &[char], b: &[char]) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    let window = (a.len().max(b.len()) / 2).saturating_sub(1);
+    let mut b_matched = vec![false; b.len()];
+    let mut a_matches = Vec::new();
+
+    for (i, c) in a.iter().enumerate() {
+        let start = i.saturating_sub(window);
+        let end = (i + window + 1).min(b.len());
+        if let Some(j) = (start..end).find(|&j| !b_matched[j] && b[j] == *c) {
+            b_matched[j] = true;
+            a_matches.push(*c);
+        }
+    }
+    if a_matches.is_empty() {
+        return 0.0;
+    }
+
+    let b_matches = b
+        .iter()
+        .zip(&b_matched)
+        .filter(|(_, matched)| **matched)
+        .map(|(c, _)| *c);
+    let transpositions = a_matches
+        .iter()
+        .zip(b_matches)
+        .filter(|(a, b)| **a != *b)
+        .count();
+
+    let matches = a_matches.len() as f64;
+    (matches / a.len() as f64
+        + matches / b.len() as f64
+        + (matches - transpositions as f64 / 2.0) / matches)
+        / 3.0
+}
+
+//Jaro-Winkler similarity, between 0 (nothing in common) and 1 (equal)
+fn jaro_winkler(a: &str, b: &str) -> f64 {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let similarity = jaro(&a, &b);
+    let prefix = a.iter().zip(&b).take(4).take_while(|(a, b)| a == b).count();
+    similarity + prefix as f64 * 0.1 * (1.0 - similarity)
+}
+
+fn digits(value: &str) -> String {
+    value.chars().filter(char::is_ascii_digit).collect()
+}
+
+//Compares two values, or returns `None` when either of them is missing
+fn agrees(a: &str, b: &str, same: impl Fn(&str, &str) -> bool) -> Option<bool> {
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+    Some(same(a, b))
+}
+
+fn compare(checked: &Demographics, patient: &Patient) -> Option<DuplicateCandidate> {
+    let other = Demographics::from(patient);
+    let comparisons = [
+        (
+            &NAME,
+            agrees(
+                &normalize_name(checked.name),
+                &normalize_name(other.name),
+                |a, b| jaro_winkler(a, b) >= NAME_SIMILARITY,
+            ),
+        ),
+        (
+            &DATE_OF_BIRTH,
+            agrees(checked.date_of_birth, other.date_of_birth, |a, b| a == b),
+        ),
+        (
+            &PHONE_NUMBER,
+            agrees(
+                &digits(checked.phone_number),
+                &digits(other.phone_number),
+                |a, b| a == b,
+            ),
+        ),
+        (
+            &EMAIL,
+            agrees(checked.email.trim(), other.email.trim(), |a, b| {
+                a.eq_ignore_ascii_case(b)
+            }),
+        ),
+    ];
+
+    let score: f64 = comparisons
+        .iter()
+        .map(|(model, agrees)| model.weight(*agrees))
+        .sum();
+    if score < DUPLICATE_THRESHOLD {
+        return None;
+    }
+
+    Some(DuplicateCandidate {
+        patient_id: patient.id,
+        name: patient.name.clone(),
+        mrn: patient.mrn.clone(),
+        score,
+        matching_fields: comparisons
+            .iter()
+            .filter(|(_, agrees)| *agrees == Some(true))
+            .map(|(model, _)| model.field.to_string())
+            .collect(),
+    })
+}
+
+//Active patients that are probably the same person, most likely first. Only the patients the
+//caller may read are listed
+pub(crate) fn find_duplicates(checked: &Demographics, exclude_id: u64) -> Vec<DuplicateCandidate> {
+    let caller = caller();
+    let mut candidates: Vec<DuplicateCandidate> = PATIENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(id, patient)| {
+                *id != exclude_id && patient.archived.is_none() && patient.merged_into.is_none()
+            })
+            .filter_map(|(_, patient)| compare(checked, &patient))
+            .filter(|candidate| can_read_patient(caller, candidate.patient_id))
+            .collect()
+    });
+    candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+    candidates.truncate(MAX_CANDIDATES);
+    candidates
+}
+
+//The merges the patient took part in, as survivor or as duplicate
+pub(crate) fn merges_of(patient_id: u64) -> Vec<Merge> {
+    MERGES.with(|merges| {
+        merges
+            .borrow()
+            .iter()
+            .map(|(_, merge)| merge)
+            .filter(|merge| merge.survivor_id == patient_id || merge.duplicate_id == patient_id)
+            .collect()
+    })
+}
+
+//Forgets the identifiers moved by the patient's merges, once erasure has unlinked them. Undoing
+//such a merge then leaves the identifiers where they are
+pub(crate) fn forget_merged_identifiers(patient_id: u64) {
+    for mut merge in merges_of(patient_id) {
+        merge.identifiers = Vec::new();
+        MERGES.with(|merges| merges.borrow_mut().insert(merge.duplicate_id, merge));
+    }
+}
+
+//Reads a patient that has not been merged into another one
+fn unmerged_patient(id: u64) -> Result<Patient, Error> {
+    let patient = get_patient(id)?;
+    if patient.id != id {
+        return Err(Error::CanNotMerge {
+            msg: format!(
+                "Patient with ID {} was merged into patient with ID {}",
+                id, patient.id
+            ),
+        });
+    }
+    Ok(patient)
+}
+
+//Counts the diagnoses, doctor assignments, contacts and consents a merge would move
+fn records_to_move(patient_id: u64) -> usize {
+    let diagnoses = DIAGNOSIS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, diagnosis)| diagnosis.patient_id == patient_id)
+            .count()
+    });
+    let doctors = DOCTOR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, doctor)| doctor.current_patient == patient_id)
+            .count()
+    });
+    diagnoses + doctors + contacts_of(patient_id).len() + consents_of(patient_id).len()
+}
+
+//Points the given diagnoses (or all of them) at another patient
+fn move_diagnoses(from: u64, to: u64, diagnosis_ids: Option<&[u64]>) -> Vec<u64> {
+    DIAGNOSIS_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let moved: Vec<_> = storage
+            .iter()
+            .filter(|(id, diagnosis)| {
+                diagnosis.patient_id == from && diagnosis_ids.is_none_or(|ids| ids.contains(id))
+            })
+            .collect();
+
+        for (id, mut diagnosis) in moved.iter().cloned() {
+            diagnosis.patient_id = to;
+            diagnosis.version += 1;
+            storage.insert(id, diagnosis);
+        }
+        moved.into_iter().map(|(id, _)| id).collect()
+    })
+}
+
+//Makes another patient the current patient of the given doctors (or of all of them)
+fn move_doctor_assignments(from: u64, to: u64, doctor_ids: Option<&[u64]>) -> Vec<u64> {
+    let doctors: Vec<_> = DOCTOR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(id, doctor)| {
+                doctor.current_patient == from && doctor_ids.is_none_or(|ids| ids.contains(id))
+            })
+            .map(|(_, doctor)| doctor)
+            .collect()
+    });
+
+    doctors
+        .into_iter()
+        .map(|mut doctor| {
+            doctor.current_patient = to;
+            save_doctor(&mut doctor);
+            doctor.id
+        })
+        .collect()
+}
+
+//Lists patients that are probably the same person as the given patient
+#[ic_cdk::query]
+fn find_possible_duplicates(patient_id: u64) -> Result<Vec<DuplicateCandidate>, Error> {
+    let patient = unmerged_patient(patient_id)?;
+    ensure_can_read_patient(patient_id)?;
+
+    Ok(find_duplicates(&Demographics::from(&patient), patient_id))
+}
+
+//Moves everything that refers to the duplicate over to the survivor, and leaves the duplicate
+//as a redirect to the survivor
+#[ic_cdk::update]
+fn merge_patients(survivor_id: u64, duplicate_id: u64) -> Result<Patient, Error> {
+    observe("merge_patients", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        if survivor_id == duplicate_id {
+            return Err(invalid_field(
+                "duplicate_id",
+                "A patient can not be merged into itself",
+            ));
+        }
+        let _survivor = unmerged_patient(survivor_id)?;
+        let mut duplicate = unmerged_patient(duplicate_id)?;
+        if records_to_move(duplicate_id) > MAX_MERGED_RECORDS {
+            return Err(Error::CanNotMerge {
+                msg: format!(
+                    "Patient with ID {} has more than {} diagnoses, doctor assignments, contacts and consents to move",
+                    duplicate_id, MAX_MERGED_RECORDS
+                ),
+            });
+        }
+        let moved_identifiers = identifiers_of(duplicate_id)
+            .iter()
+            .filter(|identifier| identifier.kind != IdentifierKind::MedicalRecordNumber)
+            .count();
+        if identifiers_of(survivor_id).len() + moved_identifiers > MAX_IDENTIFIERS {
+            return Err(Error::CanNotMerge {
+                msg: format!(
+                    "Together the patients would have more than {} identifiers",
+                    MAX_IDENTIFIERS
+                ),
+            });
+        }
+
+        let merge = Merge {
+            survivor_id,
+            duplicate_id,
+            merged_by: caller(),
+            merged_on: time(),
+            diagnosis_ids: move_diagnoses(duplicate_id, survivor_id, None),
+            doctor_ids: move_doctor_assignments(duplicate_id, survivor_id, None),
+            contact_ids: move_contacts(duplicate_id, survivor_id, None),
+            consent_ids: move_consents(duplicate_id, survivor_id, None),
+            identifiers: move_identifiers(duplicate_id, survivor_id, None),
+        };
+
+        duplicate.merged_into = Some(survivor_id);
+        save_patient(&mut duplicate);
+        MERGES.with(|merges| merges.borrow_mut().insert(duplicate_id, merge));
+
+        get_patient(survivor_id)
+    })
+}
+
+//Reverses a merge within the grace period, moving back what was moved to the survivor
+#[ic_cdk::update]
+fn unmerge_patients(duplicate_id: u64) -> Result<Patient, Error> {
+    observe("unmerge_patients", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        let merge = MERGES
+            .with(|merges| merges.borrow().get(&duplicate_id))
+            .ok_or(Error::NotFound {
+                msg: format!("No merge found for patient with ID {}", duplicate_id),
+            })?;
+        if time() > merge.merged_on.saturating_add(MERGE_GRACE_PERIOD) {
+            return Err(Error::CanNotMerge {
+                msg: "The grace period for undoing this merge has passed".to_string(),
+            });
+        }
+        // Whatever was moved may have been moved on again by a later merge of the survivor,
+        // so it is moved back from the patient the survivor now redirects to
+        let survivor_id = get_patient(merge.survivor_id)?.id;
+
+        move_diagnoses(survivor_id, duplicate_id, Some(&merge.diagnosis_ids));
+        move_doctor_assignments(survivor_id, duplicate_id, Some(&merge.doctor_ids));
+        move_contacts(survivor_id, duplicate_id, Some(&merge.contact_ids));
+        move_consents(survivor_id, duplicate_id, Some(&merge.consent_ids));
+        move_identifiers(survivor_id, duplicate_id, Some(&merge.identifiers));
+
+        let mut duplicate = PATIENT_STORAGE
+            .with(|storage| storage.borrow().get(&duplicate_id))
+            .expect("A merged patient is kept as a redirect");
+        duplicate.merged_into = None;
+        save_patient(&mut duplicate);
+        MERGES.with(|merges| merges.borrow_mut().remove(&duplicate_id));
+
+        Ok(duplicate)
+    })
+}
+
+//Lists the merges into the given patient
+#[ic_cdk::query]
+fn get_merges_into(survivor_id: u64) -> Result<Vec<Merge>, Error> {
+    ensure_admin()?;
+
+    Ok(MERGES.with(|merges| {
+        merges
+            .borrow()
+            .iter()
+            .filter(|(_, merge)| merge.survivor_id == survivor_id)
+            .map(|(_, merge)| merge)
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 0.001,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn scores_names_like_the_published_jaro_winkler_examples() {
+        assert_close(jaro_winkler("martha", "marhta"), 0.961);
+        assert_close(jaro_winkler("dwayne", "duane"), 0.840);
+        assert_close(jaro_winkler("dixon", "dicksonx"), 0.813);
+    }
+
+    #[test]
+    fn scores_equal_and_unrelated_names() {
+        assert_close(jaro_winkler("jane", "jane"), 1.0);
+        assert_close(jaro_winkler("abc", "xyz"), 0.0);
+        assert_close(jaro_winkler("", ""), 1.0);
+        assert_close(jaro_winkler("jane", ""), 0.0);
+    }
+
+    #[test]
+    fn compares_names_regardless_of_order_case_and_punctuation() {
+        assert_eq!(normalize_name("Doe, JANE"), "doe jane");
+        assert_eq!(normalize_name("jane  doe"), normalize_name("Doe-Jane"));
+        assert!(
+            jaro_winkler(&normalize_name("Jane Doe"), &normalize_name("Doe Jayne"))
+                >= NAME_SIMILARITY
+        );
+    }
+}
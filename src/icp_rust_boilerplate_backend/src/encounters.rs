@@ -0,0 +1,154 @@
+// Hospital visits (admission to discharge) and lab results, as reported by the HL7 feeds
+use crate::access::ensure_can_read_patient;
+use crate::duplicates::merges_of;
+use crate::{get_patient, Error, ENCOUNTERS, LAB_RESULTS};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//Define our Encounter struct
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Encounter {
+    pub(crate) id: u64,
+    pub(crate) patient_id: u64,
+    pub(crate) visit_number: String, //As assigned by the admission system
+    pub(crate) patient_class: String, //e.g. I (inpatient), O (outpatient), E (emergency)
+    pub(crate) location: String,
+    pub(crate) room_id: Option<u64>, //Set when the location names one of our rooms
+    pub(crate) attending_doctor: String,
+    pub(crate) admitted_on: u64,
+    pub(crate) discharged_on: Option<u64>,
+}
+
+impl Storable for Encounter {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Encounter {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//A single measured value of a lab result
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Observation {
+    pub(crate) code: String,
+    pub(crate) value: String,
+    pub(crate) units: String,
+    pub(crate) reference_range: String,
+    pub(crate) abnormal_flags: String, //e.g. H (high), L (low), empty when normal
+    pub(crate) status: String,         //e.g. F (final), P (preliminary), C (corrected)
+}
+
+//Define our LabResult struct
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct LabResult {
+    pub(crate) id: u64,
+    pub(crate) patient_id: u64,
+    pub(crate) order_number: String,
+    pub(crate) test: String,
+    pub(crate) status: String,
+    pub(crate) observed_on: u64,
+    pub(crate) received_on: u64,
+    pub(crate) observations: Vec<Observation>,
+}
+
+impl Storable for LabResult {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LabResult {
+    const MAX_SIZE: u32 = 32 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//The patient and the duplicates that were merged into them
+fn record_ids(patient_id: u64) -> Vec<u64> {
+    let mut ids = vec![patient_id];
+    ids.extend(
+        merges_of(patient_id)
+            .iter()
+            .filter(|merge| merge.survivor_id == patient_id)
+            .map(|merge| merge.duplicate_id),
+    );
+    ids
+}
+
+pub(crate) fn encounters_of(patient_id: u64) -> Vec<Encounter> {
+    let ids = record_ids(patient_id);
+    ENCOUNTERS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, encounter)| encounter)
+            .filter(|encounter| ids.contains(&encounter.patient_id))
+            .collect()
+    })
+}
+
+pub(crate) fn lab_results_of(patient_id: u64) -> Vec<LabResult> {
+    let ids = record_ids(patient_id);
+    LAB_RESULTS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, result)| result)
+            .filter(|result| ids.contains(&result.patient_id))
+            .collect()
+    })
+}
+
+pub(crate) fn remove_encounters_and_lab_results(patient_id: u64) {
+    ENCOUNTERS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let ids: Vec<u64> = storage
+            .iter()
+            .filter(|(_, encounter)| encounter.patient_id == patient_id)
+            .map(|(id, _)| id)
+            .collect();
+        for id in ids {
+            storage.remove(&id);
+        }
+    });
+    LAB_RESULTS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let ids: Vec<u64> = storage
+            .iter()
+            .filter(|(_, result)| result.patient_id == patient_id)
+            .map(|(id, _)| id)
+            .collect();
+        for id in ids {
+            storage.remove(&id);
+        }
+    });
+}
+
+//Retrieves the hospital visits of a patient the caller may read, oldest first
+#[ic_cdk::query]
+fn get_patient_encounters(patient_id: u64) -> Result<Vec<Encounter>, Error> {
+    let patient = get_patient(patient_id)?;
+    ensure_can_read_patient(patient.id)?;
+
+    Ok(encounters_of(patient.id))
+}
+
+//Retrieves the lab results of a patient the caller may read, oldest first
+#[ic_cdk::query]
+fn get_patient_lab_results(patient_id: u64) -> Result<Vec<LabResult>, Error> {
+    let patient = get_patient(patient_id)?;
+    ensure_can_read_patient(patient.id)?;
+
+    Ok(lab_results_of(patient.id))
+}
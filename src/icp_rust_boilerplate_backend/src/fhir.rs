@@ -0,0 +1,386 @@
+// Renders records as HL7 FHIR R4 resources (JSON) for partner systems
+use crate::access::ensure_can_read_patient;
+use crate::consents::{ensure_consent, ConsentScope};
+use crate::contacts::{contacts_of, RelatedPerson, Relationship};
+use crate::identifiers::{identifiers_of, ExternalIdentifier, IdentifierKind};
+use crate::redaction::project;
+use crate::validation::Date;
+use crate::{
+    get_doctor, get_patient, get_room, AdministrativeGender, Diagnosis, Doctor, Error, Patient,
+    Room, DIAGNOSIS_STORAGE, FACILITY_CODE,
+};
+use ic_cdk::api::time;
+use serde_json::{json, Map, Value};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+//Converts a DD-MM-YYYY date to the YYYY-MM-DD format FHIR uses
+fn fhir_date(value: &str) -> Option<String> {
+    Date::parse(value).ok().map(Date::to_iso)
+}
+
+//Converts a timestamp in nanoseconds to a FHIR instant, in UTC
+fn fhir_instant(timestamp: u64) -> String {
+    let seconds_of_day = timestamp / NANOS_PER_SECOND % (24 * 60 * 60);
+    format!(
+        "{}T{:02}:{:02}:{:02}Z",
+        Date::from_timestamp(timestamp).to_iso(),
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+//FHIR does not allow empty strings, arrays or objects, so they are left out
+fn prune(value: Value) -> Option<Value> {
+    match value {
+        Value::Null => None,
+        Value::String(text) if text.is_empty() => None,
+        Value::Array(items) => {
+            let items: Vec<Value> = items.into_iter().filter_map(prune).collect();
+            (!items.is_empty()).then_some(Value::Array(items))
+        }
+        Value::Object(fields) => {
+            let fields: Map<String, Value> = fields
+                .into_iter()
+                .filter_map(|(name, value)| prune(value).map(|value| (name, value)))
+                .collect();
+            (!fields.is_empty()).then_some(Value::Object(fields))
+        }
+        value => Some(value),
+    }
+}
+
+//A name or address of the given use, left out when it has nothing else
+fn with_use(use_code: &str, element: Value) -> Option<Value> {
+    let mut element = prune(element)?;
+    element["use"] = json!(use_code);
+    Some(element)
+}
+
+fn reference(resource_type: &str, id: u64) -> Value {
+    json!({ "reference": format!("{}/{}", resource_type, id) })
+}
+
+fn telecom(phone_number: &str, email: &str) -> Vec<Value> {
+    let mut telecom = Vec::new();
+    if !phone_number.is_empty() {
+        telecom.push(json!({ "system": "phone", "value": phone_number }));
+    }
+    if !email.is_empty() {
+        telecom.push(json!({ "system": "email", "value": email }));
+    }
+    telecom
+}
+
+fn gender(gender: AdministrativeGender) -> &'static str {
+    match gender {
+        AdministrativeGender::Male => "male",
+        AdministrativeGender::Female => "female",
+        AdministrativeGender::Other => "other",
+        AdministrativeGender::Unknown => "unknown",
+    }
+}
+
+//Uses the identifier type codes of HL7 v2 table 0203
+fn identifier(identifier: &ExternalIdentifier) -> Value {
+    let (code, display) = match identifier.kind {
+        IdentifierKind::MedicalRecordNumber => ("MR", "Medical record number"),
+        IdentifierKind::NationalId => ("NI", "National unique individual identifier"),
+        IdentifierKind::InsuranceMemberNumber => ("MB", "Member Number"),
+        IdentifierKind::Passport => ("PPN", "Passport number"),
+    };
+    json!({
+        "use": "official",
+        "type": {
+            "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/v2-0203",
+                "code": code,
+                "display": display,
+            }],
+        },
+        "value": identifier.value,
+        "assigner": { "display": identifier.issuer },
+    })
+}
+
+//Uses the role codes of HL7 v3 for the relationships that have one
+fn relationship(relationship: Relationship) -> Value {
+    let code = match relationship {
+        Relationship::Spouse => Some(("SPS", "spouse")),
+        Relationship::Partner => Some(("DOMPART", "domestic partner")),
+        Relationship::Parent => Some(("PRN", "parent")),
+        Relationship::Child => Some(("CHILD", "child")),
+        Relationship::Sibling => Some(("SIB", "sibling")),
+        Relationship::Guardian => Some(("GUARD", "guardian")),
+        Relationship::Friend => Some(("FRND", "unrelated friend")),
+        Relationship::NextOfKin => Some(("NOK", "next of kin")),
+        Relationship::Other => None,
+    };
+    match code {
+        Some((code, display)) => json!({
+            "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/v3-RoleCode",
+                "code": code,
+                "display": display,
+            }],
+        }),
+        None => json!({ "text": "Other" }),
+    }
+}
+
+pub(crate) fn patient_resource(patient: &Patient, identifiers: &[ExternalIdentifier]) -> Value {
+    let facility_code = FACILITY_CODE.with(|code| code.borrow().get().clone());
+    let address = &patient.address;
+    let mut resource = json!({
+        "resourceType": "Patient",
+        "id": patient.id.to_string(),
+        "meta": { "versionId": patient.version.to_string() },
+        "active": patient.archived.is_none(),
+        "identifier": identifiers.iter().map(|external| {
+            let mut value = identifier(external);
+            if external.kind == IdentifierKind::MedicalRecordNumber {
+                value["assigner"] = json!({ "display": facility_code });
+            }
+            value
+        }).collect::<Vec<Value>>(),
+        "name": [with_use("official", json!({ "text": patient.name }))],
+        "telecom": telecom(&patient.phone_number, &patient.email),
+        "gender": gender(patient.gender),
+        "address": [with_use("home", json!({
+            "line": [address.street],
+            "city": address.city,
+            "state": address.region,
+            "postalCode": address.postal_code,
+            "country": address.country,
+        }))],
+    });
+    if let Some(birth_date) = fhir_date(&patient.date_of_birth) {
+        resource["birthDate"] = json!(birth_date);
+    }
+    prune(resource).unwrap_or_default()
+}
+
+pub(crate) fn related_person_resource(contact: &RelatedPerson) -> Value {
+    let resource = json!({
+        "resourceType": "RelatedPerson",
+        "id": contact.id.to_string(),
+        "active": true,
+        "patient": reference("Patient", contact.patient_id),
+        "relationship": [relationship(contact.relationship)],
+        "name": [{ "text": contact.name }],
+        "telecom": telecom(&contact.phone_number, &contact.email),
+    });
+    prune(resource).unwrap_or_default()
+}
+
+pub(crate) fn practitioner_resource(doctor: &Doctor) -> Value {
+    let resource = json!({
+        "resourceType": "Practitioner",
+        "id": doctor.id.to_string(),
+        "meta": { "versionId": doctor.version.to_string() },
+        "active": doctor.archived.is_none(),
+        "name": [{ "text": doctor.name }],
+        "telecom": telecom(&doctor.phone_number, &doctor.email),
+        "qualification": [{ "code": { "text": doctor.speciality } }],
+    });
+    prune(resource).unwrap_or_default()
+}
+
+pub(crate) fn location_resource(room: &Room) -> Value {
+    json!({
+        "resourceType": "Location",
+        "id": room.id.to_string(),
+        "meta": { "versionId": room.version.to_string() },
+        "status": if room.archived.is_none() { "active" } else { "inactive" },
+        "name": room.name,
+        "description": room.location,
+        "mode": "instance",
+        "physicalType": {
+            "coding": [{
+                "system": "http://terminology.hl7.org/CodeSystem/location-physical-type",
+                "code": "ro",
+                "display": "Room",
+            }],
+        },
+    })
+}
+
+pub(crate) fn condition_resource(diagnosis: &Diagnosis) -> Value {
+    json!({
+        "resourceType": "Condition",
+        "id": diagnosis.id.to_string(),
+        "meta": { "versionId": diagnosis.version.to_string() },
+        "subject": reference("Patient", diagnosis.patient_id),
+        "asserter": reference("Practitioner", diagnosis.doctor_id),
+        "note": [{ "text": diagnosis.treatment }],
+    })
+}
+
+//A diagnosis without medication has no medication statement
+pub(crate) fn medication_statement_resource(diagnosis: &Diagnosis) -> Option<Value> {
+    if diagnosis.medication.is_empty() {
+        return None;
+    }
+    Some(json!({
+        "resourceType": "MedicationStatement",
+        "id": diagnosis.id.to_string(),
+        "status": "active",
+        "medicationCodeableConcept": { "text": diagnosis.medication },
+        "subject": reference("Patient", diagnosis.patient_id),
+        "informationSource": reference("Practitioner", diagnosis.doctor_id),
+        "reasonReference": [reference("Condition", diagnosis.id)],
+    }))
+}
+
+fn to_json(resource: Value) -> String {
+    serde_json::to_string(&resource).expect("Cannot serialize the FHIR resource")
+}
+
+//Sharing a patient's data with another system needs read access and their consent
+fn shareable_patient(patient_id: u64) -> Result<Patient, Error> {
+    let patient = get_patient(patient_id)?;
+    ensure_can_read_patient(patient.id)?;
+    ensure_consent(patient.id, ConsentScope::DataSharing)?;
+    Ok(project(patient))
+}
+
+fn shareable_diagnosis(diagnosis_id: u64) -> Result<Diagnosis, Error> {
+    let diagnosis = DIAGNOSIS_STORAGE
+        .with(|storage| storage.borrow().get(&diagnosis_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Diagnosis with ID {} not found", diagnosis_id),
+        })?;
+    let _patient = shareable_patient(diagnosis.patient_id)?;
+    Ok(project(diagnosis))
+}
+
+//Retrieves a patient as a FHIR Patient resource
+#[ic_cdk::query]
+fn get_patient_fhir(id: u64) -> Result<String, Error> {
+    let patient = shareable_patient(id)?;
+    Ok(to_json(patient_resource(
+        &patient,
+        &identifiers_of(patient.id),
+    )))
+}
+
+//Retrieves a doctor as a FHIR Practitioner resource
+#[ic_cdk::query]
+fn get_practitioner_fhir(id: u64) -> Result<String, Error> {
+    get_doctor(id).map(|doctor| to_json(practitioner_resource(&doctor)))
+}
+
+//Retrieves a room as a FHIR Location resource
+#[ic_cdk::query]
+fn get_location_fhir(id: u64) -> Result<String, Error> {
+    get_room(id).map(|room| to_json(location_resource(&room)))
+}
+
+//Retrieves a diagnosis as a FHIR Condition resource
+#[ic_cdk::query]
+fn get_condition_fhir(id: u64) -> Result<String, Error> {
+    shareable_diagnosis(id).map(|diagnosis| to_json(condition_resource(&diagnosis)))
+}
+
+//Retrieves the medication of a diagnosis as a FHIR MedicationStatement resource
+#[ic_cdk::query]
+fn get_medication_statement_fhir(id: u64) -> Result<String, Error> {
+    let diagnosis = shareable_diagnosis(id)?;
+    medication_statement_resource(&diagnosis)
+        .map(to_json)
+        .ok_or(Error::NotFound {
+            msg: format!("Diagnosis with ID {} has no medication", id),
+        })
+}
+
+//Exports a patient's whole record as a FHIR collection Bundle
+#[ic_cdk::query]
+fn export_patient_fhir_bundle(patient_id: u64) -> Result<String, Error> {
+    let patient = shareable_patient(patient_id)?;
+
+    let diagnoses: Vec<Diagnosis> = DIAGNOSIS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, diagnosis)| diagnosis.patient_id == patient.id)
+            .map(|(_, diagnosis)| project(diagnosis))
+            .collect()
+    });
+    let mut doctor_ids: Vec<u64> = diagnoses
+        .iter()
+        .map(|diagnosis| diagnosis.doctor_id)
+        .collect();
+    doctor_ids.sort_unstable();
+    doctor_ids.dedup();
+
+    let mut resources = vec![patient_resource(&patient, &identifiers_of(patient.id))];
+    resources.extend(
+        contacts_of(patient.id)
+            .into_iter()
+            .map(|contact| related_person_resource(&project(contact))),
+    );
+    resources.extend(
+        doctor_ids
+            .into_iter()
+            .filter_map(|id| get_doctor(id).ok())
+            .map(|doctor| practitioner_resource(&doctor)),
+    );
+    for diagnosis in &diagnoses {
+        resources.push(condition_resource(diagnosis));
+        resources.extend(medication_statement_resource(diagnosis));
+    }
+
+    Ok(to_json(json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "timestamp": fhir_instant(time()),
+        "entry": resources
+            .into_iter()
+            .map(|resource| json!({ "resource": resource }))
+            .collect::<Vec<Value>>(),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_out_empty_elements() {
+        let patient = Patient {
+            id: 1,
+            name: "Jane Doe".to_string(),
+            ..Default::default()
+        };
+        let resource = patient_resource(&patient, &[]);
+        assert_eq!(
+            resource["name"],
+            json!([{ "use": "official", "text": "Jane Doe" }])
+        );
+        for element in ["identifier", "telecom", "address", "birthDate"] {
+            assert!(resource.get(element).is_none(), "{} is present", element);
+        }
+
+        let contact = RelatedPerson {
+            id: 2,
+            patient_id: 1,
+            name: "John Doe".to_string(),
+            phone_number: "+447700900123".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            related_person_resource(&contact)["telecom"],
+            json!([{ "system": "phone", "value": "+447700900123" }])
+        );
+
+        let doctor = Doctor {
+            id: 3,
+            name: "Dr Smith".to_string(),
+            ..Default::default()
+        };
+        let resource = practitioner_resource(&doctor);
+        assert!(resource.get("telecom").is_none());
+        assert!(resource.get("qualification").is_none());
+    }
+}
@@ -0,0 +1,719 @@
+// Imports patients, doctors and diagnoses from HL7 FHIR R4 bundles exported by other systems
+use crate::identifiers::{
+    check_identifier_part, identifier_owner, link, IdentifierKey, IdentifierKind, MAX_IDENTIFIERS,
+};
+use crate::metrics::observe;
+use crate::migrations::map_ethnicity;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::{
+    cap_review_notes, invalid_field, validate_doctor_payload, validate_imported_patient,
+    FieldError, Validator, MAX_CLINICAL_TEXT_LENGTH,
+};
+use crate::{
+    ensure_admin, record_diagnosis, register_doctor, register_patient, Address,
+    AdministrativeGender, DiagnosisPayload, DoctorPayLoad, Error, PatientPayLoad,
+};
+use candid::CandidType;
+use serde_json::Value;
+
+//Resource types in the order they are mapped: conditions refer to patients and practitioners,
+//and medication statements to conditions
+const IMPORTED_TYPES: [&str; 4] = [
+    "Patient",
+    "Practitioner",
+    "Condition",
+    "MedicationStatement",
+];
+
+//The record a FHIR resource was imported as
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ImportedAs {
+    Patient,
+    Doctor,
+    Diagnosis,
+}
+
+//A resource that was (or, on a dry run, would be) imported
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct ImportedResource {
+    entry: u64,       //Position of the entry in the bundle
+    resource: String, //e.g. Patient/123, as named in the bundle
+    imported_as: ImportedAs,
+    id: Option<u64>,           //Not set on a dry run
+    review_notes: Vec<String>, //Values that could not be mapped and need a manual review
+}
+
+//A resource that could not be imported, and why
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct SkippedResource {
+    entry: u64,
+    resource: String,
+    reason: String,
+    fields: Vec<FieldError>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct FhirImportReport {
+    dry_run: bool,
+    imported: Vec<ImportedResource>,
+    skipped: Vec<SkippedResource>,
+}
+
+//A resource mapped to the payload it will be stored from
+enum Mapped {
+    Patient {
+        payload: PatientPayLoad,
+        identifiers: Vec<IdentifierKey>,
+    },
+    Doctor(DoctorPayLoad),
+    Diagnosis {
+        payload: DiagnosisPayload,
+        patient: usize, //Index of the mapped patient in the plan
+        doctor: usize,  //Index of the mapped doctor in the plan
+    },
+}
+
+struct Planned {
+    entry: u64,
+    resource: String,
+    mapped: Mapped,
+    review_notes: Vec<String>,
+}
+
+struct Entry<'a> {
+    index: u64,
+    name: String,
+    full_url: Option<&'a str>,
+    resource: &'a Value,
+}
+
+impl Entry<'_> {
+    fn resource_type(&self) -> &str {
+        self.resource["resourceType"].as_str().unwrap_or_default()
+    }
+
+    //A reference to this resource may use its fullUrl or its type and ID
+    fn is_referenced_by(&self, reference: &str) -> bool {
+        reference == self.name || self.full_url == Some(reference)
+    }
+
+    fn skip(&self, reason: &str) -> SkippedResource {
+        SkippedResource {
+            entry: self.index,
+            resource: self.name.clone(),
+            reason: reason.to_string(),
+            fields: Vec::new(),
+        }
+    }
+
+    fn skip_invalid(&self, error: Error) -> SkippedResource {
+        match error {
+            Error::ValidationFailed { msg, fields } => SkippedResource {
+                entry: self.index,
+                resource: self.name.clone(),
+                reason: msg,
+                fields,
+            },
+            _ => self.skip("The resource is not valid"),
+        }
+    }
+}
+
+fn text(value: &Value) -> String {
+    value.as_str().unwrap_or_default().trim().to_string()
+}
+
+//The text of a CodeableConcept, or the display of its first coding
+fn concept_text(concept: &Value) -> String {
+    let concept_text = text(&concept["text"]);
+    if !concept_text.is_empty() {
+        return concept_text;
+    }
+    concept["coding"]
+        .as_array()
+        .and_then(|codings| {
+            codings
+                .iter()
+                .map(|coding| text(&coding["display"]))
+                .find(|display| !display.is_empty())
+        })
+        .unwrap_or_default()
+}
+
+//Prefers the element marked with the given use, then the first one
+fn preferred<'a>(elements: &'a Value, preferred_use: &str) -> Option<&'a Value> {
+    let elements = elements.as_array()?;
+    elements
+        .iter()
+        .find(|element| element["use"] == preferred_use)
+        .or_else(|| elements.first())
+}
+
+fn human_name(names: &Value) -> String {
+    let Some(name) = preferred(names, "official") else {
+        return String::new();
+    };
+    let full_name = text(&name["text"]);
+    if !full_name.is_empty() {
+        return full_name;
+    }
+    let mut parts: Vec<String> = name["given"]
+        .as_array()
+        .map(|given| given.iter().map(text).collect())
+        .unwrap_or_default();
+    parts.push(text(&name["family"]));
+    parts.retain(|part| !part.is_empty());
+    parts.join(" ")
+}
+
+//The first contact point of the given system. Phone numbers lose their spaces and punctuation
+fn contact_point(telecom: &Value, systems: &[&str]) -> String {
+    let value = telecom
+        .as_array()
+        .and_then(|telecom| {
+            telecom
+                .iter()
+                .find(|point| systems.iter().any(|system| point["system"] == *system))
+        })
+        .map(|point| text(&point["value"]))
+        .unwrap_or_default();
+    if systems.contains(&"phone") {
+        return value
+            .chars()
+            .filter(|c| c.is_ascii_digit() || *c == '+')
+            .collect();
+    }
+    value
+}
+
+//Converts a FHIR YYYY-MM-DD date to DD-MM-YYYY, leaving anything else for validation to report
+fn date_of_birth(value: &Value) -> String {
+    let value = text(value);
+    let parts: Vec<&str> = value.split('-').collect();
+    match parts.as_slice() {
+        [year, month, day] => format!("{}-{}-{}", day, month, year),
+        _ => value,
+    }
+}
+
+fn gender(value: &Value, review_notes: &mut Vec<String>) -> AdministrativeGender {
+    match value.as_str() {
+        Some("male") => AdministrativeGender::Male,
+        Some("female") => AdministrativeGender::Female,
+        Some("other") => AdministrativeGender::Other,
+        Some("unknown") | None => AdministrativeGender::Unknown,
+        Some(other) => {
+            review_notes.push(format!("gender: could not map '{}'", other));
+            AdministrativeGender::Unknown
+        }
+    }
+}
+
+fn address(addresses: &Value) -> Address {
+    let Some(address) = preferred(addresses, "home") else {
+        return Address::default();
+    };
+    Address {
+        street: address["line"]
+            .as_array()
+            .map(|lines| {
+                lines
+                    .iter()
+                    .map(text)
+                    .filter(|line| !line.is_empty())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            })
+            .unwrap_or_default(),
+        city: text(&address["city"]),
+        region: text(&address["state"]),
+        postal_code: text(&address["postalCode"]),
+        country: text(&address["country"]).to_uppercase(),
+    }
+}
+
+//FHIR has no ethnicity element, so it is read from any extension about ethnicity (e.g. US Core's)
+fn ethnicity(extensions: &Value, review_notes: &mut Vec<String>) -> String {
+    let extension = extensions.as_array().and_then(|extensions| {
+        extensions.iter().find(|extension| {
+            text(&extension["url"])
+                .to_lowercase()
+                .ends_with("ethnicity")
+        })
+    });
+    let Some(extension) = extension else {
+        review_notes.push("ethnicity: not recorded in the imported record".to_string());
+        return String::new();
+    };
+
+    let mut value = text(&extension["valueString"]);
+    if value.is_empty() {
+        value = concept_text(&extension["valueCodeableConcept"]);
+    }
+    if value.is_empty() {
+        // US Core puts the text in a nested "text" extension
+        value = extension["extension"]
+            .as_array()
+            .and_then(|nested| nested.iter().find(|nested| nested["url"] == "text"))
+            .map(|nested| text(&nested["valueString"]))
+            .unwrap_or_default();
+    }
+    map_ethnicity(&value, review_notes)
+}
+
+//Uses the identifier type codes of HL7 v2 table 0203. Other identifiers are left for review
+fn identifiers(
+    values: &Value,
+    plan: &[Planned],
+    review_notes: &mut Vec<String>,
+) -> Vec<IdentifierKey> {
+    let planned_keys: Vec<&IdentifierKey> = plan
+        .iter()
+        .flat_map(|planned| match &planned.mapped {
+            Mapped::Patient { identifiers, .. } => identifiers.iter().collect(),
+            _ => Vec::new(),
+        })
+        .collect();
+    let mut keys: Vec<IdentifierKey> = Vec::new();
+    for value in values.as_array().into_iter().flatten() {
+        let code = value["type"]["coding"]
+            .as_array()
+            .and_then(|codings| codings.iter().find_map(|coding| coding["code"].as_str()));
+        let kind = match code {
+            Some("NI") => IdentifierKind::NationalId,
+            Some("MB") => IdentifierKind::InsuranceMemberNumber,
+            Some("PPN") => IdentifierKind::Passport,
+            _ => {
+                review_notes.push(format!(
+                    "identifier: could not map '{}' from '{}'",
+                    text(&value["value"]),
+                    text(&value["system"])
+                ));
+                continue;
+            }
+        };
+
+        let mut issuer = text(&value["assigner"]["display"]);
+        if issuer.is_empty() {
+            issuer = text(&value["system"]);
+        }
+        let key = IdentifierKey::new(kind, &issuer, &text(&value["value"]));
+        if let Err(msg) =
+            check_identifier_part(&key.issuer).and_then(|_| check_identifier_part(&key.value))
+        {
+            review_notes.push(format!(
+                "identifier: could not map '{}' from '{}': {}",
+                text(&value["value"]),
+                issuer,
+                msg
+            ));
+        } else if let Some(owner) = identifier_owner(&key) {
+            review_notes.push(format!(
+                "identifier: '{}' is already linked to patient with ID {}",
+                text(&value["value"]),
+                owner
+            ));
+        } else if planned_keys.contains(&&key) {
+            review_notes.push(format!(
+                "identifier: '{}' is also given for another patient in the bundle",
+                text(&value["value"])
+            ));
+        } else if keys.len() + 1 >= MAX_IDENTIFIERS {
+            review_notes.push(format!(
+                "identifier: '{}' is over the most identifiers allowed",
+                text(&value["value"])
+            ));
+        } else if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    keys
+}
+
+fn map_patient(entry: &Entry, plan: &[Planned]) -> Result<Planned, SkippedResource> {
+    let resource = entry.resource;
+    let mut review_notes = Vec::new();
+    let payload = PatientPayLoad {
+        name: human_name(&resource["name"]),
+        date_of_birth: date_of_birth(&resource["birthDate"]),
+        gender: gender(&resource["gender"], &mut review_notes),
+        ethnicity: ethnicity(&resource["extension"], &mut review_notes),
+        address: address(&resource["address"]),
+        phone_number: contact_point(&resource["telecom"], &["phone", "sms"]),
+        email: contact_point(&resource["telecom"], &["email"]),
+    };
+    validate_imported_patient(&payload).map_err(|error| entry.skip_invalid(error))?;
+
+    let identifiers = identifiers(&resource["identifier"], plan, &mut review_notes);
+    // Capped here, so that a dry run reports the notes an import would store
+    cap_review_notes(&mut review_notes);
+    Ok(Planned {
+        entry: entry.index,
+        resource: entry.name.clone(),
+        mapped: Mapped::Patient {
+            payload,
+            identifiers,
+        },
+        review_notes,
+    })
+}
+
+fn map_practitioner(entry: &Entry) -> Result<Planned, SkippedResource> {
+    let resource = entry.resource;
+    let speciality = resource["qualification"]
+        .as_array()
+        .and_then(|qualifications| {
+            qualifications
+                .iter()
+                .map(|qualification| concept_text(&qualification["code"]))
+                .find(|speciality| !speciality.is_empty())
+        })
+        .unwrap_or_default();
+    let payload = DoctorPayLoad {
+        name: human_name(&resource["name"]),
+        email: contact_point(&resource["telecom"], &["email"]),
+        phone_number: contact_point(&resource["telecom"], &["phone", "sms"]),
+        speciality,
+    };
+    validate_doctor_payload(&payload).map_err(|error| entry.skip_invalid(error))?;
+
+    Ok(Planned {
+        entry: entry.index,
+        resource: entry.name.clone(),
+        mapped: Mapped::Doctor(payload),
+        review_notes: Vec::new(),
+    })
+}
+
+//Finds the planned record of the given kind that the reference points to
+fn resolve(
+    reference: &Value,
+    plan: &[Planned],
+    entries: &[Entry],
+    is_kind: impl Fn(&Mapped) -> bool,
+) -> Option<usize> {
+    let reference = reference["reference"].as_str()?;
+    plan.iter().position(|planned| {
+        is_kind(&planned.mapped)
+            && entries
+                .iter()
+                .any(|entry| entry.index == planned.entry && entry.is_referenced_by(reference))
+    })
+}
+
+fn map_condition(
+    entry: &Entry,
+    entries: &[Entry],
+    plan: &[Planned],
+) -> Result<Planned, SkippedResource> {
+    let resource = entry.resource;
+    let patient = resolve(&resource["subject"], plan, entries, |mapped| {
+        matches!(mapped, Mapped::Patient { .. })
+    })
+    .ok_or_else(|| entry.skip("The subject is not a patient imported from this bundle"))?;
+    let doctor = [&resource["asserter"], &resource["recorder"]]
+        .into_iter()
+        .find_map(|reference| {
+            resolve(reference, plan, entries, |mapped| {
+                matches!(mapped, Mapped::Doctor(_))
+            })
+        })
+        .ok_or_else(|| {
+            entry.skip("The asserter or recorder is not a practitioner imported from this bundle")
+        })?;
+
+    let mut treatment = concept_text(&resource["code"]);
+    let notes: Vec<String> = resource["note"]
+        .as_array()
+        .map(|notes| notes.iter().map(|note| text(&note["text"])).collect())
+        .unwrap_or_default();
+    for note in notes.into_iter().filter(|note| !note.is_empty()) {
+        if treatment.is_empty() {
+            treatment = note;
+        } else {
+            treatment = format!("{}; {}", treatment, note);
+        }
+    }
+    if treatment.is_empty() {
+        return Err(entry.skip_invalid(invalid_field(
+            "treatment",
+            "The condition has no code or note to import as the treatment",
+        )));
+    }
+    Validator::default()
+        .limit("treatment", &treatment, MAX_CLINICAL_TEXT_LENGTH)
+        .finish()
+        .map_err(|error| entry.skip_invalid(error))?;
+
+    Ok(Planned {
+        entry: entry.index,
+        resource: entry.name.clone(),
+        mapped: Mapped::Diagnosis {
+            payload: DiagnosisPayload {
+                treatment,
+                ..Default::default()
+            },
+            patient,
+            doctor,
+        },
+        review_notes: Vec::new(),
+    })
+}
+
+//Adds the medication to the diagnosis imported from the condition it was taken for
+fn map_medication_statement(
+    entry: &Entry,
+    entries: &[Entry],
+    plan: &mut [Planned],
+) -> Result<(), SkippedResource> {
+    let resource = entry.resource;
+    let medication = concept_text(&resource["medicationCodeableConcept"]);
+    if medication.is_empty() {
+        return Err(entry.skip_invalid(invalid_field(
+            "medication",
+            "Only medications given as a CodeableConcept can be imported",
+        )));
+    }
+
+    let diagnosis = resource["reasonReference"]
+        .as_array()
+        .and_then(|references| {
+            references.iter().find_map(|reference| {
+                resolve(reference, plan, entries, |mapped| {
+                    matches!(mapped, Mapped::Diagnosis { .. })
+                })
+            })
+        })
+        .ok_or_else(|| entry.skip("The reason is not a condition imported from this bundle"))?;
+
+    if let Mapped::Diagnosis { payload, .. } = &mut plan[diagnosis].mapped {
+        let medication = if payload.medication.is_empty() {
+            medication
+        } else {
+            format!("{}; {}", payload.medication, medication)
+        };
+        // Checked with the medications before it, as they are stored together
+        Validator::default()
+            .limit("medication", &medication, MAX_CLINICAL_TEXT_LENGTH)
+            .finish()
+            .map_err(|error| entry.skip_invalid(error))?;
+        payload.medication = medication;
+    }
+    Ok(())
+}
+
+//Stores the planned records in order, so diagnoses can use the IDs of their patient and doctor
+fn store(plan: Vec<Planned>) -> Vec<ImportedResource> {
+    let mut ids: Vec<u64> = Vec::with_capacity(plan.len());
+    let mut imported = Vec::with_capacity(plan.len());
+    for planned in plan {
+        let (imported_as, id) = match planned.mapped {
+            Mapped::Patient {
+                payload,
+                identifiers,
+            } => {
+                let patient = register_patient(payload, planned.review_notes.clone());
+                for key in identifiers {
+                    link(patient.id, key);
+                }
+                (ImportedAs::Patient, patient.id)
+            }
+            Mapped::Doctor(payload) => (ImportedAs::Doctor, register_doctor(payload).id),
+            Mapped::Diagnosis {
+                mut payload,
+                patient,
+                doctor,
+            } => {
+                payload.patient_id = ids[patient];
+                payload.doctor_id = ids[doctor];
+                (ImportedAs::Diagnosis, record_diagnosis(payload).id)
+            }
+        };
+        ids.push(id);
+        imported.push(ImportedResource {
+            entry: planned.entry,
+            resource: planned.resource,
+            imported_as,
+            id: Some(id),
+            review_notes: planned.review_notes,
+        });
+    }
+    imported
+}
+
+//Imports the patients, practitioners, conditions and medication statements of a FHIR Bundle.
+//Resources that can not be mapped are reported and skipped. A dry run only validates
+#[ic_cdk::update]
+fn import_fhir_bundle(bundle: String, dry_run: bool) -> Result<FhirImportReport, Error> {
+    observe("import_fhir_bundle", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        let bundle: Value = serde_json::from_str(&bundle)
+            .map_err(|error| invalid_field("bundle", &format!("Not valid JSON: {}", error)))?;
+        if bundle["resourceType"] != "Bundle" {
+            return Err(invalid_field(
+                "bundle",
+                "The resource must be a FHIR Bundle",
+            ));
+        }
+
+        let entries: Vec<Entry> = bundle["entry"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .map(|(index, entry)| {
+                let resource = &entry["resource"];
+                Entry {
+                    index: index as u64,
+                    name: format!(
+                        "{}/{}",
+                        text(&resource["resourceType"]),
+                        text(&resource["id"])
+                    ),
+                    full_url: entry["fullUrl"].as_str(),
+                    resource,
+                }
+            })
+            .collect();
+
+        let mut plan: Vec<Planned> = Vec::new();
+        let mut skipped: Vec<SkippedResource> = Vec::new();
+        for pass in IMPORTED_TYPES {
+            for entry in entries.iter().filter(|entry| entry.resource_type() == pass) {
+                let mapped = match pass {
+                    "Patient" => map_patient(entry, &plan).map(Some),
+                    "Practitioner" => map_practitioner(entry).map(Some),
+                    "Condition" => map_condition(entry, &entries, &plan).map(Some),
+                    _ => map_medication_statement(entry, &entries, &mut plan).map(|_| None),
+                };
+                match mapped {
+                    Ok(Some(planned)) => plan.push(planned),
+                    Ok(None) => {}
+                    Err(skip) => skipped.push(skip),
+                }
+            }
+        }
+
+        for entry in entries
+            .iter()
+            .filter(|entry| !IMPORTED_TYPES.contains(&entry.resource_type()))
+        {
+            skipped.push(entry.skip(&format!(
+                "Resources of type '{}' are not imported",
+                entry.resource_type()
+            )));
+        }
+        skipped.sort_by_key(|skip| skip.entry);
+
+        let imported = if dry_run {
+            plan.into_iter()
+                .map(|planned| ImportedResource {
+                    entry: planned.entry,
+                    resource: planned.resource,
+                    imported_as: match planned.mapped {
+                        Mapped::Patient { .. } => ImportedAs::Patient,
+                        Mapped::Doctor(_) => ImportedAs::Doctor,
+                        Mapped::Diagnosis { .. } => ImportedAs::Diagnosis,
+                    },
+                    id: None,
+                    review_notes: planned.review_notes,
+                })
+                .collect()
+        } else {
+            store(plan)
+        };
+
+        Ok(FhirImportReport {
+            dry_run,
+            imported,
+            skipped,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry<'a>(index: u64, name: &str, resource: &'a Value) -> Entry<'a> {
+        Entry {
+            index,
+            name: name.to_string(),
+            full_url: None,
+            resource,
+        }
+    }
+
+    #[test]
+    fn skips_clinical_text_that_is_too_long_to_store() {
+        let patient = json!({ "resourceType": "Patient" });
+        let practitioner = json!({ "resourceType": "Practitioner" });
+        let condition = |note: &str| {
+            json!({
+                "resourceType": "Condition",
+                "subject": { "reference": "Patient/1" },
+                "asserter": { "reference": "Practitioner/2" },
+                "note": [{ "text": note }],
+            })
+        };
+        let statement = |medication: &str| {
+            json!({
+                "resourceType": "MedicationStatement",
+                "medicationCodeableConcept": { "text": medication },
+                "reasonReference": [{ "reference": "Condition/3" }],
+            })
+        };
+        let long_condition = condition(&"x".repeat(MAX_CLINICAL_TEXT_LENGTH + 1));
+        let short_condition = condition("Rest");
+        let first_statement = statement(&"x".repeat(MAX_CLINICAL_TEXT_LENGTH - 10));
+        let second_statement = statement("Ibuprofen");
+
+        let mut entries = vec![
+            entry(0, "Patient/1", &patient),
+            entry(1, "Practitioner/2", &practitioner),
+            entry(2, "Condition/3", &long_condition),
+        ];
+        let mut plan = vec![
+            Planned {
+                entry: 0,
+                resource: "Patient/1".to_string(),
+                mapped: Mapped::Patient {
+                    payload: PatientPayLoad {
+                        name: "Jane Doe".to_string(),
+                        date_of_birth: "01-02-1990".to_string(),
+                        gender: AdministrativeGender::Female,
+                        ethnicity: String::new(),
+                        address: Address::default(),
+                        phone_number: "+447700900123".to_string(),
+                        email: String::new(),
+                    },
+                    identifiers: Vec::new(),
+                },
+                review_notes: Vec::new(),
+            },
+            Planned {
+                entry: 1,
+                resource: "Practitioner/2".to_string(),
+                mapped: Mapped::Doctor(DoctorPayLoad {
+                    name: "Dr Smith".to_string(),
+                    email: String::new(),
+                    phone_number: "+447700900456".to_string(),
+                    speciality: "Cardiology".to_string(),
+                }),
+                review_notes: Vec::new(),
+            },
+        ];
+        assert!(map_condition(&entries[2], &entries, &plan).is_err());
+
+        entries[2] = entry(2, "Condition/3", &short_condition);
+        let planned = map_condition(&entries[2], &entries, &plan).ok().unwrap();
+        plan.push(planned);
+        let first = entry(3, "MedicationStatement/4", &first_statement);
+        let second = entry(4, "MedicationStatement/5", &second_statement);
+        assert!(map_medication_statement(&first, &entries, &mut plan).is_ok());
+        assert!(map_medication_statement(&second, &entries, &mut plan).is_err());
+    }
+}
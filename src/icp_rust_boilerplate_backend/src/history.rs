@@ -0,0 +1,316 @@
+// Keeps every saved version of patients, doctors and rooms
+use crate::access::ensure_can_read_patient;
+use crate::redaction::{caller_audience, hidden_fields, project, RecordKind};
+use crate::validation::invalid_field;
+use crate::{Doctor, Error, Memory, Patient, Room, DOCTOR_HISTORY, PATIENT_HISTORY, ROOM_HISTORY};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use std::{borrow::Cow, cell::RefCell, thread::LocalKey};
+
+//Most revisions returned by one history query, so that the reply stays under the size limit
+const MAX_PAGE_SIZE: u64 = 50;
+
+//History entries are keyed by (record ID, version)
+pub(crate) type History<T> = StableBTreeMap<(u64, u64), Revision<T>, Memory>;
+type HistoryStore<T> = &'static LocalKey<RefCell<History<T>>>;
+
+//A saved version of a record, with who saved it and when. `revision` is the record's version
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Revision<T> {
+    revision: u64,
+    record: T,
+    edited_by: Principal,
+    edited_on: u64,
+}
+
+impl<T: CandidType + DeserializeOwned> Storable for Revision<T> {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl<T: CandidType + DeserializeOwned> BoundedStorable for Revision<T> {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl<T> Revision<T> {
+    //Converts the stored record, keeping the revision details
+    pub(crate) fn map<U>(self, convert: impl FnOnce(T) -> U) -> Revision<U> {
+        Revision {
+            revision: self.revision,
+            record: convert(self.record),
+            edited_by: self.edited_by,
+            edited_on: self.edited_on,
+        }
+    }
+}
+
+//A single field that differs between two revisions, with both values rendered as JSON
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct FieldChange {
+    field: String,
+    old_value: String,
+    new_value: String,
+}
+
+//Stores the given version of the record with the given ID
+pub(crate) fn record_revision<T>(history: HistoryStore<T>, id: u64, revision: u64, record: &T)
+where
+    T: CandidType + DeserializeOwned + Clone,
+{
+    history.with(|history| {
+        history.borrow_mut().insert(
+            (id, revision),
+            Revision {
+                revision,
+                record: record.clone(),
+                edited_by: caller(),
+                edited_on: time(),
+            },
+        )
+    });
+}
+
+//Removes every revision of the record with the given ID
+pub(crate) fn remove_history<T>(history: HistoryStore<T>, id: u64)
+where
+    T: CandidType + DeserializeOwned,
+{
+    history.with(|history| {
+        let mut history = history.borrow_mut();
+        let keys: Vec<(u64, u64)> = history
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            history.remove(&key);
+        }
+    });
+}
+
+//Rewrites every stored revision of the record, keeping who saved each one and when
+pub(crate) fn rewrite_history<T>(history: HistoryStore<T>, id: u64, rewrite: impl Fn(T) -> T)
+where
+    T: CandidType + DeserializeOwned,
+{
+    for revision in revisions_of(history, id) {
+        let key = (id, revision.revision);
+        let revision = revision.map(&rewrite);
+        history.with(|history| history.borrow_mut().insert(key, revision));
+    }
+}
+
+pub(crate) fn revisions_of<T>(history: HistoryStore<T>, id: u64) -> Vec<Revision<T>>
+where
+    T: CandidType + DeserializeOwned,
+{
+    history.with(|history| {
+        history
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, revision)| revision)
+            .collect()
+    })
+}
+
+fn ensure_has_history<T>(history: HistoryStore<T>, id: u64, name: &str) -> Result<(), Error>
+where
+    T: CandidType + DeserializeOwned,
+{
+    let found = history.with(|history| {
+        history
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .next()
+            .is_some()
+    });
+    if !found {
+        return Err(Error::NotFound {
+            msg: format!("No history found for {} with ID {}", name, id),
+        });
+    }
+    Ok(())
+}
+
+//Retrieves up to `limit` revisions of the record, oldest first, skipping the first `offset`
+fn revisions_page<T>(
+    history: HistoryStore<T>,
+    id: u64,
+    offset: u64,
+    limit: u64,
+    name: &str,
+) -> Result<Vec<Revision<T>>, Error>
+where
+    T: CandidType + DeserializeOwned,
+{
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(invalid_field(
+            "limit",
+            &format!("Must be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+    ensure_has_history(history, id, name)?;
+    Ok(history.with(|history| {
+        history
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(_, revision)| revision)
+            .collect()
+    }))
+}
+
+//Finds the revision that was current at the given timestamp
+fn revision_as_of<T>(
+    history: HistoryStore<T>,
+    id: u64,
+    timestamp: u64,
+    name: &str,
+) -> Result<Revision<T>, Error>
+where
+    T: CandidType + DeserializeOwned,
+{
+    ensure_has_history(history, id, name)?;
+    history
+        .with(|history| {
+            history
+                .borrow()
+                .range((id, 0)..=(id, u64::MAX))
+                .map(|(_, revision)| revision)
+                .take_while(|revision| revision.edited_on <= timestamp)
+                .last()
+        })
+        .ok_or(Error::NotFound {
+            msg: format!("{} with ID {} did not exist at {}", name, id, timestamp),
+        })
+}
+
+fn revision<T>(history: HistoryStore<T>, id: u64, revision: u64, name: &str) -> Result<T, Error>
+where
+    T: CandidType + DeserializeOwned,
+{
+    history
+        .with(|history| history.borrow().get(&(id, revision)))
+        .map(|revision| revision.record)
+        .ok_or(Error::NotFound {
+            msg: format!("Revision {} of {} with ID {} not found", revision, name, id),
+        })
+}
+
+//Compares two revisions field by field
+fn diff_revisions<T>(
+    history: HistoryStore<T>,
+    id: u64,
+    from_revision: u64,
+    to_revision: u64,
+    name: &str,
+) -> Result<Vec<FieldChange>, Error>
+where
+    T: CandidType + DeserializeOwned + serde::Serialize,
+{
+    let old = serde_json::to_value(revision(history, id, from_revision, name)?)
+        .expect("Cannot serialize the old revision");
+    let new = serde_json::to_value(revision(history, id, to_revision, name)?)
+        .expect("Cannot serialize the new revision");
+
+    let (Some(old), Some(new)) = (old.as_object(), new.as_object()) else {
+        return Ok(Vec::new());
+    };
+
+    Ok(new
+        .iter()
+        .filter(|(field, value)| old.get(*field) != Some(*value))
+        .map(|(field, value)| FieldChange {
+            field: field.clone(),
+            old_value: old
+                .get(field)
+                .map(|old| old.to_string())
+                .unwrap_or_default(),
+            new_value: value.to_string(),
+        })
+        .collect())
+}
+
+//Retrieves a page of the saved versions of a patient, oldest first
+#[ic_cdk::query]
+fn get_patient_history(id: u64, offset: u64, limit: u64) -> Result<Vec<Revision<Patient>>, Error> {
+    ensure_can_read_patient(id)?;
+    Ok(
+        revisions_page(&PATIENT_HISTORY, id, offset, limit, "Patient")?
+            .into_iter()
+            .map(|revision| revision.map(project))
+            .collect(),
+    )
+}
+
+//Retrieves the patient as it was at the given timestamp
+#[ic_cdk::query]
+fn get_patient_as_of(id: u64, timestamp: u64) -> Result<Revision<Patient>, Error> {
+    ensure_can_read_patient(id)?;
+    revision_as_of(&PATIENT_HISTORY, id, timestamp, "Patient").map(|revision| revision.map(project))
+}
+
+//Lists the fields that changed between two revisions of a patient
+#[ic_cdk::query]
+fn get_patient_diff(
+    id: u64,
+    from_revision: u64,
+    to_revision: u64,
+) -> Result<Vec<FieldChange>, Error> {
+    ensure_can_read_patient(id)?;
+    let hidden = hidden_fields(caller_audience(), RecordKind::Patient);
+    Ok(
+        diff_revisions(&PATIENT_HISTORY, id, from_revision, to_revision, "Patient")?
+            .into_iter()
+            .filter(|change| !hidden.contains(&change.field))
+            .collect(),
+    )
+}
+
+//Retrieves a page of the saved versions of a doctor, oldest first
+#[ic_cdk::query]
+fn get_doctor_history(id: u64, offset: u64, limit: u64) -> Result<Vec<Revision<Doctor>>, Error> {
+    revisions_page(&DOCTOR_HISTORY, id, offset, limit, "Doctor")
+}
+
+//Retrieves the doctor as it was at the given timestamp
+#[ic_cdk::query]
+fn get_doctor_as_of(id: u64, timestamp: u64) -> Result<Revision<Doctor>, Error> {
+    revision_as_of(&DOCTOR_HISTORY, id, timestamp, "Doctor")
+}
+
+//Lists the fields that changed between two revisions of a doctor
+#[ic_cdk::query]
+fn get_doctor_diff(
+    id: u64,
+    from_revision: u64,
+    to_revision: u64,
+) -> Result<Vec<FieldChange>, Error> {
+    diff_revisions(&DOCTOR_HISTORY, id, from_revision, to_revision, "Doctor")
+}
+
+//Retrieves a page of the saved versions of a room, oldest first
+#[ic_cdk::query]
+fn get_room_history(id: u64, offset: u64, limit: u64) -> Result<Vec<Revision<Room>>, Error> {
+    revisions_page(&ROOM_HISTORY, id, offset, limit, "Room")
+}
+
+//Retrieves the room as it was at the given timestamp
+#[ic_cdk::query]
+fn get_room_as_of(id: u64, timestamp: u64) -> Result<Revision<Room>, Error> {
+    revision_as_of(&ROOM_HISTORY, id, timestamp, "Room")
+}
+
+//Lists the fields that changed between two revisions of a room
+#[ic_cdk::query]
+fn get_room_diff(id: u64, from_revision: u64, to_revision: u64) -> Result<Vec<FieldChange>, Error> {
+    diff_revisions(&ROOM_HISTORY, id, from_revision, to_revision, "Room")
+}
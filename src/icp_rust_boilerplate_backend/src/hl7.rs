@@ -0,0 +1,1080 @@
+// Ingests HL7 v2 messages from admission systems (ADT) and lab analysers (ORU) and acknowledges them
+use crate::access::{staff_role, StaffRole};
+use crate::encounters::{Encounter, LabResult, Observation};
+use crate::identifiers::{
+    check_identifier_part, identifier_owner, identifiers_of, link, IdentifierKey, IdentifierKind,
+    MAX_IDENTIFIERS, MAX_IDENTIFIER_PART_LENGTH,
+};
+use crate::metrics::observe;
+use crate::migrations::map_ethnicity;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::{
+    cap_review_notes, invalid_field, validate_imported_patient, Date, Validator, MAX_CODE_LENGTH,
+    MAX_TEXT_LENGTH,
+};
+use crate::{
+    ensure_admin, get_patient, next_id, register_patient, save_patient, Address,
+    AdministrativeGender, Error, Patient, PatientPayLoad, DEAD_LETTERS, DEAD_LETTER_ID_COUNTER,
+    ENCOUNTERS, ENCOUNTER_ID_COUNTER, FACILITY_CODE, LAB_RESULTS, LAB_RESULT_ID_COUNTER,
+    ROOM_STORAGE,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, is_controller, time};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//Longest message accepted, and longest error kept with it, in bytes. Errors can quote the
+//message, so they are cut short. Together they leave room for the other fields of a dead letter
+//in DeadLetter::MAX_SIZE
+const MAX_MESSAGE_LENGTH: usize = 16 * 1024;
+const MAX_ERROR_LENGTH: usize = 1024;
+
+//Identifies this canister as the sender of acknowledgements
+const SENDING_APPLICATION: &str = "HMS";
+const DEFAULT_VERSION: &str = "2.5";
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+//Error codes from HL7 table 0357
+const SEGMENT_SEQUENCE_ERROR: &str = "100^Segment sequence error^HL70357";
+const REQUIRED_FIELD_MISSING: &str = "101^Required field missing^HL70357";
+const DATA_TYPE_ERROR: &str = "102^Data type error^HL70357";
+const UNSUPPORTED_MESSAGE_TYPE: &str = "200^Unsupported message type^HL70357";
+const UNSUPPORTED_EVENT_CODE: &str = "201^Unsupported event code^HL70357";
+const UNKNOWN_KEY_IDENTIFIER: &str = "204^Unknown key identifier^HL70357";
+const DUPLICATE_KEY_IDENTIFIER: &str = "205^Duplicate key identifier^HL70357";
+
+//A message that could not be applied, kept so that it can be reprocessed
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DeadLetter {
+    id: u64,
+    message: String,
+    error: String,
+    received_from: Principal,
+    received_on: u64,
+    attempts: u32,
+}
+
+impl Storable for DeadLetter {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DeadLetter {
+    const MAX_SIZE: u32 = 20 * 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Why a message could not be applied. Rejected messages can not be processed here at all
+struct Failure {
+    code: &'static str,
+    text: String,
+    rejected: bool,
+}
+
+impl Failure {
+    fn error(code: &'static str, text: impl Into<String>) -> Self {
+        Failure {
+            code,
+            text: text.into(),
+            rejected: false,
+        }
+    }
+
+    fn rejection(code: &'static str, text: impl Into<String>) -> Self {
+        Failure {
+            code,
+            text: text.into(),
+            rejected: true,
+        }
+    }
+
+    fn invalid(error: Error) -> Self {
+        Failure::error(DATA_TYPE_ERROR, error.message())
+    }
+}
+
+//The delimiters declared in MSH-1 and MSH-2
+#[derive(Clone, Copy)]
+struct Separators {
+    field: char,
+    component: char,
+    repetition: char,
+    escape: char,
+    subcomponent: char,
+}
+
+impl Default for Separators {
+    fn default() -> Self {
+        Separators {
+            field: '|',
+            component: '^',
+            repetition: '~',
+            escape: '\\',
+            subcomponent: '&',
+        }
+    }
+}
+
+impl Separators {
+    //Replaces escape sequences with the delimiters they stand for. Formatting sequences are dropped
+    fn unescape(&self, value: &str) -> String {
+        let mut parts = value.split(self.escape);
+        let mut unescaped = parts.next().unwrap_or_default().to_string();
+        // Text and escape sequences alternate: text \F\ text \S\ text
+        for (index, part) in parts.enumerate() {
+            if index % 2 == 1 {
+                unescaped.push_str(part);
+                continue;
+            }
+            match part {
+                "F" => unescaped.push(self.field),
+                "S" => unescaped.push(self.component),
+                "R" => unescaped.push(self.repetition),
+                "E" => unescaped.push(self.escape),
+                "T" => unescaped.push(self.subcomponent),
+                _ => {}
+            }
+        }
+        unescaped
+    }
+
+    fn escape(&self, value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            let sequence = match c {
+                c if c == self.escape => "E",
+                c if c == self.field => "F",
+                c if c == self.component => "S",
+                c if c == self.repetition => "R",
+                c if c == self.subcomponent => "T",
+                '\r' | '\n' => {
+                    escaped.push(' ');
+                    continue;
+                }
+                c => {
+                    escaped.push(c);
+                    continue;
+                }
+            };
+            escaped.push(self.escape);
+            escaped.push_str(sequence);
+            escaped.push(self.escape);
+        }
+        escaped
+    }
+
+    //Component `index` (from 1) of a field or repetition, unescaped and without subcomponents
+    fn component(&self, value: &str, index: usize) -> String {
+        value
+            .split(self.component)
+            .nth(index - 1)
+            .and_then(|component| component.split(self.subcomponent).next())
+            .map(|component| self.unescape(component).trim().to_string())
+            .unwrap_or_default()
+    }
+}
+
+struct Segment {
+    id: String,
+    fields: Vec<String>,
+    separators: Separators,
+}
+
+impl Segment {
+    //The raw value of a field, numbered as in the HL7 standard
+    fn field(&self, number: usize) -> &str {
+        // MSH-1 is the field separator itself, so the MSH fields are shifted by one
+        let index = if self.id == "MSH" { number - 1 } else { number };
+        self.fields
+            .get(index)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    fn repetitions(&self, number: usize) -> impl Iterator<Item = &str> {
+        self.field(number)
+            .split(self.separators.repetition)
+            .filter(|repetition| !repetition.is_empty())
+    }
+
+    //A component of the first repetition of a field
+    fn component(&self, number: usize, component: usize) -> String {
+        let first = self.repetitions(number).next().unwrap_or_default();
+        self.separators.component(first, component)
+    }
+
+    fn value(&self, number: usize) -> String {
+        self.component(number, 1)
+    }
+}
+
+struct Message {
+    segments: Vec<Segment>,
+}
+
+impl Message {
+    fn parse(text: &str) -> Result<Self, Failure> {
+        let text = text.trim_start();
+        let Some(header) = text.strip_prefix("MSH") else {
+            return Err(Failure::rejection(
+                SEGMENT_SEQUENCE_ERROR,
+                "The message must start with an MSH segment",
+            ));
+        };
+
+        let mut delimiters = header.chars();
+        let field = delimiters.next().ok_or_else(|| {
+            Failure::rejection(REQUIRED_FIELD_MISSING, "MSH-1 (field separator) is missing")
+        })?;
+        let encoding: Vec<char> = delimiters.take_while(|c| *c != field).collect();
+        let separators = match encoding.as_slice() {
+            [component, repetition, escape, subcomponent, ..] => Separators {
+                field,
+                component: *component,
+                repetition: *repetition,
+                escape: *escape,
+                subcomponent: *subcomponent,
+            },
+            _ => Separators {
+                field,
+                ..Default::default()
+            },
+        };
+
+        let segments: Vec<Segment> = text
+            .split(['\r', '\n'])
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let fields: Vec<String> =
+                    line.split(separators.field).map(str::to_string).collect();
+                Segment {
+                    id: fields[0].clone(),
+                    fields,
+                    separators,
+                }
+            })
+            .collect();
+        Ok(Message { segments })
+    }
+
+    fn header(&self) -> &Segment {
+        &self.segments[0]
+    }
+
+    fn segment(&self, id: &str) -> Result<&Segment, Failure> {
+        self.segments
+            .iter()
+            .find(|segment| segment.id == id)
+            .ok_or_else(|| {
+                Failure::error(
+                    SEGMENT_SEQUENCE_ERROR,
+                    format!("The message has no {} segment", id),
+                )
+            })
+    }
+
+    //When the message was created (MSH-7), or now when it does not say
+    fn created_on(&self) -> u64 {
+        timestamp(self.header().field(7)).unwrap_or_else(time)
+    }
+}
+
+//Converts an HL7 date/time (YYYYMMDD[HHMM[SS[.S]]][+/-ZZZZ]) to nanoseconds since the Unix epoch
+fn timestamp(value: &str) -> Option<u64> {
+    let value = value.split('^').next().unwrap_or_default().trim();
+    let (local, offset) = match value.find(['+', '-']) {
+        Some(index) => (&value[..index], Some(&value[index..])),
+        None => (value, None),
+    };
+    let local = local.split('.').next().unwrap_or_default();
+    if local.len() < 8 || !local.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let date = Date::parse(&format!(
+        "{}-{}-{}",
+        &local[6..8],
+        &local[4..6],
+        &local[..4]
+    ))
+    .ok()?;
+    let number = |from: usize| {
+        local
+            .get(from..from + 2)
+            .and_then(|part| part.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    let seconds = number(8) * 3600 + number(10) * 60 + number(12);
+    let timestamp = date.to_timestamp() + seconds * NANOS_PER_SECOND;
+
+    // Timestamps are stored in UTC, so the offset from UTC is taken away
+    let offset_seconds = offset
+        .and_then(|offset| {
+            let hours = offset.get(1..3)?.parse::<u64>().ok()?;
+            let minutes = offset
+                .get(3..5)
+                .and_then(|part| part.parse::<u64>().ok())
+                .unwrap_or(0);
+            Some((offset.starts_with('-'), (hours * 60 + minutes) * 60))
+        })
+        .unwrap_or((false, 0));
+    match offset_seconds {
+        (true, seconds) => Some(timestamp + seconds * NANOS_PER_SECOND),
+        (false, seconds) => Some(timestamp.saturating_sub(seconds * NANOS_PER_SECOND)),
+    }
+}
+
+//Formats a timestamp in nanoseconds as an HL7 date/time in UTC
+fn hl7_timestamp(timestamp: u64) -> String {
+    let seconds_of_day = timestamp / NANOS_PER_SECOND % (24 * 60 * 60);
+    format!(
+        "{}{:02}{:02}{:02}+0000",
+        Date::from_timestamp(timestamp).to_iso().replace('-', ""),
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+//Builds the ACK for a message: AA when it was applied, AE when it failed, AR when it was rejected
+fn acknowledgement(message: Option<&Message>, failure: Option<&Failure>) -> String {
+    let separators = Separators::default();
+    let header = message.map(Message::header);
+    let echo = |number: usize| {
+        header
+            .map(|header| separators.escape(&header.value(number)))
+            .unwrap_or_default()
+    };
+    let control_id = echo(10);
+    let version = match echo(12) {
+        version if version.is_empty() => DEFAULT_VERSION.to_string(),
+        version => version,
+    };
+    let trigger = header
+        .map(|header| separators.escape(&header.component(9, 2)))
+        .unwrap_or_default();
+    let facility_code = FACILITY_CODE.with(|code| code.borrow().get().clone());
+
+    let mut segments = vec![
+        format!(
+            "MSH|^~\\&|{}|{}|{}|{}|{}||ACK^{}^ACK|ACK{}|P|{}",
+            SENDING_APPLICATION,
+            separators.escape(&facility_code),
+            echo(3),
+            echo(4),
+            hl7_timestamp(time()),
+            trigger,
+            control_id,
+            version
+        ),
+        format!(
+            "MSA|{}|{}",
+            match failure {
+                None => "AA",
+                Some(failure) if failure.rejected => "AR",
+                Some(_) => "AE",
+            },
+            control_id
+        ),
+    ];
+    if let Some(failure) = failure {
+        segments.push(format!(
+            "ERR|||{}|E||||{}",
+            failure.code,
+            separators.escape(&failure.text)
+        ));
+    }
+    segments.join("\r")
+}
+
+//Identifiers in PID-3 that can be matched to ours. Other identifiers and unknown MRNs are
+//noted for review
+fn patient_identifiers(pid: &Segment, review_notes: &mut Vec<String>) -> Vec<IdentifierKey> {
+    let separators = pid.separators;
+    let mut keys = Vec::new();
+    for identifier in pid.repetitions(3) {
+        let value = separators.component(identifier, 1);
+        let authority = separators.component(identifier, 4);
+        let key = match separators.component(identifier, 5).as_str() {
+            "MR" if identifier_owner(&IdentifierKey::mrn(&value)).is_none() => {
+                review_notes.push(format!("identifier: MRN '{}' is not known", value));
+                continue;
+            }
+            "MR" => IdentifierKey::mrn(&value),
+            "NI" => IdentifierKey::new(IdentifierKind::NationalId, &authority, &value),
+            "MB" => IdentifierKey::new(IdentifierKind::InsuranceMemberNumber, &authority, &value),
+            "PPN" => IdentifierKey::new(IdentifierKind::Passport, &authority, &value),
+            other => {
+                review_notes.push(format!(
+                    "identifier: could not map '{}' of type '{}'",
+                    value, other
+                ));
+                continue;
+            }
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+//The patient the message is about, found by the identifiers in PID-3
+fn find_patient(keys: &[IdentifierKey]) -> Option<Patient> {
+    keys.iter()
+        .find_map(identifier_owner)
+        .and_then(|patient_id| get_patient(patient_id).ok())
+}
+
+fn require_patient(pid: &Segment) -> Result<Patient, Failure> {
+    find_patient(&patient_identifiers(pid, &mut Vec::new())).ok_or_else(|| {
+        Failure::error(
+            UNKNOWN_KEY_IDENTIFIER,
+            "No patient matches the identifiers in PID-3",
+        )
+    })
+}
+
+//Identifiers are only linked when they fit an identifier key
+fn check_identifiers(keys: &[IdentifierKey]) -> Result<(), Failure> {
+    let mut validator = Validator::default();
+    for key in keys {
+        validator
+            .check("PID-3", check_identifier_part(&key.issuer))
+            .check("PID-3", check_identifier_part(&key.value));
+    }
+    validator.finish().map_err(Failure::invalid)
+}
+
+//Links the identifiers that are not linked to any patient yet
+fn link_identifiers(patient_id: u64, keys: Vec<IdentifierKey>) {
+    for key in keys {
+        if identifier_owner(&key).is_none() && identifiers_of(patient_id).len() < MAX_IDENTIFIERS {
+            link(patient_id, key);
+        }
+    }
+}
+
+fn gender(value: &str, review_notes: &mut Vec<String>) -> Option<AdministrativeGender> {
+    match value {
+        "" => None,
+        "M" => Some(AdministrativeGender::Male),
+        "F" => Some(AdministrativeGender::Female),
+        "O" | "A" => Some(AdministrativeGender::Other),
+        "U" | "N" => Some(AdministrativeGender::Unknown),
+        other => {
+            review_notes.push(format!("gender: could not map '{}'", other));
+            None
+        }
+    }
+}
+
+//Builds the demographics in PID, keeping the current values of fields the message leaves empty
+fn demographics(
+    pid: &Segment,
+    current: Option<&Patient>,
+    review_notes: &mut Vec<String>,
+) -> PatientPayLoad {
+    let keep =
+        |value: String, current_value: Option<&String>| match (value.is_empty(), current_value) {
+            (true, Some(current_value)) => current_value.clone(),
+            _ => value,
+        };
+
+    let name = [
+        pid.component(5, 2),
+        pid.component(5, 3),
+        pid.component(5, 1),
+    ]
+    .into_iter()
+    .filter(|part| !part.is_empty())
+    .collect::<Vec<String>>()
+    .join(" ");
+    let birth_date = pid.value(7);
+    // Anything other than YYYYMMDD is left for validation to report
+    let date_of_birth = match birth_date.get(..8) {
+        Some(date) if date.bytes().all(|byte| byte.is_ascii_digit()) => {
+            format!("{}-{}-{}", &date[6..8], &date[4..6], &date[..4])
+        }
+        _ => birth_date,
+    };
+
+    let address = Address {
+        street: [pid.component(11, 1), pid.component(11, 2)]
+            .into_iter()
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<String>>()
+            .join(", "),
+        city: pid.component(11, 3),
+        region: pid.component(11, 4),
+        postal_code: pid.component(11, 5),
+        country: pid.component(11, 6).to_uppercase(),
+    };
+    let address = match current {
+        Some(current) if address.street.is_empty() && address.city.is_empty() => {
+            current.address.clone()
+        }
+        _ => address,
+    };
+
+    // XTN-4 holds email addresses; phone numbers are in XTN-1 or split over XTN-5 to XTN-7
+    let separators = pid.separators;
+    let mut phone_number = String::new();
+    let mut email = String::new();
+    for telecom in pid.repetitions(13) {
+        let address = separators.component(telecom, 4);
+        if !address.is_empty() {
+            if email.is_empty() {
+                email = address;
+            }
+            continue;
+        }
+        let number = match separators.component(telecom, 1) {
+            number if number.is_empty() => format!(
+                "+{}{}{}",
+                separators.component(telecom, 5),
+                separators.component(telecom, 6),
+                separators.component(telecom, 7)
+            ),
+            number => number,
+        };
+        if phone_number.is_empty() {
+            phone_number = number
+                .chars()
+                .filter(|c| c.is_ascii_digit() || *c == '+')
+                .collect();
+        }
+    }
+
+    let ethnic_group = match pid.component(22, 2) {
+        text if text.is_empty() => pid.component(22, 1),
+        text => text,
+    };
+    let ethnicity = if ethnic_group.is_empty() {
+        if current.is_none() {
+            review_notes.push("ethnicity: not recorded in the message".to_string());
+        }
+        String::new()
+    } else {
+        map_ethnicity(&ethnic_group, review_notes)
+    };
+
+    PatientPayLoad {
+        name: keep(name, current.map(|patient| &patient.name)),
+        date_of_birth: keep(date_of_birth, current.map(|patient| &patient.date_of_birth)),
+        gender: gender(&pid.value(8), review_notes)
+            .or(current.map(|patient| patient.gender))
+            .unwrap_or_default(),
+        ethnicity: keep(ethnicity, current.map(|patient| &patient.ethnicity)),
+        address,
+        phone_number: keep(phone_number, current.map(|patient| &patient.phone_number)),
+        email: keep(email, current.map(|patient| &patient.email)),
+    }
+}
+
+fn open_encounters(patient_id: u64) -> Vec<Encounter> {
+    ENCOUNTERS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, encounter)| encounter)
+            .filter(|encounter| {
+                encounter.patient_id == patient_id && encounter.discharged_on.is_none()
+            })
+            .collect()
+    })
+}
+
+//ADT^A01: registers the patient when they are new, and opens an encounter
+fn admit(message: &Message) -> Result<(), Failure> {
+    let pid = message.segment("PID")?;
+    let pv1 = message.segment("PV1")?;
+
+    let mut review_notes = Vec::new();
+    let keys = patient_identifiers(pid, &mut review_notes);
+    let existing = find_patient(&keys);
+
+    let visit_number = pv1.value(19);
+    if let Some(patient) = &existing {
+        if !visit_number.is_empty()
+            && open_encounters(patient.id)
+                .iter()
+                .any(|encounter| encounter.visit_number == visit_number)
+        {
+            return Err(Failure::error(
+                DUPLICATE_KEY_IDENTIFIER,
+                format!("Visit {} is already open", visit_number),
+            ));
+        }
+    }
+
+    let location: Vec<String> = (1..=4)
+        .map(|component| pv1.component(3, component))
+        .filter(|part| !part.is_empty())
+        .collect();
+    let room = pv1.component(3, 2);
+    let room_id = ROOM_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .find(|(_, stored)| {
+                !room.is_empty()
+                    && stored.archived.is_none()
+                    && stored.name.eq_ignore_ascii_case(&room)
+            })
+            .map(|(id, _)| id)
+    });
+    let attending_doctor = match [pv1.component(7, 3), pv1.component(7, 2)]
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ")
+    {
+        name if name.is_empty() => pv1.component(7, 1),
+        name => name,
+    };
+    let patient_class = pv1.value(2);
+    let location = location.join(" / ");
+    // Checked before the patient is registered, so that a refused message changes nothing
+    Validator::default()
+        .limit("PV1-19", &visit_number, MAX_IDENTIFIER_PART_LENGTH)
+        .limit("PV1-2", &patient_class, MAX_CODE_LENGTH)
+        .limit("PV1-3", &location, MAX_TEXT_LENGTH)
+        .limit("PV1-7", &attending_doctor, MAX_TEXT_LENGTH)
+        .finish()
+        .map_err(Failure::invalid)?;
+
+    let patient = match existing {
+        Some(patient) => patient,
+        None => {
+            let payload = demographics(pid, None, &mut review_notes);
+            validate_imported_patient(&payload).map_err(Failure::invalid)?;
+            check_identifiers(&keys)?;
+            let patient = register_patient(payload, review_notes);
+            link_identifiers(patient.id, keys);
+            patient
+        }
+    };
+
+    let encounter = Encounter {
+        id: next_id(&ENCOUNTER_ID_COUNTER),
+        patient_id: patient.id,
+        visit_number,
+        patient_class,
+        location,
+        room_id,
+        attending_doctor,
+        admitted_on: timestamp(pv1.field(44)).unwrap_or_else(|| message.created_on()),
+        discharged_on: None,
+    };
+    ENCOUNTERS.with(|storage| storage.borrow_mut().insert(encounter.id, encounter));
+    Ok(())
+}
+
+//ADT^A03: closes the encounter with the visit number in PV1-19, or the latest open one
+fn discharge(message: &Message) -> Result<(), Failure> {
+    let patient = require_patient(message.segment("PID")?)?;
+    let pv1 = message.segment("PV1")?;
+
+    let visit_number = pv1.value(19);
+    let mut encounter = open_encounters(patient.id)
+        .into_iter()
+        .filter(|encounter| visit_number.is_empty() || encounter.visit_number == visit_number)
+        .max_by_key(|encounter| encounter.admitted_on)
+        .ok_or_else(|| {
+            Failure::error(
+                UNKNOWN_KEY_IDENTIFIER,
+                format!(
+                    "Patient with ID {} has no open encounter to discharge",
+                    patient.id
+                ),
+            )
+        })?;
+
+    encounter.discharged_on =
+        Some(timestamp(pv1.field(45)).unwrap_or_else(|| message.created_on()));
+    ENCOUNTERS.with(|storage| storage.borrow_mut().insert(encounter.id, encounter));
+    Ok(())
+}
+
+//ADT^A08: updates the demographics of a known patient
+fn update_demographics(message: &Message) -> Result<(), Failure> {
+    let pid = message.segment("PID")?;
+    let mut patient = require_patient(pid)?;
+
+    let mut review_notes = Vec::new();
+    let keys = patient_identifiers(pid, &mut review_notes);
+    let payload = demographics(pid, Some(&patient), &mut review_notes);
+    validate_imported_patient(&payload).map_err(Failure::invalid)?;
+    check_identifiers(&keys)?;
+
+    link_identifiers(patient.id, keys);
+    patient.name = payload.name;
+    patient.date_of_birth = payload.date_of_birth;
+    patient.gender = payload.gender;
+    patient.ethnicity = payload.ethnicity;
+    patient.address = payload.address;
+    patient.phone_number = payload.phone_number;
+    patient.email = payload.email;
+    cap_review_notes(&mut review_notes);
+    for note in review_notes {
+        if !patient.review_notes.contains(&note) {
+            patient.review_notes.push(note);
+        }
+    }
+    cap_review_notes(&mut patient.review_notes);
+    save_patient(&mut patient);
+    Ok(())
+}
+
+//ORU^R01: stores a lab result for every OBR segment, with the OBX segments that follow it
+fn record_results(message: &Message) -> Result<(), Failure> {
+    let patient = require_patient(message.segment("PID")?)?;
+
+    let received_on = time();
+    let mut results: Vec<LabResult> = Vec::new();
+    for segment in &message.segments {
+        match segment.id.as_str() {
+            "OBR" => {
+                let order_number = match segment.value(3) {
+                    filler if filler.is_empty() => segment.value(2),
+                    filler => filler,
+                };
+                let test = match segment.component(4, 2) {
+                    text if text.is_empty() => segment.component(4, 1),
+                    text => text,
+                };
+                if test.is_empty() {
+                    return Err(Failure::error(
+                        REQUIRED_FIELD_MISSING,
+                        "OBR-4 (universal service identifier) is required",
+                    ));
+                }
+                results.push(LabResult {
+                    id: 0,
+                    patient_id: patient.id,
+                    order_number,
+                    test,
+                    status: segment.value(25),
+                    observed_on: timestamp(segment.field(7))
+                        .unwrap_or_else(|| message.created_on()),
+                    received_on,
+                    observations: Vec::new(),
+                });
+            }
+            "OBX" => {
+                let Some(result) = results.last_mut() else {
+                    return Err(Failure::error(
+                        SEGMENT_SEQUENCE_ERROR,
+                        "OBX segments must follow the OBR segment they belong to",
+                    ));
+                };
+                let separators = segment.separators;
+                let value: Vec<String> = segment
+                    .repetitions(5)
+                    .map(|repetition| separators.unescape(repetition))
+                    .collect();
+                let code = match segment.component(3, 2) {
+                    text if text.is_empty() => segment.component(3, 1),
+                    text => text,
+                };
+                result.observations.push(Observation {
+                    code,
+                    value: value.join("; "),
+                    units: segment.value(6),
+                    reference_range: segment.value(7),
+                    abnormal_flags: segment.value(8),
+                    status: segment.value(11),
+                });
+            }
+            _ => {}
+        }
+    }
+    if results.is_empty() {
+        return Err(Failure::error(
+            SEGMENT_SEQUENCE_ERROR,
+            "The message has no OBR segment",
+        ));
+    }
+
+    for mut result in results {
+        result.id = next_id(&LAB_RESULT_ID_COUNTER);
+        LAB_RESULTS.with(|storage| storage.borrow_mut().insert(result.id, result));
+    }
+    Ok(())
+}
+
+fn apply(message: &Message) -> Result<(), Failure> {
+    let header = message.header();
+    match (
+        header.component(9, 1).as_str(),
+        header.component(9, 2).as_str(),
+    ) {
+        ("ADT", "A01") => admit(message),
+        ("ADT", "A03") => discharge(message),
+        ("ADT", "A08") => update_demographics(message),
+        ("ORU", "R01") => record_results(message),
+        ("ADT", event) => Err(Failure::rejection(
+            UNSUPPORTED_EVENT_CODE,
+            format!("ADT^{} messages are not supported", event),
+        )),
+        (message_type, _) => Err(Failure::rejection(
+            UNSUPPORTED_MESSAGE_TYPE,
+            format!("{} messages are not supported", message_type),
+        )),
+    }
+}
+
+//Cuts an error down to MAX_ERROR_LENGTH bytes, so that it can be kept with the message
+fn shorten(mut error: String) -> String {
+    if error.len() > MAX_ERROR_LENGTH {
+        let mut end = MAX_ERROR_LENGTH - 3;
+        while !error.is_char_boundary(end) {
+            end -= 1;
+        }
+        error.truncate(end);
+        error.push_str("...");
+    }
+    error
+}
+
+//Applies a message, returning its acknowledgement and why it failed, if it did
+fn process(text: &str) -> (String, Option<String>) {
+    let message = match Message::parse(text) {
+        Ok(message) => message,
+        Err(failure) => {
+            return (
+                acknowledgement(None, Some(&failure)),
+                Some(shorten(failure.text)),
+            )
+        }
+    };
+    match apply(&message) {
+        Ok(()) => (acknowledgement(Some(&message), None), None),
+        Err(failure) => (
+            acknowledgement(Some(&message), Some(&failure)),
+            Some(shorten(failure.text)),
+        ),
+    }
+}
+
+//Only admins and registered interfaces send HL7 messages
+fn ensure_interface() -> Result<(), Error> {
+    let caller = caller();
+    if is_controller(&caller) || staff_role(caller) == Some(StaffRole::Interface) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: "Only a registered interface can send HL7 messages".to_string(),
+    })
+}
+
+//Tells whether the message is about a patient with one of the identifiers. Messages that can
+//not be parsed can not be matched
+fn is_about(text: &str, keys: &[IdentifierKey]) -> bool {
+    let Ok(message) = Message::parse(text) else {
+        return false;
+    };
+    let Ok(pid) = message.segment("PID") else {
+        return false;
+    };
+    patient_identifiers(pid, &mut Vec::new())
+        .iter()
+        .any(|key| keys.contains(key))
+}
+
+//Removes the dead letters about the patient with the identifiers
+pub(crate) fn remove_dead_letters_about(keys: &[IdentifierKey]) {
+    let ids: Vec<u64> = DEAD_LETTERS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, dead_letter)| is_about(&dead_letter.message, keys))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    DEAD_LETTERS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for id in ids {
+            storage.remove(&id);
+        }
+    });
+}
+
+fn get_dead_letter(id: u64) -> Result<DeadLetter, Error> {
+    DEAD_LETTERS
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("Dead letter with ID {} not found", id),
+        })
+}
+
+//Applies an HL7 v2 message and returns its ACK. Messages that fail are kept as dead letters
+#[ic_cdk::update]
+fn ingest_hl7_message(message: String) -> Result<String, Error> {
+    observe("ingest_hl7_message", || {
+        ensure_writes_allowed()?;
+        ensure_interface()?;
+        if message.len() > MAX_MESSAGE_LENGTH {
+            return Err(invalid_field(
+                "message",
+                &format!(
+                    "Messages can not be longer than {} bytes",
+                    MAX_MESSAGE_LENGTH
+                ),
+            ));
+        }
+
+        let (acknowledgement, failure) = process(&message);
+        if let Some(error) = failure {
+            let dead_letter = DeadLetter {
+                id: next_id(&DEAD_LETTER_ID_COUNTER),
+                message,
+                error,
+                received_from: caller(),
+                received_on: time(),
+                attempts: 1,
+            };
+            DEAD_LETTERS.with(|storage| storage.borrow_mut().insert(dead_letter.id, dead_letter));
+        }
+        Ok(acknowledgement)
+    })
+}
+
+//Lists the messages that could not be applied, oldest first
+#[ic_cdk::query]
+fn get_dead_letters() -> Result<Vec<DeadLetter>, Error> {
+    ensure_admin()?;
+
+    Ok(DEAD_LETTERS.with(|storage| storage.borrow().iter().map(|(_, letter)| letter).collect()))
+}
+
+//Applies a dead letter again, e.g. once the patient it is about has been registered.
+//It is removed when it applies, and kept with the new error when it does not
+#[ic_cdk::update]
+fn reprocess_dead_letter(id: u64) -> Result<String, Error> {
+    observe("reprocess_dead_letter", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+        let mut dead_letter = get_dead_letter(id)?;
+
+        let (acknowledgement, failure) = process(&dead_letter.message);
+        DEAD_LETTERS.with(|storage| match failure {
+            Some(error) => {
+                dead_letter.error = error;
+                dead_letter.attempts += 1;
+                storage.borrow_mut().insert(id, dead_letter);
+            }
+            None => {
+                storage.borrow_mut().remove(&id);
+            }
+        });
+        Ok(acknowledgement)
+    })
+}
+
+//Removes a dead letter that will not be reprocessed
+#[ic_cdk::update]
+fn discard_dead_letter(id: u64) -> Result<(), Error> {
+    observe("discard_dead_letter", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+        let _dead_letter = get_dead_letter(id)?;
+
+        DEAD_LETTERS.with(|storage| storage.borrow_mut().remove(&id));
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(date: &str, seconds: u64) -> u64 {
+        Date::parse(date).unwrap().to_timestamp() + seconds * NANOS_PER_SECOND
+    }
+
+    #[test]
+    fn unescapes_delimiters_and_drops_formatting() {
+        let separators = Separators::default();
+        assert_eq!(separators.unescape(r"A\F\B\S\C\E\D"), r"A|B^C\D");
+        assert_eq!(separators.unescape(r"bold\H\text\N\"), "boldtext");
+        assert_eq!(separators.unescape("plain"), "plain");
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        let separators = Separators::default();
+        let value = r"Smith|Jones^Jr~&\";
+        assert_eq!(separators.unescape(&separators.escape(value)), value);
+    }
+
+    #[test]
+    fn converts_timestamps_with_offsets_to_utc() {
+        assert_eq!(timestamp("20240301"), Some(utc("01-03-2024", 0)));
+        assert_eq!(
+            timestamp("202403011200"),
+            Some(utc("01-03-2024", 12 * 3600))
+        );
+        assert_eq!(
+            timestamp("20240301120000+0200"),
+            Some(utc("01-03-2024", 10 * 3600))
+        );
+        assert_eq!(
+            timestamp("20240301120000-0530"),
+            Some(utc("01-03-2024", 17 * 3600 + 30 * 60))
+        );
+        assert_eq!(
+            timestamp("20240301003000+0100"),
+            Some(utc("29-02-2024", 23 * 3600 + 30 * 60))
+        );
+        assert_eq!(
+            timestamp("20240301120000.1234+0000^S"),
+            Some(utc("01-03-2024", 12 * 3600))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_timestamps() {
+        assert_eq!(timestamp("2024"), None);
+        assert_eq!(timestamp("2024030A"), None);
+        assert_eq!(timestamp("20240230"), None);
+    }
+
+    #[test]
+    fn leaves_birth_dates_that_are_not_digits_for_validation() {
+        let message = Message::parse("MSH|^~\\&|ADT\rPID|1||||Doe^Jane||1990é101")
+            .ok()
+            .unwrap();
+        let pid = message.segment("PID").ok().unwrap();
+        let payload = demographics(pid, None, &mut Vec::new());
+        assert_eq!(payload.date_of_birth, "1990é101");
+    }
+
+    #[test]
+    fn an_encounter_with_the_longest_fields_fits_when_stored() {
+        let encounter = Encounter {
+            id: u64::MAX,
+            patient_id: u64::MAX,
+            visit_number: "x".repeat(MAX_IDENTIFIER_PART_LENGTH),
+            patient_class: "x".repeat(MAX_CODE_LENGTH),
+            location: "x".repeat(MAX_TEXT_LENGTH),
+            room_id: Some(u64::MAX),
+            attending_doctor: "x".repeat(MAX_TEXT_LENGTH),
+            admitted_on: u64::MAX,
+            discharged_on: Some(u64::MAX),
+        };
+        assert!(encounter.to_bytes().len() <= Encounter::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn shortens_long_errors() {
+        let error = shorten(format!("{} messages are not supported", "X".repeat(5000)));
+        assert!(error.len() <= MAX_ERROR_LENGTH && error.ends_with("..."));
+        assert_eq!(shorten("short".to_string()), "short");
+    }
+}
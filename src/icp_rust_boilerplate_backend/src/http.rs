@@ -0,0 +1,143 @@
+// Read-only JSON REST API served through the HTTP gateway, for clients that do not speak Candid.
+// Requests through the gateway are anonymous, so it only serves what anyone may read: doctors,
+// rooms and the metrics. Patient records and every change go through authenticated Candid calls
+use crate::metrics::render;
+use crate::{get_doctor, get_room, Error};
+use candid::CandidType;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+//A request as passed on by the HTTP gateway
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    upgrade: Option<bool>, //Never set, as no route needs an update call
+}
+
+//The body of a response
+enum Body {
+    Json(Value),
+    Text(String),
+}
+
+//The status code and body of a successful request
+type Reply = (u16, Body);
+
+//Handles a route, given the IDs in its path
+type Handler = fn(&[u64]) -> Result<Reply, Error>;
+
+//The routes of the API. ":id" matches a numeric ID, which is passed on to the handler
+fn routes() -> Vec<(&'static str, &'static str, Handler)> {
+    vec![
+        ("GET", "/doctors/:id", |ids| ok(get_doctor(ids[0]))),
+        ("GET", "/rooms/:id", |ids| ok(get_room(ids[0]))),
+        ("GET", "/metrics", |_| Ok((200, Body::Text(render())))),
+    ]
+}
+
+fn ok<T: Serialize>(result: Result<T, Error>) -> Result<Reply, Error> {
+    Ok((200, Body::Json(json!(result?))))
+}
+
+//Matches a path against a route, returning the IDs in the path
+fn match_path(route: &str, path: &str) -> Option<Vec<u64>> {
+    let route: Vec<&str> = route.trim_matches('/').split('/').collect();
+    let path: Vec<&str> = path.trim_matches('/').split('/').collect();
+    if route.len() != path.len() {
+        return None;
+    }
+
+    let mut ids = Vec::new();
+    for (expected, segment) in route.iter().zip(path) {
+        if *expected == ":id" {
+            ids.push(segment.parse().ok()?);
+        } else if *expected != segment {
+            return None;
+        }
+    }
+    Some(ids)
+}
+
+//Maps the canister's errors to HTTP status codes
+fn status_code(error: &Error) -> u16 {
+    match error {
+        Error::EmptyFields { .. } | Error::ValidationFailed { .. } => 400,
+        Error::Unauthorized { .. } | Error::ConsentRequired { .. } => 403,
+        Error::NotFound { .. } => 404,
+        Error::AlreadyAssigned { .. }
+        | Error::CanNotAssign { .. }
+        | Error::CanNotPurge { .. }
+        | Error::CanNotMerge { .. } => 409,
+        Error::Conflict { .. } => 412, //The If-Match version is not the current one
+        Error::ServiceUnavailable { .. } => 503,
+    }
+}
+
+fn respond(status_code: u16, body: Body, mut headers: Vec<(String, String)>) -> HttpResponse {
+    let (content_type, body) = match body {
+        Body::Json(value) => ("application/json", value.to_string()),
+        Body::Text(text) => ("text/plain; version=0.0.4", text), //The Prometheus text format
+    };
+    headers.push(("Content-Type".to_string(), content_type.to_string()));
+    HttpResponse {
+        status_code,
+        headers,
+        body: body.into_bytes(),
+        upgrade: None,
+    }
+}
+
+fn handle(request: HttpRequest) -> HttpResponse {
+    let path = request.url.split(['?', '#']).next().unwrap_or_default();
+    let method = request.method.to_uppercase();
+
+    let mut allowed = Vec::new();
+    for (route_method, route, handler) in routes() {
+        let Some(ids) = match_path(route, path) else {
+            continue;
+        };
+        if route_method != method {
+            allowed.push(route_method);
+            continue;
+        }
+
+        return match handler(&ids) {
+            Ok((status_code, body)) => respond(status_code, body, Vec::new()),
+            Err(error) => respond(
+                status_code(&error),
+                Body::Json(json!({ "error": error.message(), "details": error })),
+                Vec::new(),
+            ),
+        };
+    }
+
+    if allowed.is_empty() {
+        respond(
+            404,
+            Body::Json(json!({ "error": format!("There is no route for {}", path) })),
+            Vec::new(),
+        )
+    } else {
+        respond(
+            405,
+            Body::Json(json!({ "error": format!("{} is not allowed on {}", method, path) })),
+            vec![("Allow".to_string(), allowed.join(", "))],
+        )
+    }
+}
+
+//Answers GET requests. Other methods are not allowed on any route
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    handle(request)
+}
@@ -0,0 +1,246 @@
+// Idempotency keys, so that a client retrying a create call after an ingress timeout gets the
+// original result back instead of creating a duplicate record
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::invalid_field;
+use crate::{ensure_admin, Error, IDEMPOTENCY_EXPIRY, IDEMPOTENCY_KEYS, IDEMPOTENCY_WINDOW};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::{BoundedStorable, Storable};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+//Longest idempotency key accepted, in characters
+const MAX_KEY_LENGTH: usize = 64;
+
+//Expired keys forgotten per call, so that pruning never makes a call expensive
+const MAX_PRUNED_PER_CALL: usize = 100;
+
+//A key is only remembered for the caller that sent it and the endpoint it was sent to.
+//Anonymous callers all share one principal, so they can not use keys
+#[derive(CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct IdempotencyKey {
+    caller: Principal,
+    endpoint: String,
+    key: String,
+}
+
+impl Default for IdempotencyKey {
+    fn default() -> Self {
+        IdempotencyKey {
+            caller: Principal::anonymous(),
+            endpoint: String::new(),
+            key: String::new(),
+        }
+    }
+}
+
+impl Storable for IdempotencyKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdempotencyKey {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//The result a key was first used for
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct IdempotencyRecord {
+    fingerprint: Vec<u8>, //SHA-256 of the Candid-encoded payload
+    result: Vec<u8>,      //The Candid-encoded result
+    recorded_on: u64,
+}
+
+impl Storable for IdempotencyRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdempotencyRecord {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl IdempotencyRecord {
+    //Whether the record can be stored, as a larger one would trap on insert
+    fn fits(&self) -> bool {
+        self.to_bytes().len() <= Self::MAX_SIZE as usize
+    }
+}
+
+fn fingerprint<P: CandidType>(payload: &P) -> Vec<u8> {
+    Sha256::digest(Encode!(payload).unwrap()).to_vec()
+}
+
+fn window() -> u64 {
+    IDEMPOTENCY_WINDOW.with(|window| *window.borrow().get())
+}
+
+//Forgets the oldest keys whose window has passed
+fn prune_expired() {
+    let cutoff = time().saturating_sub(window());
+    let expired: Vec<(u64, IdempotencyKey)> = IDEMPOTENCY_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .iter()
+            .take(MAX_PRUNED_PER_CALL)
+            .take_while(|((recorded_on, _), _)| *recorded_on <= cutoff)
+            .map(|(entry, _)| entry)
+            .collect()
+    });
+    for (recorded_on, key) in expired {
+        IDEMPOTENCY_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&(recorded_on, key.clone())));
+        IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().remove(&key));
+    }
+}
+
+//Runs a create call once per idempotency key. A retry with the same key and payload within the
+//window returns the original result; successful results only are remembered, so a call that
+//failed can be retried with the same key. A result too large to store is not remembered either,
+//so a retry runs the call again
+pub(crate) fn idempotent<P: CandidType, T: CandidType + DeserializeOwned>(
+    endpoint: &str,
+    key: Option<String>,
+    payload: P,
+    create: impl FnOnce(P) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let Some(key) = key else {
+        return create(payload);
+    };
+    let key = key.trim().to_string();
+    if key.is_empty() || key.chars().count() > MAX_KEY_LENGTH {
+        return Err(invalid_field(
+            "idempotency_key",
+            &format!("Must be between 1 and {} characters", MAX_KEY_LENGTH),
+        ));
+    }
+
+    if caller() == Principal::anonymous() {
+        return Err(invalid_field(
+            "idempotency_key",
+            "Anonymous callers can not use idempotency keys, as they would share them",
+        ));
+    }
+
+    prune_expired();
+
+    let key = IdempotencyKey {
+        caller: caller(),
+        endpoint: endpoint.to_string(),
+        key,
+    };
+    let fingerprint = fingerprint(&payload);
+    let previous = IDEMPOTENCY_KEYS.with(|keys| keys.borrow().get(&key));
+    if let Some(previous) = previous.filter(|previous| previous.recorded_on + window() > time()) {
+        if previous.fingerprint != fingerprint {
+            return Err(invalid_field(
+                "idempotency_key",
+                "This key was already used with a different payload",
+            ));
+        }
+        return Ok(Decode!(&previous.result, T).expect("Cannot decode the remembered result"));
+    }
+
+    let result = create(payload)?;
+    let record = IdempotencyRecord {
+        fingerprint,
+        result: Encode!(&result).unwrap(),
+        recorded_on: time(),
+    };
+    if !record.fits() {
+        return Ok(result);
+    }
+    IDEMPOTENCY_EXPIRY.with(|expiry| {
+        expiry
+            .borrow_mut()
+            .insert((record.recorded_on, key.clone()), ())
+    });
+    let replaced = IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().insert(key.clone(), record));
+    if let Some(replaced) = replaced {
+        //The key had expired but was not pruned yet
+        IDEMPOTENCY_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&(replaced.recorded_on, key)));
+    }
+    Ok(result)
+}
+
+//Forgets the remembered results of the endpoint that match, so that no personal data outlives
+//an erasure. A retry with a forgotten key runs the call again
+pub(crate) fn forget_results<T: CandidType + DeserializeOwned>(
+    endpoint: &str,
+    matches: impl Fn(&T) -> bool,
+) {
+    let forgotten: Vec<(IdempotencyKey, u64)> = IDEMPOTENCY_KEYS.with(|keys| {
+        keys.borrow()
+            .iter()
+            .filter(|(key, record)| {
+                key.endpoint == endpoint
+                    && matches(
+                        &Decode!(&record.result, T).expect("Cannot decode the remembered result"),
+                    )
+            })
+            .map(|(key, record)| (key, record.recorded_on))
+            .collect()
+    });
+    for (key, recorded_on) in forgotten {
+        IDEMPOTENCY_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&(recorded_on, key.clone())));
+        IDEMPOTENCY_KEYS.with(|keys| keys.borrow_mut().remove(&key));
+    }
+}
+
+//Sets how long idempotency keys are remembered, in nanoseconds
+#[ic_cdk::update]
+fn set_idempotency_window(window: u64) -> Result<(), Error> {
+    observe("set_idempotency_window", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        if window == 0 {
+            return Err(invalid_field(
+                "window",
+                "Idempotency keys must be remembered for longer than 0 nanoseconds",
+            ));
+        }
+        IDEMPOTENCY_WINDOW
+            .with(|cell| cell.borrow_mut().set(window))
+            .expect("Cannot set the idempotency window");
+        Ok(())
+    })
+}
+
+//Retrieves how long idempotency keys are remembered, in nanoseconds
+#[ic_cdk::query]
+fn get_idempotency_window() -> u64 {
+    window()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(result_length: usize) -> IdempotencyRecord {
+        IdempotencyRecord {
+            fingerprint: vec![0; 32],
+            result: vec![0; result_length],
+            recorded_on: 0,
+        }
+    }
+
+    #[test]
+    fn records_larger_than_the_bound_do_not_fit() {
+        assert!(record(1024).fits());
+        assert!(!record(IdempotencyRecord::MAX_SIZE as usize).fits());
+    }
+}
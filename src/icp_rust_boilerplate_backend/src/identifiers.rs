@@ -0,0 +1,510 @@
+// Medical Record Numbers and other identifiers that point to a patient
+use crate::access::{ensure_can_read_patient, ensure_can_write_patient};
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::redaction::project;
+use crate::validation::{check_length, check_required, invalid_field, Validator};
+use crate::{
+    ensure_admin, get_patient, Error, Patient, FACILITY_CODE, IDENTIFIER_INDEX, PATIENT_IDENTIFIERS,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//The kinds of identifier a patient can be looked up by
+#[derive(
+    CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub(crate) enum IdentifierKind {
+    MedicalRecordNumber, //Assigned by this facility at registration
+    #[default]
+    NationalId,
+    InsuranceMemberNumber,
+    Passport,
+}
+
+//Uniquely identifies an identifier: the same value may be issued by different issuers
+#[derive(CandidType, Clone, Serialize, Deserialize, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct IdentifierKey {
+    pub(crate) kind: IdentifierKind,
+    pub(crate) issuer: String, //Issuing country or insurer
+    pub(crate) value: String,
+}
+
+impl Storable for IdentifierKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdentifierKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Define our ExternalIdentifier struct
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ExternalIdentifier {
+    pub(crate) kind: IdentifierKind,
+    pub(crate) issuer: String,
+    pub(crate) value: String,
+    patient_id: u64,
+    added_on: u64,
+}
+
+//All the identifiers linked to one patient
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct IdentifierList(Vec<ExternalIdentifier>);
+
+impl Storable for IdentifierList {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdentifierList {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Represents payload for linking an identifier to a patient
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct IdentifierPayload {
+    kind: IdentifierKind,
+    issuer: String,
+    value: String,
+}
+
+impl IdentifierPayload {
+    pub(crate) fn key(&self) -> IdentifierKey {
+        IdentifierKey::new(self.kind, &self.issuer, &self.value)
+    }
+}
+
+//Checks an identifier before it is linked to a patient
+pub(crate) fn validate_identifier_payload(payload: &IdentifierPayload) -> Result<(), Error> {
+    if payload.kind == IdentifierKind::MedicalRecordNumber {
+        return Err(invalid_field(
+            "kind",
+            "Medical record numbers are assigned at registration",
+        ));
+    }
+    let key = payload.key();
+    Validator::default()
+        .check("issuer", check_identifier_part(&key.issuer))
+        .check("value", check_identifier_part(&key.value))
+        .finish()
+}
+
+//Checks the issuer or value of an identifier once normalized, so that its key always fits in
+//IdentifierKey::MAX_SIZE
+pub(crate) fn check_identifier_part(normalized: &str) -> Result<(), String> {
+    check_required(normalized)?;
+    check_length(normalized, MAX_IDENTIFIER_PART_LENGTH)
+        .map_err(|msg| format!("Without spaces and dashes, this field {}", msg))
+}
+
+//Identifiers are compared without case, spaces or dashes
+fn normalize(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_uppercase()
+}
+
+impl IdentifierKey {
+    pub(crate) fn new(kind: IdentifierKind, issuer: &str, value: &str) -> Self {
+        IdentifierKey {
+            kind,
+            issuer: normalize(issuer),
+            value: normalize(value),
+        }
+    }
+
+    pub(crate) fn mrn(mrn: &str) -> Self {
+        IdentifierKey::new(IdentifierKind::MedicalRecordNumber, "", mrn)
+    }
+}
+
+impl ExternalIdentifier {
+    pub(crate) fn key(&self) -> IdentifierKey {
+        IdentifierKey {
+            kind: self.kind,
+            issuer: self.issuer.clone(),
+            value: self.value.clone(),
+        }
+    }
+}
+
+//Luhn check digit over the digits of the number
+fn luhn_check_digit(digits: &str) -> u32 {
+    let sum: u32 = digits
+        .chars()
+        .rev()
+        .filter_map(|c| c.to_digit(10))
+        .enumerate()
+        .map(|(index, digit)| {
+            if index % 2 == 0 {
+                let doubled = digit * 2;
+                if doubled > 9 {
+                    doubled - 9
+                } else {
+                    doubled
+                }
+            } else {
+                digit
+            }
+        })
+        .sum();
+    (10 - sum % 10) % 10
+}
+
+//Builds the MRN for a patient: the facility code, the zero-padded patient ID and a check digit
+fn generate_mrn(patient_id: u64) -> String {
+    let facility_code = FACILITY_CODE.with(|code| code.borrow().get().clone());
+    let number = format!("{:08}", patient_id);
+    format!("{}{}{}", facility_code, number, luhn_check_digit(&number))
+}
+
+//Checks that the digits of an MRN end with a valid check digit
+fn check_mrn(mrn: &str) -> Result<(), String> {
+    let digits: String = mrn.chars().skip_while(|c| !c.is_ascii_digit()).collect();
+    let Some((number, check_digit)) = digits
+        .len()
+        .checked_sub(1)
+        .map(|split| digits.split_at(split))
+    else {
+        return Err(format!("{} is not a valid medical record number", mrn));
+    };
+
+    if number.is_empty()
+        || !digits.chars().all(|c| c.is_ascii_digit())
+        || check_digit.parse::<u32>().ok() != Some(luhn_check_digit(number))
+    {
+        return Err(format!("{} is not a valid medical record number", mrn));
+    }
+    Ok(())
+}
+
+//Most identifiers a single patient can have
+pub(crate) const MAX_IDENTIFIERS: usize = 16;
+
+//Longest issuer or value of an identifier, in bytes once normalized
+pub(crate) const MAX_IDENTIFIER_PART_LENGTH: usize = 64;
+
+//Longest facility code, so that MRNs stay well within the identifier limits
+const MAX_FACILITY_CODE_LENGTH: usize = 10;
+
+pub(crate) fn identifiers_of(patient_id: u64) -> Vec<ExternalIdentifier> {
+    PATIENT_IDENTIFIERS
+        .with(|identifiers| identifiers.borrow().get(&patient_id))
+        .unwrap_or_default()
+        .0
+}
+
+fn store_identifiers(patient_id: u64, identifiers: Vec<ExternalIdentifier>) {
+    PATIENT_IDENTIFIERS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(patient_id, IdentifierList(identifiers))
+    });
+}
+
+pub(crate) fn link(patient_id: u64, key: IdentifierKey) -> ExternalIdentifier {
+    let identifier = ExternalIdentifier {
+        kind: key.kind,
+        issuer: key.issuer.clone(),
+        value: key.value.clone(),
+        patient_id,
+        added_on: time(),
+    };
+
+    let mut identifiers = identifiers_of(patient_id);
+    identifiers.push(identifier.clone());
+
+    IDENTIFIER_INDEX.with(|index| index.borrow_mut().insert(key, patient_id));
+    store_identifiers(patient_id, identifiers);
+    identifier
+}
+
+//Generates the MRN of a newly registered patient and adds it to the identifier index
+pub(crate) fn assign_mrn(patient_id: u64) -> String {
+    link(patient_id, IdentifierKey::mrn(&generate_mrn(patient_id))).value
+}
+
+//Removes every identifier of a patient from the index
+pub(crate) fn remove_identifiers(patient_id: u64) {
+    for identifier in identifiers_of(patient_id) {
+        IDENTIFIER_INDEX.with(|index| index.borrow_mut().remove(&identifier.key()));
+    }
+    PATIENT_IDENTIFIERS.with(|storage| storage.borrow_mut().remove(&patient_id));
+}
+
+//Removes every identifier of a patient but their MRN
+pub(crate) fn remove_external_identifiers(patient_id: u64) {
+    let (mrn, external): (Vec<ExternalIdentifier>, Vec<ExternalIdentifier>) =
+        identifiers_of(patient_id)
+            .into_iter()
+            .partition(|identifier| identifier.kind == IdentifierKind::MedicalRecordNumber);
+    for identifier in external {
+        IDENTIFIER_INDEX.with(|index| index.borrow_mut().remove(&identifier.key()));
+    }
+    store_identifiers(patient_id, mrn);
+}
+
+//Moves the given identifiers (or all but the MRN) to another patient. A patient keeps their MRN
+//when merged, so it can still be looked up and redirect to the patient they were merged into
+pub(crate) fn move_identifiers(
+    from: u64,
+    to: u64,
+    keys: Option<&[IdentifierKey]>,
+) -> Vec<IdentifierKey> {
+    let (moved, kept): (Vec<ExternalIdentifier>, Vec<ExternalIdentifier>) = identifiers_of(from)
+        .into_iter()
+        .partition(|identifier| match keys {
+            Some(keys) => keys.contains(&identifier.key()),
+            None => identifier.kind != IdentifierKind::MedicalRecordNumber,
+        });
+    let moved_keys = moved.iter().map(ExternalIdentifier::key).collect();
+
+    let mut identifiers = identifiers_of(to);
+    for mut identifier in moved {
+        IDENTIFIER_INDEX.with(|index| index.borrow_mut().insert(identifier.key(), to));
+        identifier.patient_id = to;
+        identifiers.push(identifier);
+    }
+    store_identifiers(from, kept);
+    store_identifiers(to, identifiers);
+
+    moved_keys
+}
+
+//The patient an identifier is linked to, if any
+pub(crate) fn identifier_owner(key: &IdentifierKey) -> Option<u64> {
+    IDENTIFIER_INDEX.with(|index| index.borrow().get(key))
+}
+
+fn find_patient(key: &IdentifierKey) -> Result<Patient, Error> {
+    let patient_id = IDENTIFIER_INDEX
+        .with(|index| index.borrow().get(key))
+        .ok_or(Error::NotFound {
+            msg: "No patient found with this identifier".to_string(),
+        })?;
+    let patient = get_patient(patient_id)?;
+    ensure_can_read_patient(patient.id)?;
+
+    Ok(project(patient))
+}
+
+//Retrieves the identifiers linked to a patient, including their MRN
+#[ic_cdk::query]
+fn get_patient_identifiers(patient_id: u64) -> Result<Vec<ExternalIdentifier>, Error> {
+    let patient_id = get_patient(patient_id)?.id;
+    ensure_can_read_patient(patient_id)?;
+
+    Ok(identifiers_of(patient_id))
+}
+
+//Links a national ID, insurance member number or passport to a patient
+#[ic_cdk::update]
+pub(crate) fn add_patient_identifier(
+    patient_id: u64,
+    payload: IdentifierPayload,
+) -> Result<ExternalIdentifier, Error> {
+    observe("add_patient_identifier", || {
+        ensure_writes_allowed()?;
+
+        //Validation Logic
+        validate_identifier_payload(&payload)?;
+
+        let patient_id = get_patient(patient_id)?.id;
+        ensure_can_write_patient(patient_id)?;
+        if identifiers_of(patient_id).len() >= MAX_IDENTIFIERS {
+            return Err(invalid_field(
+                "patient_id",
+                "The patient already has the most identifiers allowed",
+            ));
+        }
+
+        // Identifiers are unique across all patients
+        let key = payload.key();
+        if let Some(owner) = identifier_owner(&key) {
+            return Err(Error::AlreadyAssigned {
+                msg: format!(
+                    "This identifier is already linked to patient with ID {}",
+                    owner
+                ),
+            });
+        }
+
+        Ok(link(patient_id, key))
+    })
+}
+
+//Unlinks an identifier from a patient
+#[ic_cdk::update]
+fn remove_patient_identifier(patient_id: u64, payload: IdentifierPayload) -> Result<(), Error> {
+    observe("remove_patient_identifier", || {
+        ensure_writes_allowed()?;
+
+        if payload.kind == IdentifierKind::MedicalRecordNumber {
+            return Err(invalid_field(
+                "kind",
+                "Medical record numbers can not be removed",
+            ));
+        }
+
+        let patient_id = get_patient(patient_id)?.id;
+        ensure_can_write_patient(patient_id)?;
+
+        let key = IdentifierKey::new(payload.kind, &payload.issuer, &payload.value);
+        let mut identifiers = identifiers_of(patient_id);
+        let Some(position) = identifiers
+            .iter()
+            .position(|identifier| identifier.key() == key)
+        else {
+            return Err(Error::NotFound {
+                msg: format!("Identifier not found for patient with ID {}", patient_id),
+            });
+        };
+        identifiers.remove(position);
+
+        IDENTIFIER_INDEX.with(|index| index.borrow_mut().remove(&key));
+        store_identifiers(patient_id, identifiers);
+        Ok(())
+    })
+}
+
+//Looks up a patient by a national ID, insurance member number or passport
+#[ic_cdk::query]
+fn find_patient_by_identifier(payload: IdentifierPayload) -> Result<Patient, Error> {
+    find_patient(&IdentifierKey::new(
+        payload.kind,
+        &payload.issuer,
+        &payload.value,
+    ))
+}
+
+//Looks up a patient by their Medical Record Number
+#[ic_cdk::query]
+fn find_patient_by_mrn(mrn: String) -> Result<Patient, Error> {
+    Validator::default()
+        .check("mrn", check_mrn(&mrn))
+        .finish()?;
+
+    find_patient(&IdentifierKey::mrn(&mrn))
+}
+
+//Sets the code that prefixes the MRNs of newly registered patients
+#[ic_cdk::update]
+fn set_facility_code(code: String) -> Result<(), Error> {
+    observe("set_facility_code", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        if code.is_empty() || !code.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(invalid_field(
+                "code",
+                "The facility code must only contain capital letters",
+            ));
+        }
+        if code.len() > MAX_FACILITY_CODE_LENGTH {
+            return Err(invalid_field(
+                "code",
+                &format!(
+                    "The facility code can have at most {} letters",
+                    MAX_FACILITY_CODE_LENGTH
+                ),
+            ));
+        }
+
+        FACILITY_CODE
+            .with(|cell| cell.borrow_mut().set(code))
+            .expect("Cannot set the facility code");
+        Ok(())
+    })
+}
+
+//Retrieves the code that prefixes the MRNs of newly registered patients
+#[ic_cdk::query]
+fn get_facility_code() -> String {
+    FACILITY_CODE.with(|code| code.borrow().get().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_luhn_check_digits() {
+        assert_eq!(luhn_check_digit("7992739871"), 3);
+        assert_eq!(luhn_check_digit("00000001"), 8);
+        assert_eq!(luhn_check_digit("00000000"), 0);
+    }
+
+    #[test]
+    fn generated_mrns_pass_their_check() {
+        for patient_id in [1, 42, 9_999_999, 12_345_678] {
+            let mrn = generate_mrn(patient_id);
+            assert!(mrn.starts_with("HMS"));
+            assert!(check_mrn(&mrn).is_ok(), "{} was rejected", mrn);
+        }
+    }
+
+    #[test]
+    fn rejects_mrns_with_a_wrong_check_digit_or_a_typo() {
+        let mrn = generate_mrn(42);
+        let (number, check_digit) = mrn.split_at(mrn.len() - 1);
+        let wrong_digit = (check_digit.parse::<u32>().unwrap() + 1) % 10;
+        assert!(check_mrn(&format!("{}{}", number, wrong_digit)).is_err());
+        assert_eq!(mrn, "HMS000000422");
+        assert!(check_mrn("HMS000000242").is_err()); // Two digits swapped
+        assert!(check_mrn("HMS").is_err());
+        assert!(check_mrn("HMS0000004X2").is_err());
+    }
+
+    #[test]
+    fn identifiers_at_the_length_limit_fit_when_stored() {
+        let longest = "X".repeat(MAX_IDENTIFIER_PART_LENGTH);
+        let key = IdentifierKey::new(IdentifierKind::InsuranceMemberNumber, &longest, &longest);
+        assert!(check_identifier_part(&key.value).is_ok());
+        assert!(key.to_bytes().len() <= IdentifierKey::MAX_SIZE as usize);
+
+        let identifier = ExternalIdentifier {
+            kind: key.kind,
+            issuer: key.issuer,
+            value: key.value,
+            patient_id: u64::MAX,
+            added_on: u64::MAX,
+        };
+        let identifiers = IdentifierList(vec![identifier; MAX_IDENTIFIERS]);
+        assert!(identifiers.to_bytes().len() <= IdentifierList::MAX_SIZE as usize);
+    }
+
+    #[test]
+    fn rejects_identifiers_that_are_too_long_or_empty_once_normalized() {
+        assert!(check_identifier_part(&"X".repeat(MAX_IDENTIFIER_PART_LENGTH + 1)).is_err());
+        // Uppercasing can make a value longer
+        let key = IdentifierKey::new(IdentifierKind::Passport, "KE", &"ŉ".repeat(25));
+        assert!(check_identifier_part(&key.value).is_err());
+        let key = IdentifierKey::new(IdentifierKind::Passport, "KE", " - ");
+        assert!(check_identifier_part(&key.value).is_err());
+    }
+
+    #[test]
+    fn normalizes_identifier_values() {
+        let key = IdentifierKey::new(IdentifierKind::Passport, "ke", " a12-345 678 ");
+        assert_eq!(key.issuer, "KE");
+        assert_eq!(key.value, "A12345678");
+    }
+}
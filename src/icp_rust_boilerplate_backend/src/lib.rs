@@ -1,31 +1,193 @@
 // Importing neccessary dependencies
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+mod access;
+mod batch;
+mod break_glass;
+mod certification;
+mod consents;
+mod contacts;
+mod csv;
+mod duplicates;
+mod encounters;
+mod fhir;
+mod fhir_import;
+mod history;
+mod hl7;
+mod http;
+mod idempotency;
+mod identifiers;
+mod metrics;
+mod migrations;
+mod operating_mode;
+mod portal;
+mod privacy;
+mod redaction;
+mod snapshot;
+mod validation;
+
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::{caller, is_controller, time};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use std::{borrow::Cow, cell::RefCell, thread::LocalKey};
+
+use access::{
+    can_read_patient, ensure_can_diagnose, ensure_can_read_patient, ensure_can_write_patient,
+    ensure_receptionist, is_patient, PrincipalKey, StaffMember, StaffPayload, StaffRole,
+};
+use batch::{BatchOperation, BatchResult};
+use break_glass::BreakGlassAccess;
+use certification::{
+    certify, uncertify, CertifiedDoctor, CertifiedPatient, CertifiedRoom, Collection,
+};
+use consents::{remove_consents, Consent, ConsentPayload, ConsentScope};
+use contacts::{remove_contacts, RelatedPerson, RelatedPersonPayload};
+use csv::{CsvImportOptions, CsvImportReport};
+use duplicates::{find_duplicates, DuplicateCandidate, Merge, PatientRegistration};
+use encounters::{remove_encounters_and_lab_results, Encounter, LabResult};
+use fhir_import::FhirImportReport;
+use history::{record_revision, remove_history, FieldChange, History, Revision};
+use hl7::DeadLetter;
+use http::{HttpRequest, HttpResponse};
+use idempotency::{idempotent, IdempotencyKey, IdempotencyRecord};
+use identifiers::{
+    assign_mrn, remove_identifiers, ExternalIdentifier, IdentifierKey, IdentifierList,
+    IdentifierPayload,
+};
+use metrics::observe;
+use operating_mode::{ensure_writes_allowed, OperatingModePayload, OperatingModeSetting};
+use portal::{remove_portal_access, ContactDetailsPatch, Enrollment};
+use privacy::{Erasure, ErasureRequest, SubjectAccessExport};
+use redaction::{project, FieldPolicy, RecordKind, RedactionPolicies};
+use snapshot::{SnapshotChunk, SnapshotManifest, SnapshotState};
+use validation::{
+    cap_review_notes, check_equipment, current_age, validate_clinical_text, validate_doctor_patch,
+    validate_doctor_payload, validate_patient_patch, validate_patient_payload, validate_room_patch,
+    validate_room_payload, FieldError, Validator, MAX_CODE_LENGTH, MAX_NAME_LENGTH,
+};
 
 //Use these types to store our canister's state and generate unique IDs
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
+//Archived records can only be purged after this period has passed (about 10 years, in nanoseconds)
+const DEFAULT_RETENTION_PERIOD: u64 = 10 * 365 * 24 * 60 * 60 * 1_000_000_000;
+
+//How long emergency access to a patient lasts unless an admin changes it (4 hours, in nanoseconds)
+const DEFAULT_BREAK_GLASS_DURATION: u64 = 4 * 60 * 60 * 1_000_000_000;
+
+//How long idempotency keys are remembered unless an admin changes it (24 hours, in nanoseconds)
+const DEFAULT_IDEMPOTENCY_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+//Prefixes the Medical Record Numbers until an admin sets the facility's own code
+const DEFAULT_FACILITY_CODE: &str = "HMS";
+
+//Records who archived a record and when
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Archive {
+    archived_by: Principal,
+    archived_on: u64,
+}
+
+//Administrative gender, as used for registration and reporting
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+enum AdministrativeGender {
+    Male,
+    Female,
+    Other,
+    #[default]
+    Unknown,
+}
+
+//A structured postal address
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Address {
+    street: String,
+    city: String,
+    region: String,      //Optional
+    postal_code: String, //Optional
+    country: String,     //ISO 3166-1 alpha-2 code, e.g. KE
+}
+
+//An entry in the configurable list of ethnicity codes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EthnicityCode {
+    code: String,
+    display: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EthnicityCodes(Vec<EthnicityCode>);
+
+impl Default for EthnicityCodes {
+    fn default() -> Self {
+        let codes = [
+            ("african", "African"),
+            ("asian", "Asian"),
+            ("european", "European"),
+            ("hispanic_or_latino", "Hispanic or Latino"),
+            (
+                "middle_eastern_or_north_african",
+                "Middle Eastern or North African",
+            ),
+            ("pacific_islander", "Pacific Islander"),
+            ("indigenous", "Indigenous"),
+            ("mixed", "Mixed"),
+            ("other", "Other"),
+            ("declined", "Declined to answer"),
+        ];
+        EthnicityCodes(
+            codes
+                .iter()
+                .map(|(code, display)| EthnicityCode {
+                    code: code.to_string(),
+                    display: display.to_string(),
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Storable for EthnicityCodes {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//The current state of a record, returned when an update conflicts with it.
+//It is only ever sent back boxed, so the variant sizes do not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum Record {
+    Patient(Patient),
+    Doctor(Doctor),
+    Room(Room),
+    Diagnosis(Diagnosis),
+}
+
 //Define our Patient Struct   njjilesssstd
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Patient {
     id: u64,
     name: String,
     date_of_birth: String, //Format: DD-MM-YYYY
-    age: u32,
-    gender: String,
-    ethncity: String,
-    address: String,
+    age: u32,              //Derived from date_of_birth
+    gender: AdministrativeGender,
+    ethnicity: String, //One of the configured ethnicity codes
+    address: Address,
     phone_number: String,
     email: String, //Optional
-    next_of_kin: String,
-    kins_phone_number: String,
     registered_on: u64,
+    mrn: Option<String>,      //Medical Record Number, assigned at registration
+    merged_into: Option<u64>, //Set when this patient was a duplicate of another one
+    archived: Option<Archive>,
+    version: u64,
+    review_notes: Vec<String>, //Values that could not be migrated and need a manual review
 }
 
 impl Storable for Patient {
@@ -52,6 +214,8 @@ struct Doctor {
     phone_number: String,
     speciality: String,
     current_patient: u64,
+    archived: Option<Archive>,
+    version: u64,
 }
 
 impl Storable for Doctor {
@@ -77,6 +241,8 @@ struct Room {
     location: String,
     current_doctor_id: u64,
     equipment: Vec<String>,
+    archived: Option<Archive>,
+    version: u64,
 }
 
 impl Storable for Room {
@@ -102,6 +268,7 @@ struct Diagnosis {
     patient_id: u64,
     treatment: String,
     medication: String,
+    version: u64,
 }
 
 impl Storable for Diagnosis {
@@ -124,14 +291,11 @@ impl BoundedStorable for Diagnosis {
 struct PatientPayLoad {
     name: String,
     date_of_birth: String, //Format: DD-MM-YYYY
-    age: u32,
-    gender: String,
-    ethncity: String,
-    address: String,
+    gender: AdministrativeGender,
+    ethnicity: String,
+    address: Address,
     phone_number: String,
     email: String, //Optional
-    next_of_kin: String,
-    kins_phone_number: String,
 }
 
 impl Default for PatientPayLoad {
@@ -139,14 +303,11 @@ impl Default for PatientPayLoad {
         PatientPayLoad {
             name: String::default(),
             date_of_birth: String::default(), //Format: DD-MM-YYYY
-            age: u32::default(),
-            gender: String::default(),
-            ethncity: String::default(),
-            address: String::default(),
+            gender: AdministrativeGender::default(),
+            ethnicity: String::default(),
+            address: Address::default(),
             phone_number: String::default(),
             email: String::default(), //Optional
-            next_of_kin: String::default(),
-            kins_phone_number: String::default(),
         }
     }
 }
@@ -193,7 +354,7 @@ struct DiagnosisPayload {
     doctor_id: u64,
     patient_id: u64,
     treatment: String,
-    medication: String
+    medication: String,
 }
 
 impl Default for DiagnosisPayload {
@@ -207,100 +368,391 @@ impl Default for DiagnosisPayload {
     }
 }
 
-//thread-local variables that will hold our canister's state
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+//Represents payload for changing only some of a patient's fields
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PatientPatch {
+    name: Option<String>,
+    date_of_birth: Option<String>, //Format: DD-MM-YYYY
+    gender: Option<AdministrativeGender>,
+    ethnicity: Option<String>,
+    address: Option<Address>,
+    phone_number: Option<String>,
+    email: Option<String>,
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+//Represents payload for changing only some of a doctor's fields
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct DoctorPatch {
+    name: Option<String>,
+    email: Option<String>,
+    phone_number: Option<String>,
+    speciality: Option<String>,
+}
 
-    static PATIENT_STORAGE: RefCell<StableBTreeMap<u64, Patient, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+/// Represents payload for changing only some of a Room's fields.
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct RoomPatch {
+    name: Option<String>,
+    location: Option<String>,
+}
 
-    static DOCTOR_STORAGE: RefCell<StableBTreeMap<u64, Doctor, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+//Holds the progress of a snapshot, so it is left out of the snapshot itself
+const SNAPSHOT_STATE_MEMORY_ID: u8 = 37;
+
+//Declares every stable structure with the MemoryId it lives in. The same table gives the MemoryIds
+//a snapshot holds, and how the structures are loaded again after a restore or emptied when one is
+//abandoned. A MemoryId is never reused or renumbered, as its data stays where it was written
+macro_rules! stable_structures {
+    ($($kind:ident $name:ident: $ty:ty = $id:expr $(=> $default:expr)?;)*) => {
+        //thread-local variables that will hold our canister's state
+        thread_local! {
+            static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+                MemoryManager::init(DefaultMemoryImpl::default())
+            );
+
+            $(static $name: RefCell<$ty> =
+                RefCell::new(stable_structures!(@init $kind $name $id $(, $default)?));)*
+        }
 
-    static ROOM_STORAGE: RefCell<StableBTreeMap<u64, Room, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+        //Every MemoryId in use
+        const MEMORY_IDS: &[u8] = &[$($id),*];
 
-    static DIAGNOSIS_STORAGE: RefCell<StableBTreeMap<u64, Diagnosis, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+        //Loads every stable structure again from the memory a restore has written
+        fn reload_stable_state() {
+            $(stable_structures!(@reload $kind $name $id);)*
+        }
+
+        //Empties the stable structure in the MemoryId. A cell gets its initial value again
+        fn reset_stable_structure(id: u8) {
+            $(if id == $id {
+                stable_structures!(@reset $kind $name $id $(, $default)?);
+            })*
+        }
+    };
+    (@memory $id:expr) => {
+        MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new($id)))
+    };
+    (@init map $name:ident $id:expr) => {
+        StableBTreeMap::init(stable_structures!(@memory $id))
+    };
+    (@init cell $name:ident $id:expr, $default:expr) => {
+        Cell::init(stable_structures!(@memory $id), $default)
+            .expect(concat!("Cannot create ", stringify!($name)))
+    };
+    (@reload map $name:ident $id:expr) => {
+        $name.with(|map| *map.borrow_mut() = StableBTreeMap::init(stable_structures!(@memory $id)))
+    };
+    (@reload cell $name:ident $id:expr) => {
+        $name.with(|cell| {
+            let current = cell.borrow().get().clone();
+            *cell.borrow_mut() = Cell::init(stable_structures!(@memory $id), current)
+                .expect(concat!("Cannot load ", stringify!($name)));
+        })
+    };
+    (@reset map $name:ident $id:expr) => {
+        $name.with(|map| *map.borrow_mut() = StableBTreeMap::new(stable_structures!(@memory $id)))
+    };
+    (@reset cell $name:ident $id:expr, $default:expr) => {{
+        let initial = $default;
+        $name.with(|cell| {
+            *cell.borrow_mut() = Cell::new(stable_structures!(@memory $id), initial)
+                .expect(concat!("Cannot reset ", stringify!($name)));
+        })
+    }};
+}
+
+stable_structures! {
+    cell ID_COUNTER: IdCell = 0 => 0;
+    map PATIENT_STORAGE: StableBTreeMap<u64, Patient, Memory> = 1;
+    map DOCTOR_STORAGE: StableBTreeMap<u64, Doctor, Memory> = 2;
+    map ROOM_STORAGE: StableBTreeMap<u64, Room, Memory> = 3;
+    map DIAGNOSIS_STORAGE: StableBTreeMap<u64, Diagnosis, Memory> = 4;
+    cell RETENTION_PERIOD: Cell<u64, Memory> = 5 => DEFAULT_RETENTION_PERIOD;
+    map PATIENT_HISTORY: History<Patient> = 6;
+    map DOCTOR_HISTORY: History<Doctor> = 7;
+    map ROOM_HISTORY: History<Room> = 8;
+    cell SCHEMA_VERSION: Cell<u32, Memory> = 9 => 0;
+    cell ETHNICITY_CODES: Cell<EthnicityCodes, Memory> = 10 => EthnicityCodes::default();
+    map CONTACT_STORAGE: StableBTreeMap<(u64, u64), RelatedPerson, Memory> = 11;
+    map IDENTIFIER_INDEX: StableBTreeMap<IdentifierKey, u64, Memory> = 12;
+    map PATIENT_IDENTIFIERS: StableBTreeMap<u64, IdentifierList, Memory> = 13;
+    // Each entity has its own ID sequence, continuing from the shared counter they replace
+    cell PATIENT_ID_COUNTER: IdCell = 14 => ID_COUNTER.with(|c| *c.borrow().get());
+    cell DOCTOR_ID_COUNTER: IdCell = 15 => ID_COUNTER.with(|c| *c.borrow().get());
+    cell ROOM_ID_COUNTER: IdCell = 16 => ID_COUNTER.with(|c| *c.borrow().get());
+    cell DIAGNOSIS_ID_COUNTER: IdCell = 17 => ID_COUNTER.with(|c| *c.borrow().get());
+    cell CONTACT_ID_COUNTER: IdCell = 18 => ID_COUNTER.with(|c| *c.borrow().get());
+    cell FACILITY_CODE: Cell<String, Memory> = 19 => DEFAULT_FACILITY_CODE.to_string();
+    // Keyed by the ID of the duplicate that was merged
+    map MERGES: StableBTreeMap<u64, Merge, Memory> = 20;
+    map CONSENT_STORAGE: StableBTreeMap<(u64, u64), Consent, Memory> = 21;
+    cell CONSENT_ID_COUNTER: IdCell = 22 => 0;
+    map STAFF: StableBTreeMap<PrincipalKey, StaffMember, Memory> = 23;
+    map BREAK_GLASS_ACCESS: StableBTreeMap<u64, BreakGlassAccess, Memory> = 24;
+    cell BREAK_GLASS_ID_COUNTER: IdCell = 25 => 0;
+    cell BREAK_GLASS_DURATION: Cell<u64, Memory> = 26 => DEFAULT_BREAK_GLASS_DURATION;
+    // Keyed by patient ID, as each patient has at most one code that has not been used yet
+    map PORTAL_ENROLLMENTS: StableBTreeMap<u64, Enrollment, Memory> = 27;
+    // Maps the principal of an enrolled patient to their patient ID
+    map PORTAL_LINKS: StableBTreeMap<PrincipalKey, u64, Memory> = 28;
+    cell REDACTION_POLICIES: Cell<RedactionPolicies, Memory> = 29 => RedactionPolicies::default();
+    // Keyed by (patient ID, erasure number). Erasures recorded before erasures were numbered are
+    // keyed by the time of the erasure instead
+    map ERASURES: StableBTreeMap<(u64, u64), Erasure, Memory> = 30;
+    map ENCOUNTERS: StableBTreeMap<u64, Encounter, Memory> = 31;
+    cell ENCOUNTER_ID_COUNTER: IdCell = 32 => 0;
+    map LAB_RESULTS: StableBTreeMap<u64, LabResult, Memory> = 33;
+    cell LAB_RESULT_ID_COUNTER: IdCell = 34 => 0;
+    // HL7 messages that could not be applied
+    map DEAD_LETTERS: StableBTreeMap<u64, DeadLetter, Memory> = 35;
+    cell DEAD_LETTER_ID_COUNTER: IdCell = 36 => 0;
+    cell SNAPSHOT_STATE: Cell<SnapshotState, Memory> = SNAPSHOT_STATE_MEMORY_ID => SnapshotState::default();
+    cell OPERATING_MODE: Cell<OperatingModeSetting, Memory> = 38 => OperatingModeSetting::default();
+    map IDEMPOTENCY_KEYS: StableBTreeMap<IdempotencyKey, IdempotencyRecord, Memory> = 39;
+    // Orders the idempotency keys by when they were recorded, so the expired ones can be pruned
+    map IDEMPOTENCY_EXPIRY: StableBTreeMap<(u64, IdempotencyKey), (), Memory> = 40;
+    cell IDEMPOTENCY_WINDOW: Cell<u64, Memory> = 41 => DEFAULT_IDEMPOTENCY_WINDOW;
+    cell ERASURE_ID_COUNTER: IdCell = 42 => 0;
 }
 
 // Represents errors that might occcur
 #[derive(candid::CandidType, Deserialize, Serialize)]
 enum Error {
-    NotFound { msg: String },
-    EmptyFields { msg: String },
-    AlreadyAssigned { msg: String },
-    CanNotAssign { msg: String },
+    NotFound {
+        msg: String,
+    },
+    EmptyFields {
+        msg: String,
+    },
+    AlreadyAssigned {
+        msg: String,
+    },
+    CanNotAssign {
+        msg: String,
+    },
+    CanNotPurge {
+        msg: String,
+    },
+    CanNotMerge {
+        msg: String,
+    },
+    ConsentRequired {
+        msg: String,
+        scope: ConsentScope,
+    },
+    Unauthorized {
+        msg: String,
+    },
+    Conflict {
+        msg: String,
+        current: Option<Box<Record>>, //Left out for callers who may not read the record
+    },
+    ValidationFailed {
+        msg: String,
+        fields: Vec<FieldError>,
+    },
+    ServiceUnavailable {
+        msg: String,
+        expected_end: Option<u64>, //When writes are expected to be accepted again
+    },
 }
 
-//Adds a new patient with the provided payload
-#[ic_cdk::update]
-fn add_patient(payload: PatientPayLoad) -> Result<Patient, Error> {
-    //Validation Logic
-    if payload.name.is_empty()
-        || payload.address.is_empty()
-        || payload.date_of_birth.is_empty()
-        || payload.ethncity.is_empty()
-        || payload.gender.is_empty()
-        || payload.phone_number.is_empty()
-        || payload.next_of_kin.is_empty()
-        || payload.kins_phone_number.is_empty()
-        || payload.age == 0
-    {
-        return Err(Error::EmptyFields {
-            msg: "Please fill in all the required fields to be able to submit".to_string(),
-        });
+impl Error {
+    //Describes the error in one line, listing the invalid fields when validation failed
+    fn message(&self) -> String {
+        match self {
+            Error::NotFound { msg }
+            | Error::EmptyFields { msg }
+            | Error::AlreadyAssigned { msg }
+            | Error::CanNotAssign { msg }
+            | Error::CanNotPurge { msg }
+            | Error::CanNotMerge { msg }
+            | Error::ConsentRequired { msg, .. }
+            | Error::Unauthorized { msg }
+            | Error::Conflict { msg, .. }
+            | Error::ServiceUnavailable { msg, .. } => msg.clone(),
+            Error::ValidationFailed { msg, fields } => {
+                let fields: Vec<String> = fields.iter().map(FieldError::to_string).collect();
+                format!("{}: {}", msg, fields.join("; "))
+            }
+        }
     }
+}
 
-    let id = ID_COUNTER.with(|counter| {
+//Takes the next ID from the given sequence
+fn next_id(counter: &'static LocalKey<RefCell<IdCell>>) -> u64 {
+    counter.with(|counter| {
         let current_value = *counter.borrow().get();
         let _ = counter.borrow_mut().set(current_value + 1);
         current_value + 1
-    });
+    })
+}
+
+//Only controllers of the canister may use the admin endpoints
+fn ensure_admin() -> Result<(), Error> {
+    if is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only an admin can perform this action".to_string(),
+        })
+    }
+}
+
+//Saves a patient as its next version and keeps that version in its history
+fn save_patient(patient: &mut Patient) {
+    patient.version += 1;
+    PATIENT_STORAGE.with(|storage| storage.borrow_mut().insert(patient.id, patient.clone()));
+    record_revision(&PATIENT_HISTORY, patient.id, patient.version, patient);
+    certify(Collection::Patients, patient.id, patient);
+}
+
+//Saves a doctor as its next version and keeps that version in its history
+fn save_doctor(doctor: &mut Doctor) {
+    doctor.version += 1;
+    DOCTOR_STORAGE.with(|storage| storage.borrow_mut().insert(doctor.id, doctor.clone()));
+    record_revision(&DOCTOR_HISTORY, doctor.id, doctor.version, doctor);
+    certify(Collection::Doctors, doctor.id, doctor);
+}
+
+//Saves a room as its next version and keeps that version in its history
+fn save_room(room: &mut Room) {
+    room.version += 1;
+    ROOM_STORAGE.with(|storage| storage.borrow_mut().insert(room.id, room.clone()));
+    record_revision(&ROOM_HISTORY, room.id, room.version, room);
+    certify(Collection::Rooms, room.id, room);
+}
+
+//Rejects an update that was made against an outdated version of the record
+fn ensure_version(expected_version: u64, current: Record) -> Result<(), Error> {
+    let current_version = match &current {
+        Record::Patient(patient) => patient.version,
+        Record::Doctor(doctor) => doctor.version,
+        Record::Room(room) => room.version,
+        Record::Diagnosis(diagnosis) => diagnosis.version,
+    };
+
+    if current_version != expected_version {
+        let caller = caller();
+        let readable = match &current {
+            Record::Patient(patient) => {
+                can_read_patient(caller, patient.id) || is_patient(caller, patient.id)
+            }
+            Record::Diagnosis(diagnosis) => can_read_patient(caller, diagnosis.patient_id),
+            Record::Doctor(_) | Record::Room(_) => true,
+        };
+        return Err(Error::Conflict {
+            msg: format!(
+                "Expected version {} but the record is at version {}",
+                expected_version, current_version
+            ),
+            current: readable.then(|| {
+                Box::new(match current {
+                    Record::Patient(patient) => Record::Patient(project(patient)),
+                    Record::Diagnosis(diagnosis) => Record::Diagnosis(project(diagnosis)),
+                    other => other,
+                })
+            }),
+        });
+    }
+    Ok(())
+}
 
-    let patient = Patient {
+fn archive_now() -> Archive {
+    Archive {
+        archived_by: caller(),
+        archived_on: time(),
+    }
+}
+
+//Checks that an archived record has been kept for the whole retention period
+fn ensure_retention_passed(archive: &Archive) -> Result<(), Error> {
+    let retention_period = RETENTION_PERIOD.with(|period| *period.borrow().get());
+    if time() < archive.archived_on.saturating_add(retention_period) {
+        return Err(Error::CanNotPurge {
+            msg: "The retention period for this record has not passed yet".to_string(),
+        });
+    }
+    Ok(())
+}
+
+//Adds a new patient with the provided payload, reporting patients they may duplicate.
+//A retry with the same idempotency key returns the original registration
+#[ic_cdk::update]
+fn add_patient(
+    payload: PatientPayLoad,
+    idempotency_key: Option<String>,
+) -> Result<PatientRegistration, Error> {
+    observe("add_patient", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        idempotent("add_patient", idempotency_key, payload, |payload| {
+            //Validation Logic
+            validate_patient_payload(&payload)?;
+
+            let possible_duplicates = find_duplicates(&(&payload).into(), 0);
+            let patient = register_patient(payload, Vec::new());
+
+            Ok(PatientRegistration::new(
+                project(patient),
+                possible_duplicates,
+            ))
+        })
+    })
+}
+
+//Stores a new patient from a validated payload and assigns their MRN
+fn register_patient(payload: PatientPayLoad, mut review_notes: Vec<String>) -> Patient {
+    cap_review_notes(&mut review_notes);
+    let id = next_id(&PATIENT_ID_COUNTER);
+
+    let mut patient = Patient {
         id,
         name: payload.name,
+        age: current_age(&payload.date_of_birth),
         date_of_birth: payload.date_of_birth,
-        age: payload.age,
         gender: payload.gender,
-        ethncity: payload.ethncity,
+        ethnicity: payload.ethnicity,
         address: payload.address,
         phone_number: payload.phone_number,
         email: payload.email,
-        next_of_kin: payload.next_of_kin,
-        kins_phone_number: payload.kins_phone_number,
         registered_on: time(),
+        mrn: Some(assign_mrn(id)),
+        merged_into: None,
+        archived: None,
+        version: 0, // Becomes 1 when first saved
+        review_notes,
     };
 
-    PATIENT_STORAGE.with(|storage| storage.borrow_mut().insert(id, patient.clone()));
-    Ok(patient)
+    save_patient(&mut patient);
+    patient
 }
 
-//Retrieves inforamtion about a patient based on the ID
-#[ic_cdk::query]
+//Retrieves inforamtion about a patient the caller may read
+#[ic_cdk::query(name = "get_patient")]
+fn read_patient(id: u64) -> Result<Patient, Error> {
+    let patient = get_patient(id)?;
+    ensure_can_read_patient(patient.id)?;
+
+    Ok(project(patient))
+}
+
+//Loads a patient based on the ID. Merged patients redirect to the survivor
 fn get_patient(id: u64) -> Result<Patient, Error> {
-    PATIENT_STORAGE.with(|storage| match storage.borrow().get(&id) {
-        Some(patient) => Ok(patient.clone()),
-        None => Err(Error::NotFound {
+    match PATIENT_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(Patient {
+            merged_into: Some(survivor_id),
+            ..
+        }) => get_patient(survivor_id),
+        Some(mut patient) if patient.archived.is_none() => {
+            patient.age = current_age(&patient.date_of_birth);
+            Ok(patient)
+        }
+        _ => Err(Error::NotFound {
             msg: format!("Patient with ID {} can not be found", id),
         }),
-    })
+    }
 }
 
 //Get all patients
@@ -315,106 +767,204 @@ fn get_patient(id: u64) -> Result<Patient, Error> {
 //     patients
 // }
 
-// Deletes a patient based on the ID.
+// Archives a patient based on the ID. The record is kept until it is purged.
 #[ic_cdk::update]
 fn delete_patient(id: u64) -> Result<(), Error> {
-    PATIENT_STORAGE.with(|storage| {
-        if storage.borrow_mut().remove(&id).is_some() {
-            Ok(())
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Patient with ID {} not found", id),
-            })
-        }
+    observe("delete_patient", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        let mut patient = get_patient(id)?;
+        patient.archived = Some(archive_now());
+
+        save_patient(&mut patient);
+        Ok(())
+    })
+}
+
+//Lists all the archived patients
+#[ic_cdk::query]
+fn get_archived_patients() -> Result<Vec<Patient>, Error> {
+    ensure_admin()?;
+
+    Ok(PATIENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, patient)| patient.archived.is_some())
+            .map(|(_, patient)| patient)
+            .collect()
+    }))
+}
+
+//Restores an archived patient so it shows up in normal queries again
+#[ic_cdk::update]
+fn restore_patient(id: u64) -> Result<Patient, Error> {
+    observe("restore_patient", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        let mut patient = PATIENT_STORAGE
+            .with(|storage| storage.borrow().get(&id))
+            .filter(|patient| patient.archived.is_some())
+            .ok_or(Error::NotFound {
+                msg: format!("Archived patient with ID {} not found", id),
+            })?;
+        patient.archived = None;
+
+        save_patient(&mut patient);
+        Ok(patient)
+    })
+}
+
+//Permanently removes an archived patient once its retention period has passed
+#[ic_cdk::update]
+fn purge_patient(id: u64) -> Result<(), Error> {
+    observe("purge_patient", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        PATIENT_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            match storage.get(&id).and_then(|patient| patient.archived) {
+                Some(archive) => {
+                    ensure_retention_passed(&archive)?;
+                    storage.remove(&id);
+                    remove_history(&PATIENT_HISTORY, id);
+                    uncertify(Collection::Patients, id);
+                    remove_contacts(id);
+                    remove_identifiers(id);
+                    remove_consents(id);
+                    remove_portal_access(id);
+                    remove_encounters_and_lab_results(id);
+                    Ok(())
+                }
+                None => Err(Error::NotFound {
+                    msg: format!("Archived patient with ID {} not found", id),
+                }),
+            }
+        })
     })
 }
 
 //Updates the information of the patient with the ID and payload
 #[ic_cdk::update]
-fn update_patient(id: u64, payload: PatientPayLoad) -> Result<Patient, Error> {
-    //Validation Logic
-    if payload.name.is_empty()
-        || payload.address.is_empty()
-        || payload.date_of_birth.is_empty()
-        || payload.ethncity.is_empty()
-        || payload.gender.is_empty()
-        || payload.phone_number.is_empty()
-        || payload.next_of_kin.is_empty()
-        || payload.kins_phone_number.is_empty()
-    {
-        return Err(Error::EmptyFields {
-            msg: "You must fill all of the required fields".to_string(),
-        });
-    }
+fn update_patient(
+    id: u64,
+    expected_version: u64,
+    payload: PatientPayLoad,
+) -> Result<Patient, Error> {
+    observe("update_patient", || {
+        ensure_writes_allowed()?;
+
+        //Validation Logic
+        validate_patient_payload(&payload)?;
+
+        let mut updated_patient = get_patient(id)?;
+        ensure_can_write_patient(updated_patient.id)?;
+        ensure_version(expected_version, Record::Patient(updated_patient.clone()))?;
+
+        // Update the fields
+        updated_patient.name = payload.name;
+        updated_patient.phone_number = payload.phone_number;
+        updated_patient.address = payload.address;
+        updated_patient.age = current_age(&payload.date_of_birth);
+        updated_patient.date_of_birth = payload.date_of_birth;
+        updated_patient.email = payload.email;
+        updated_patient.ethnicity = payload.ethnicity;
+        updated_patient.gender = payload.gender;
+
+        // Save the updated patient, keeping the previous version in its history
+        save_patient(&mut updated_patient);
+
+        Ok(project(updated_patient))
+    })
+}
 
-    PATIENT_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(existing_patient) = storage.get(&id) {
-            // Clone the existing patient to make a mutable copy
-            let mut updated_patient = existing_patient.clone();
-
-            // Update the fields
-            updated_patient.name = payload.name;
-            updated_patient.phone_number = payload.phone_number;
-            updated_patient.address = payload.address;
-            updated_patient.date_of_birth = payload.date_of_birth;
-            updated_patient.email = payload.email;
-            updated_patient.ethncity = payload.ethncity;
-            updated_patient.gender = payload.gender;
-            updated_patient.next_of_kin = payload.next_of_kin;
-            updated_patient.kins_phone_number = payload.kins_phone_number;
-
-            // Re-insert the updated patient back into the storage
-            storage.insert(id, updated_patient.clone());
-
-            Ok(updated_patient)
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Patient with ID {} not found", id),
-            })
+//Updates only the fields of the patient that are provided in the patch
+#[ic_cdk::update]
+fn patch_patient(id: u64, expected_version: u64, patch: PatientPatch) -> Result<Patient, Error> {
+    observe("patch_patient", || {
+        ensure_writes_allowed()?;
+
+        //Validation Logic
+        validate_patient_patch(&patch)?;
+
+        let mut updated_patient = get_patient(id)?;
+        ensure_can_write_patient(updated_patient.id)?;
+        ensure_version(expected_version, Record::Patient(updated_patient.clone()))?;
+
+        // Update each provided field
+        if let Some(name) = patch.name {
+            updated_patient.name = name;
+        }
+        if let Some(date_of_birth) = patch.date_of_birth {
+            updated_patient.age = current_age(&date_of_birth);
+            updated_patient.date_of_birth = date_of_birth;
+        }
+        if let Some(gender) = patch.gender {
+            updated_patient.gender = gender;
+        }
+        if let Some(ethnicity) = patch.ethnicity {
+            updated_patient.ethnicity = ethnicity;
         }
+        if let Some(address) = patch.address {
+            updated_patient.address = address;
+        }
+        if let Some(phone_number) = patch.phone_number {
+            updated_patient.phone_number = phone_number;
+        }
+        if let Some(email) = patch.email {
+            updated_patient.email = email;
+        }
+
+        save_patient(&mut updated_patient);
+
+        Ok(project(updated_patient))
     })
 }
 
-//Adds a new doctor with the provide payload
+//Adds a new doctor with the provide payload. A retry with the same idempotency key returns the original doctor
 #[ic_cdk::update]
-fn add_doctor(payload: DoctorPayLoad) -> Result<Doctor, Error> {
-    //Validation Logic
-    if payload.name.is_empty()
-        || payload.email.is_empty()
-        || payload.phone_number.is_empty()
-        || payload.speciality.is_empty()
-    {
-        return Err(Error::EmptyFields {
-            msg: "You must fill in all the required fields".to_string(),
-        });
-    }
+fn add_doctor(payload: DoctorPayLoad, idempotency_key: Option<String>) -> Result<Doctor, Error> {
+    observe("add_doctor", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
 
-    let id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        let _ = counter.borrow_mut().set(current_value + 1);
-        current_value + 1
-    });
+        idempotent("add_doctor", idempotency_key, payload, |payload| {
+            //Validation Logic
+            validate_doctor_payload(&payload)?;
 
-    let doctor = Doctor {
+            Ok(register_doctor(payload))
+        })
+    })
+}
+
+//Stores a new doctor from a validated payload
+fn register_doctor(payload: DoctorPayLoad) -> Doctor {
+    let id = next_id(&DOCTOR_ID_COUNTER);
+
+    let mut doctor = Doctor {
         id,
         name: payload.name,
         email: payload.email,
         phone_number: payload.phone_number,
         speciality: payload.speciality,
         current_patient: 0,
+        archived: None,
+        version: 0, // Becomes 1 when first saved
     };
 
-    DOCTOR_STORAGE.with(|storage| storage.borrow_mut().insert(id, doctor.clone()));
-    Ok(doctor)
+    save_doctor(&mut doctor);
+    doctor
 }
 
 //Retrieves inforamtion about a doctor based on the ID provided
 #[ic_cdk::query]
 fn get_doctor(id: u64) -> Result<Doctor, Error> {
     DOCTOR_STORAGE.with(|storage| match storage.borrow().get(&id) {
-        Some(doctor) => Ok(doctor.clone()),
-        None => Err(Error::NotFound {
+        Some(doctor) if doctor.archived.is_none() => Ok(doctor.clone()),
+        _ => Err(Error::NotFound {
             msg: format!("Doctor with ID {} can not be found", id),
         }),
     })
@@ -432,95 +982,180 @@ fn get_doctor(id: u64) -> Result<Doctor, Error> {
 //     doctors
 // }
 
-// Deletes a doctor based on the ID.
+// Archives a doctor based on the ID. The record is kept until it is purged.
 #[ic_cdk::update]
 fn delete_doctor(id: u64) -> Result<(), Error> {
-    DOCTOR_STORAGE.with(|storage| {
-        if storage.borrow_mut().remove(&id).is_some() {
-            Ok(())
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Doctor with ID {} not found", id),
-            })
-        }
+    observe("delete_doctor", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        let mut doctor = get_doctor(id)?;
+        doctor.archived = Some(archive_now());
+
+        save_doctor(&mut doctor);
+        Ok(())
+    })
+}
+
+//Lists all the archived doctors
+#[ic_cdk::query]
+fn get_archived_doctors() -> Result<Vec<Doctor>, Error> {
+    ensure_admin()?;
+
+    Ok(DOCTOR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, doctor)| doctor.archived.is_some())
+            .map(|(_, doctor)| doctor)
+            .collect()
+    }))
+}
+
+//Restores an archived doctor so it shows up in normal queries again
+#[ic_cdk::update]
+fn restore_doctor(id: u64) -> Result<Doctor, Error> {
+    observe("restore_doctor", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        let mut doctor = DOCTOR_STORAGE
+            .with(|storage| storage.borrow().get(&id))
+            .filter(|doctor| doctor.archived.is_some())
+            .ok_or(Error::NotFound {
+                msg: format!("Archived doctor with ID {} not found", id),
+            })?;
+        doctor.archived = None;
+
+        save_doctor(&mut doctor);
+        Ok(doctor)
+    })
+}
+
+//Permanently removes an archived doctor once its retention period has passed
+#[ic_cdk::update]
+fn purge_doctor(id: u64) -> Result<(), Error> {
+    observe("purge_doctor", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        DOCTOR_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            match storage.get(&id).and_then(|doctor| doctor.archived) {
+                Some(archive) => {
+                    ensure_retention_passed(&archive)?;
+                    storage.remove(&id);
+                    remove_history(&DOCTOR_HISTORY, id);
+                    uncertify(Collection::Doctors, id);
+                    Ok(())
+                }
+                None => Err(Error::NotFound {
+                    msg: format!("Archived doctor with ID {} not found", id),
+                }),
+            }
+        })
     })
 }
 
 //Updates the information of the doctor with the ID and payload
 #[ic_cdk::update]
-fn update_doctor(id: u64, payload: DoctorPayLoad) -> Result<Doctor, Error> {
-    //Validation Logic
-    if payload.name.is_empty()
-        || payload.email.is_empty()
-        || payload.phone_number.is_empty()
-        || payload.speciality.is_empty()
-    {
-        return Err(Error::EmptyFields {
-            msg: "You must fill in all the required fields".to_string(),
-        });
-    }
+fn update_doctor(id: u64, expected_version: u64, payload: DoctorPayLoad) -> Result<Doctor, Error> {
+    observe("update_doctor", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
 
-    DOCTOR_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(existing_doctor) = storage.get(&id) {
-            // Clone the existing doctor to make a mutable copy
-            let mut updated_doctor = existing_doctor.clone();
-
-            // Update the fields
-            updated_doctor.name = payload.name;
-            updated_doctor.phone_number = payload.phone_number;
-            updated_doctor.email = payload.email;
-            updated_doctor.speciality = payload.speciality;
-
-            // Re-insert the updated doctor back into the storage
-            storage.insert(id, updated_doctor.clone());
-
-            Ok(updated_doctor)
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Doctor with ID {} not found", id),
-            })
+        //Validation Logic
+        validate_doctor_payload(&payload)?;
+
+        let mut updated_doctor = get_doctor(id)?;
+        ensure_version(expected_version, Record::Doctor(updated_doctor.clone()))?;
+
+        // Update the fields
+        updated_doctor.name = payload.name;
+        updated_doctor.phone_number = payload.phone_number;
+        updated_doctor.email = payload.email;
+        updated_doctor.speciality = payload.speciality;
+
+        // Save the updated doctor, keeping the previous version in its history
+        save_doctor(&mut updated_doctor);
+
+        Ok(updated_doctor)
+    })
+}
+
+//Updates only the fields of the doctor that are provided in the patch
+#[ic_cdk::update]
+fn patch_doctor(id: u64, expected_version: u64, patch: DoctorPatch) -> Result<Doctor, Error> {
+    observe("patch_doctor", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        //Validation Logic
+        validate_doctor_patch(&patch)?;
+
+        let mut updated_doctor = get_doctor(id)?;
+        ensure_version(expected_version, Record::Doctor(updated_doctor.clone()))?;
+
+        // Update each provided field
+        if let Some(name) = patch.name {
+            updated_doctor.name = name;
         }
+        if let Some(email) = patch.email {
+            updated_doctor.email = email;
+        }
+        if let Some(phone_number) = patch.phone_number {
+            updated_doctor.phone_number = phone_number;
+        }
+        if let Some(speciality) = patch.speciality {
+            updated_doctor.speciality = speciality;
+        }
+
+        save_doctor(&mut updated_doctor);
+
+        Ok(updated_doctor)
     })
 }
 
-// Adds a new Room
+// Adds a new Room. A retry with the same idempotency key returns the original room
 #[ic_cdk::update]
-fn add_room(payload: RoomPayload) -> Result<Room, Error> {
-    // Validation logic
-    if payload.name.is_empty() || payload.location.is_empty() {
-        return Err(Error::EmptyFields {
-            msg: "Please fill in all the required fields".to_string(),
-        });
-    }
+fn add_room(payload: RoomPayload, idempotency_key: Option<String>) -> Result<Room, Error> {
+    observe("add_room", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
 
-    let id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        let _ = counter.borrow_mut().set(current_value + 1);
-        current_value + 1
-    });
+        idempotent("add_room", idempotency_key, payload, |payload| {
+            // Validation logic
+            validate_room_payload(&payload)?;
 
-    let room = Room {
+            Ok(register_room(payload, Vec::new()))
+        })
+    })
+}
+
+//Stores a new room from a validated payload
+fn register_room(payload: RoomPayload, equipment: Vec<String>) -> Room {
+    let id = next_id(&ROOM_ID_COUNTER);
+
+    let mut room = Room {
         id,
         name: payload.name,
         location: payload.location,
         current_doctor_id: 0,
-        equipment: Vec::new(), // Initial empty equipment list
+        equipment,
+        archived: None,
+        version: 0, // Becomes 1 when first saved
     };
 
-    ROOM_STORAGE.with(|storage| {
-        storage.borrow_mut().insert(id, room.clone());
-    });
-
-    Ok(room)
+    save_room(&mut room);
+    room
 }
 
 // Retrieves information about a Room based on the ID.
 #[ic_cdk::query]
 fn get_room(id: u64) -> Result<Room, Error> {
     ROOM_STORAGE.with(|storage| match storage.borrow().get(&id) {
-        Some(room) => Ok(room.clone()),
-        None => Err(Error::NotFound {
+        Some(room) if room.archived.is_none() => Ok(room.clone()),
+        _ => Err(Error::NotFound {
             msg: format!("Room with ID {} not found", id),
         }),
     })
@@ -540,190 +1175,416 @@ fn get_room(id: u64) -> Result<Room, Error> {
 
 /// Updates information about a Room based on the ID and payload.
 #[ic_cdk::update]
-fn update_room(id: u64, payload: RoomPayload) -> Result<Room, Error> {
-    // Validation logic
-    if payload.name.is_empty() || payload.location.is_empty() {
-        return Err(Error::EmptyFields {
-            msg: "Please fill in all the required fields".to_string(),
-        });
-    }
+fn update_room(id: u64, expected_version: u64, payload: RoomPayload) -> Result<Room, Error> {
+    observe("update_room", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
 
-    ROOM_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(existing_room) = storage.get(&id) {
-            let mut updated_room = existing_room.clone();
+        // Validation logic
+        validate_room_payload(&payload)?;
 
-            updated_room.name = payload.name;
-            updated_room.location = payload.location;
+        let mut updated_room = get_room(id)?;
+        ensure_version(expected_version, Record::Room(updated_room.clone()))?;
 
-            // Equipment is not updated here
-            storage.insert(id, updated_room.clone());
+        updated_room.name = payload.name;
+        updated_room.location = payload.location;
 
-            Ok(updated_room)
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Room with ID {} not found", id),
-            })
+        // Equipment is not updated here
+        save_room(&mut updated_room);
+
+        Ok(updated_room)
+    })
+}
+
+/// Updates only the fields of a Room that are provided in the patch.
+#[ic_cdk::update]
+fn patch_room(id: u64, expected_version: u64, patch: RoomPatch) -> Result<Room, Error> {
+    observe("patch_room", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        // Validation logic
+        validate_room_patch(&patch)?;
+
+        let mut updated_room = get_room(id)?;
+        ensure_version(expected_version, Record::Room(updated_room.clone()))?;
+
+        if let Some(name) = patch.name {
+            updated_room.name = name;
+        }
+        if let Some(location) = patch.location {
+            updated_room.location = location;
         }
+
+        save_room(&mut updated_room);
+
+        Ok(updated_room)
     })
 }
 
-/// Deletes a Room based on the ID.
+/// Archives a Room based on the ID. The record is kept until it is purged.
 #[ic_cdk::update]
 fn delete_room(id: u64) -> Result<(), Error> {
-    ROOM_STORAGE.with(|storage| {
-        if storage.borrow_mut().remove(&id).is_some() {
-            Ok(())
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Room with ID {} not found", id),
-            })
-        }
+    observe("delete_room", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        let mut room = get_room(id)?;
+        room.archived = Some(archive_now());
+
+        save_room(&mut room);
+        Ok(())
+    })
+}
+
+//Lists all the archived rooms
+#[ic_cdk::query]
+fn get_archived_rooms() -> Result<Vec<Room>, Error> {
+    ensure_admin()?;
+
+    Ok(ROOM_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, room)| room.archived.is_some())
+            .map(|(_, room)| room)
+            .collect()
+    }))
+}
+
+//Restores an archived room so it shows up in normal queries again
+#[ic_cdk::update]
+fn restore_room(id: u64) -> Result<Room, Error> {
+    observe("restore_room", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        let mut room = ROOM_STORAGE
+            .with(|storage| storage.borrow().get(&id))
+            .filter(|room| room.archived.is_some())
+            .ok_or(Error::NotFound {
+                msg: format!("Archived room with ID {} not found", id),
+            })?;
+        room.archived = None;
+
+        save_room(&mut room);
+        Ok(room)
+    })
+}
+
+//Permanently removes an archived room once its retention period has passed
+#[ic_cdk::update]
+fn purge_room(id: u64) -> Result<(), Error> {
+    observe("purge_room", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        ROOM_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            match storage.get(&id).and_then(|room| room.archived) {
+                Some(archive) => {
+                    ensure_retention_passed(&archive)?;
+                    storage.remove(&id);
+                    remove_history(&ROOM_HISTORY, id);
+                    uncertify(Collection::Rooms, id);
+                    Ok(())
+                }
+                None => Err(Error::NotFound {
+                    msg: format!("Archived room with ID {} not found", id),
+                }),
+            }
+        })
     })
 }
 
 //Clears the current patient once a diagnosis is given
 #[ic_cdk::update]
 fn clear_current_patient(id: u64) -> Result<Doctor, Error> {
+    observe("clear_current_patient", || {
+        ensure_writes_allowed()?;
 
-    DOCTOR_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(existing_doctor) = storage.get(&id) {
-            let mut updated_doctor = existing_doctor.clone();
+        let mut updated_doctor = get_doctor(id)?;
 
-            updated_doctor.current_patient = 0; 
+        updated_doctor.current_patient = 0;
 
-            storage.insert(id, updated_doctor.clone());
+        save_doctor(&mut updated_doctor);
 
-            Ok(updated_doctor)
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Doctor with ID {} not found", id),
-            })
-        }
+        Ok(updated_doctor)
     })
 }
 
-//Adds a new diagnosis 
+//Adds a new diagnosis. A retry with the same idempotency key returns the original diagnosis
 #[ic_cdk::update]
-fn add_diagnosis(payload: DiagnosisPayload) -> Result<Diagnosis, Error> {
-    // Validation logic
-    if payload.doctor_id == 0 
-        || payload.patient_id == 0 
-        || payload.medication.is_empty()
-        || payload.treatment.is_empty() {
-        return Err(Error::EmptyFields {
-            msg: "Please fill in all the required fields".to_string(),
-        });
-    }
-
-    //Check if the doctor and patient exist
-    let _patient = get_patient(payload.patient_id)?;
-    let _doctor = get_doctor(payload.doctor_id)?; 
+fn add_diagnosis(
+    payload: DiagnosisPayload,
+    idempotency_key: Option<String>,
+) -> Result<Diagnosis, Error> {
+    observe("add_diagnosis", || {
+        ensure_writes_allowed()?;
+        ensure_can_diagnose(payload.doctor_id)?;
+
+        idempotent("add_diagnosis", idempotency_key, payload, |mut payload| {
+            // Validation logic
+            if payload.doctor_id == 0
+                || payload.patient_id == 0
+                || payload.medication.is_empty()
+                || payload.treatment.is_empty()
+            {
+                return Err(Error::EmptyFields {
+                    msg: "Please fill in all the required fields".to_string(),
+                });
+            }
+            validate_clinical_text(&payload.treatment, &payload.medication)?;
+
+            //Check if the doctor and patient exist. A merged duplicate's diagnoses go to the survivor
+            payload.patient_id = get_patient(payload.patient_id)?.id;
+            let _doctor = get_doctor(payload.doctor_id)?;
+
+            let diagnosis = record_diagnosis(payload);
+
+            let _clear_patient = clear_current_patient(diagnosis.doctor_id)?;
+
+            Ok(project(diagnosis))
+        })
+    })
+}
 
-    let id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        let _ = counter.borrow_mut().set(current_value + 1);
-        current_value + 1
-    });
+//Stores a new diagnosis for a patient and doctor that exist
+fn record_diagnosis(payload: DiagnosisPayload) -> Diagnosis {
+    let id = next_id(&DIAGNOSIS_ID_COUNTER);
 
     let diagnosis = Diagnosis {
         id,
         doctor_id: payload.doctor_id,
-        patient_id:  payload.patient_id,
+        patient_id: payload.patient_id,
         treatment: payload.treatment,
-        medication: payload.medication
+        medication: payload.medication,
+        version: 1,
     };
 
     DIAGNOSIS_STORAGE.with(|storage| {
         storage.borrow_mut().insert(id, diagnosis.clone());
     });
+    diagnosis
+}
 
-    let _clear_patient = clear_current_patient(payload.doctor_id)?;
-
-    Ok(diagnosis)
+//Retrieves the diagnoses of a patient the caller may read
+#[ic_cdk::query]
+fn get_patient_diagnoses(patient_id: u64) -> Result<Vec<Diagnosis>, Error> {
+    let patient = get_patient(patient_id)?;
+    ensure_can_read_patient(patient.id)?;
+
+    Ok(DIAGNOSIS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, diagnosis)| diagnosis.patient_id == patient.id)
+            .map(|(_, diagnosis)| project(diagnosis))
+            .collect()
+    }))
 }
 
 //Assign a patient to a doctor
 #[ic_cdk::update]
 fn assign_patient_a_doctor(patient_id: u64, doctor_id: u64) -> Result<(), Error> {
-    // Check if the patient and doctor exist
-    let _patient = get_patient(patient_id)?;
-    let doctor = get_doctor(doctor_id)?;
-
-    //Check if the doctor currently has a patient
-    if doctor.current_patient != 0 {
-        return Err(Error::CanNotAssign {
-            msg: "The doctor currently has a patient".to_string(),
-        });
-    }
+    observe("assign_patient_a_doctor", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        // Check if the patient and doctor exist. A merged duplicate is assigned as the survivor
+        let patient_id = get_patient(patient_id)?.id;
+        let doctor = get_doctor(doctor_id)?;
+
+        //Check if the doctor currently has a patient
+        if doctor.current_patient != 0 {
+            return Err(Error::CanNotAssign {
+                msg: "The doctor currently has a patient".to_string(),
+            });
+        }
 
-    // Check if the patient is already assigned to the doctor
-    if doctor.current_patient == patient_id {
-        return Err(Error::AlreadyAssigned {
-            msg: "The patient is already assigned to the doctor".to_string(),
-        });
-    }
+        // Check if the patient is already assigned to the doctor
+        if doctor.current_patient == patient_id {
+            return Err(Error::AlreadyAssigned {
+                msg: "The patient is already assigned to the doctor".to_string(),
+            });
+        }
 
-    // Assign the patient
-    DOCTOR_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
+        // Assign the patient
         let mut updated_doctor = doctor.clone();
         updated_doctor.current_patient = patient_id;
-        storage.insert(doctor_id, updated_doctor);
-    });
+        save_doctor(&mut updated_doctor);
 
-    Ok(())
+        Ok(())
+    })
 }
 
 //Assign a doctor to a room
 #[ic_cdk::update]
 fn assign_doctor_a_room(doctor_id: u64, room_id: u64) -> Result<(), Error> {
-    // Check if the doctor and room exist
-    let _doctor = get_doctor(doctor_id)?;
-    let room = get_room(room_id)?;
-
-    //Check if the room currently has a doctor
-    if room.current_doctor_id != 0 {
-        return Err(Error::CanNotAssign {
-            msg: "The room currently has a doctor".to_string(),
-        });
-    }
+    observe("assign_doctor_a_room", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        // Check if the doctor and room exist
+        let _doctor = get_doctor(doctor_id)?;
+        let room = get_room(room_id)?;
+
+        //Check if the room currently has a doctor
+        if room.current_doctor_id != 0 {
+            return Err(Error::CanNotAssign {
+                msg: "The room currently has a doctor".to_string(),
+            });
+        }
 
-    // Check if the doctor is already assigned to the room
-    if room.current_doctor_id == doctor_id {
-        return Err(Error::AlreadyAssigned {
-            msg: "The doctor is already assigned to the room".to_string(),
-        });
-    }
+        // Check if the doctor is already assigned to the room
+        if room.current_doctor_id == doctor_id {
+            return Err(Error::AlreadyAssigned {
+                msg: "The doctor is already assigned to the room".to_string(),
+            });
+        }
 
-    // Assign the doctor
-    ROOM_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
+        // Assign the doctor
         let mut updated_room = room.clone();
         updated_room.current_doctor_id = doctor_id;
-        storage.insert(room_id, updated_room);
-    });
+        save_room(&mut updated_room);
 
-    Ok(())
+        Ok(())
+    })
 }
 
 /// Updates the equipment in a room.
 #[ic_cdk::update]
-fn update_room_equipment(room_id: u64, equipment: Vec<String>) -> Result<(), Error> {
-    // Check if the room exists
-    let room = get_room(room_id)?;
+fn update_room_equipment(
+    room_id: u64,
+    expected_version: u64,
+    equipment: Vec<String>,
+) -> Result<(), Error> {
+    observe("update_room_equipment", || {
+        ensure_writes_allowed()?;
+        ensure_receptionist()?;
+
+        //Validation Logic
+        Validator::default()
+            .check("equipment", check_equipment(&equipment))
+            .finish()?;
+
+        // Check if the room exists
+        let room = get_room(room_id)?;
+        ensure_version(expected_version, Record::Room(room.clone()))?;
 
-    // Update the equipment in the classroom
-    ROOM_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
+        // Update the equipment in the classroom
         let mut updated_room = room.clone();
         updated_room.equipment = equipment;
-        storage.insert(room_id, updated_room);
-    });
+        save_room(&mut updated_room);
 
-    Ok(())
+        Ok(())
+    })
+}
+
+//Sets how long archived records must be kept before they can be purged, in nanoseconds
+#[ic_cdk::update]
+fn set_retention_period(period: u64) -> Result<(), Error> {
+    observe("set_retention_period", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        RETENTION_PERIOD
+            .with(|cell| cell.borrow_mut().set(period))
+            .expect("Cannot set the retention period");
+        Ok(())
+    })
+}
+
+//Retrieves the current retention period for archived records, in nanoseconds
+#[ic_cdk::query]
+fn get_retention_period() -> u64 {
+    RETENTION_PERIOD.with(|period| *period.borrow().get())
+}
+
+//Lists the patients whose migrated demographics need a manual review
+#[ic_cdk::query]
+fn get_patients_needing_review() -> Result<Vec<Patient>, Error> {
+    ensure_admin()?;
+
+    Ok(PATIENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, patient)| {
+                patient.archived.is_none()
+                    && patient.merged_into.is_none()
+                    && !patient.review_notes.is_empty()
+            })
+            .map(|(_, patient)| patient)
+            .collect()
+    }))
+}
+
+//Clears the review notes of a patient once their demographics have been checked
+#[ic_cdk::update]
+fn mark_patient_reviewed(id: u64, expected_version: u64) -> Result<Patient, Error> {
+    observe("mark_patient_reviewed", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        let mut updated_patient = get_patient(id)?;
+        ensure_version(expected_version, Record::Patient(updated_patient.clone()))?;
+
+        updated_patient.review_notes.clear();
+        save_patient(&mut updated_patient);
+
+        Ok(project(updated_patient))
+    })
+}
+
+//Replaces the list of ethnicity codes that patients can be registered with
+#[ic_cdk::update]
+fn set_ethnicity_codes(codes: Vec<EthnicityCode>) -> Result<(), Error> {
+    observe("set_ethnicity_codes", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        //Validation Logic
+        let mut validator = Validator::default();
+        for (index, ethnicity) in codes.iter().enumerate() {
+            validator
+                .limit(
+                    &format!("codes[{}].code", index),
+                    &ethnicity.code,
+                    MAX_CODE_LENGTH,
+                )
+                .limit(
+                    &format!("codes[{}].display", index),
+                    &ethnicity.display,
+                    MAX_NAME_LENGTH,
+                );
+        }
+        validator.finish()?;
+
+        ETHNICITY_CODES
+            .with(|cell| cell.borrow_mut().set(EthnicityCodes(codes)))
+            .expect("Cannot set the ethnicity codes");
+        Ok(())
+    })
+}
+
+//Retrieves the list of ethnicity codes that patients can be registered with
+#[ic_cdk::query]
+fn get_ethnicity_codes() -> Vec<EthnicityCode> {
+    ETHNICITY_CODES.with(|codes| codes.borrow().get().0.clone())
+}
+
+#[ic_cdk::init]
+fn init() {
+    migrations::mark_schema_current();
+    certification::certify_all();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    migrations::run_migrations();
+    certification::certify_all();
 }
 
 // need this to generate candid
-ic_cdk::export_candid!();
\ No newline at end of file
+ic_cdk::export_candid!();
@@ -0,0 +1,311 @@
+// Operational metrics, served at /metrics in the Prometheus text format.
+// Only update calls are counted: the IC discards the state changes made by query calls
+use crate::{
+    Error, BREAK_GLASS_ACCESS, CONSENT_STORAGE, CONTACT_STORAGE, DEAD_LETTERS, DIAGNOSIS_STORAGE,
+    DOCTOR_HISTORY, DOCTOR_STORAGE, ENCOUNTERS, ERASURES, IDEMPOTENCY_KEYS, IDENTIFIER_INDEX,
+    LAB_RESULTS, MEMORY_IDS, MEMORY_MANAGER, MERGES, PATIENT_HISTORY, PATIENT_IDENTIFIERS,
+    PATIENT_STORAGE, PORTAL_ENROLLMENTS, PORTAL_LINKS, ROOM_HISTORY, ROOM_STORAGE, STAFF,
+};
+use ic_cdk::api::{canister_balance128, instruction_counter};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::Memory as _;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+//Endpoints whose instruction counts are kept as histograms
+const HEAVY_ENDPOINTS: [&str; 11] = [
+    "add_patient",
+    "merge_patients",
+    "purge_patient",
+    "erase_patient",
+    "import_fhir_bundle",
+    "import_patients_csv",
+    "import_doctors_csv",
+    "import_rooms_csv",
+    "ingest_hl7_message",
+    "reprocess_dead_letter",
+    "batch",
+];
+
+//Upper bounds of the instruction count buckets
+const INSTRUCTION_BUCKETS: [u64; 6] = [
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+    10_000_000_000,
+];
+
+#[cfg(target_arch = "wasm32")]
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; INSTRUCTION_BUCKETS.len()], //Calls that fall in each bucket (not cumulative)
+    sum: u64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: u64) {
+        if let Some(bucket) = INSTRUCTION_BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.sum = self.sum.saturating_add(value);
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct EndpointMetrics {
+    calls: u64,
+    errors: BTreeMap<&'static str, u64>, //By Error variant
+    instructions: Histogram,
+}
+
+thread_local! {
+    //Kept on the heap, so the counts start again after an upgrade like any process restart
+    static ENDPOINTS: RefCell<BTreeMap<&'static str, EndpointMetrics>> = RefCell::default();
+
+    //How many observed calls are running, so that endpoints calling each other are counted once
+    static DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+fn error_kind(error: &Error) -> &'static str {
+    match error {
+        Error::NotFound { .. } => "NotFound",
+        Error::EmptyFields { .. } => "EmptyFields",
+        Error::AlreadyAssigned { .. } => "AlreadyAssigned",
+        Error::CanNotAssign { .. } => "CanNotAssign",
+        Error::CanNotPurge { .. } => "CanNotPurge",
+        Error::CanNotMerge { .. } => "CanNotMerge",
+        Error::ConsentRequired { .. } => "ConsentRequired",
+        Error::Unauthorized { .. } => "Unauthorized",
+        Error::Conflict { .. } => "Conflict",
+        Error::ValidationFailed { .. } => "ValidationFailed",
+        Error::ServiceUnavailable { .. } => "ServiceUnavailable",
+    }
+}
+
+//Counts a call of an endpoint and the error it returned, if any
+pub(crate) fn record_call<T>(
+    endpoint: &'static str,
+    result: &Result<T, Error>,
+    instructions: Option<u64>,
+) {
+    ENDPOINTS.with(|endpoints| {
+        let mut endpoints = endpoints.borrow_mut();
+        let metrics = endpoints.entry(endpoint).or_default();
+        metrics.calls += 1;
+        if let Err(error) = result {
+            *metrics.errors.entry(error_kind(error)).or_default() += 1;
+        }
+        if let Some(instructions) = instructions.filter(|_| HEAVY_ENDPOINTS.contains(&endpoint)) {
+            metrics.instructions.observe(instructions);
+        }
+    });
+}
+
+//Runs an update endpoint, counting the call, its errors and the instructions it used.
+//It does not refuse anything: each endpoint checks ensure_writes_allowed itself
+pub(crate) fn observe<T>(
+    endpoint: &'static str,
+    call: impl FnOnce() -> Result<T, Error>,
+) -> Result<T, Error> {
+    let depth = DEPTH.with(|depth| depth.replace(depth.get() + 1));
+    let started_at = instruction_counter();
+    let result = call();
+    DEPTH.with(|depth| depth.set(depth.get() - 1));
+
+    if depth == 0 {
+        record_call(
+            endpoint,
+            &result,
+            Some(instruction_counter().saturating_sub(started_at)),
+        );
+    }
+    result
+}
+
+fn record_counts() -> Vec<(&'static str, u64)> {
+    vec![
+        ("patients", PATIENT_STORAGE.with(|s| s.borrow().len())),
+        ("doctors", DOCTOR_STORAGE.with(|s| s.borrow().len())),
+        ("rooms", ROOM_STORAGE.with(|s| s.borrow().len())),
+        ("diagnoses", DIAGNOSIS_STORAGE.with(|s| s.borrow().len())),
+        (
+            "patient_history",
+            PATIENT_HISTORY.with(|s| s.borrow().len()),
+        ),
+        ("doctor_history", DOCTOR_HISTORY.with(|s| s.borrow().len())),
+        ("room_history", ROOM_HISTORY.with(|s| s.borrow().len())),
+        ("contacts", CONTACT_STORAGE.with(|s| s.borrow().len())),
+        (
+            "identifier_index",
+            IDENTIFIER_INDEX.with(|s| s.borrow().len()),
+        ),
+        (
+            "patient_identifiers",
+            PATIENT_IDENTIFIERS.with(|s| s.borrow().len()),
+        ),
+        ("merges", MERGES.with(|s| s.borrow().len())),
+        ("consents", CONSENT_STORAGE.with(|s| s.borrow().len())),
+        ("staff", STAFF.with(|s| s.borrow().len())),
+        (
+            "break_glass_access",
+            BREAK_GLASS_ACCESS.with(|s| s.borrow().len()),
+        ),
+        (
+            "portal_enrollments",
+            PORTAL_ENROLLMENTS.with(|s| s.borrow().len()),
+        ),
+        ("portal_links", PORTAL_LINKS.with(|s| s.borrow().len())),
+        ("erasures", ERASURES.with(|s| s.borrow().len())),
+        ("encounters", ENCOUNTERS.with(|s| s.borrow().len())),
+        ("lab_results", LAB_RESULTS.with(|s| s.borrow().len())),
+        ("dead_letters", DEAD_LETTERS.with(|s| s.borrow().len())),
+        (
+            "idempotency_keys",
+            IDEMPOTENCY_KEYS.with(|s| s.borrow().len()),
+        ),
+    ]
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_size() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_size() -> u64 {
+    0
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+//Renders every metric in the Prometheus text exposition format
+pub(crate) fn render() -> String {
+    let mut text = String::new();
+
+    header(
+        &mut text,
+        "hms_records",
+        "gauge",
+        "Records in each stable map",
+    );
+    for (map, count) in record_counts() {
+        let _ = writeln!(text, "hms_records{{map=\"{}\"}} {}", map, count);
+    }
+
+    header(
+        &mut text,
+        "hms_stable_memory_pages",
+        "gauge",
+        "Stable memory pages (64 KiB) used by each MemoryId",
+    );
+    MEMORY_MANAGER.with(|manager| {
+        let manager = manager.borrow();
+        for id in MEMORY_IDS {
+            let pages = manager.get(MemoryId::new(*id)).size();
+            let _ = writeln!(
+                text,
+                "hms_stable_memory_pages{{memory_id=\"{}\"}} {}",
+                id, pages
+            );
+        }
+    });
+
+    header(
+        &mut text,
+        "hms_heap_memory_bytes",
+        "gauge",
+        "Size of the heap (Wasm memory)",
+    );
+    let _ = writeln!(text, "hms_heap_memory_bytes {}", heap_size());
+
+    header(
+        &mut text,
+        "hms_cycles_balance",
+        "gauge",
+        "Cycles held by the canister",
+    );
+    let _ = writeln!(text, "hms_cycles_balance {}", canister_balance128());
+
+    ENDPOINTS.with(|endpoints| {
+        let endpoints = endpoints.borrow();
+
+        header(
+            &mut text,
+            "hms_calls_total",
+            "counter",
+            "Update calls of each endpoint since the last upgrade",
+        );
+        for (endpoint, metrics) in endpoints.iter() {
+            let _ = writeln!(
+                text,
+                "hms_calls_total{{endpoint=\"{}\"}} {}",
+                endpoint, metrics.calls
+            );
+        }
+
+        header(
+            &mut text,
+            "hms_errors_total",
+            "counter",
+            "Errors returned by each endpoint since the last upgrade, by Error variant",
+        );
+        for (endpoint, metrics) in endpoints.iter() {
+            for (error, count) in &metrics.errors {
+                let _ = writeln!(
+                    text,
+                    "hms_errors_total{{endpoint=\"{}\",error=\"{}\"}} {}",
+                    endpoint, error, count
+                );
+            }
+        }
+
+        header(
+            &mut text,
+            "hms_instructions",
+            "histogram",
+            "Instructions used by the calls of the heavy endpoints",
+        );
+        for (endpoint, metrics) in endpoints.iter() {
+            let histogram = &metrics.instructions;
+            if histogram.count == 0 {
+                continue;
+            }
+            let mut cumulative = 0;
+            for (bound, count) in INSTRUCTION_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    text,
+                    "hms_instructions_bucket{{endpoint=\"{}\",le=\"{}\"}} {}",
+                    endpoint, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                text,
+                "hms_instructions_bucket{{endpoint=\"{}\",le=\"+Inf\"}} {}",
+                endpoint, histogram.count
+            );
+            let _ = writeln!(
+                text,
+                "hms_instructions_sum{{endpoint=\"{}\"}} {}",
+                endpoint, histogram.sum
+            );
+            let _ = writeln!(
+                text,
+                "hms_instructions_count{{endpoint=\"{}\"}} {}",
+                endpoint, histogram.count
+            );
+        }
+    });
+
+    text
+}
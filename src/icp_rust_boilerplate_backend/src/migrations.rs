@@ -0,0 +1,591 @@
+// Upgrades the data in stable memory when the shape of a stored record changes
+use crate::contacts::{RelatedPerson, Relationship};
+use crate::identifiers::assign_mrn;
+use crate::validation::cap_review_notes;
+use crate::{
+    next_id, Address, AdministrativeGender, Archive, Diagnosis, Doctor, History, Memory, Patient,
+    Room, CONTACT_ID_COUNTER, CONTACT_STORAGE, ETHNICITY_CODES, MEMORY_MANAGER, PATIENT_STORAGE,
+    SCHEMA_VERSION,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+
+//Bump this and add a step to run_migrations whenever stored records change shape
+pub(crate) const CURRENT_SCHEMA_VERSION: u32 = 3;
+
+const PATIENT_MEMORY_ID: u8 = 1;
+const DOCTOR_MEMORY_ID: u8 = 2;
+const ROOM_MEMORY_ID: u8 = 3;
+const DIAGNOSIS_MEMORY_ID: u8 = 4;
+const PATIENT_HISTORY_MEMORY_ID: u8 = 6;
+const DOCTOR_HISTORY_MEMORY_ID: u8 = 7;
+const ROOM_HISTORY_MEMORY_ID: u8 = 8;
+
+//Brings the stable data up to the current schema version
+pub(crate) fn run_migrations() {
+    let schema_version = SCHEMA_VERSION.with(|version| *version.borrow().get());
+
+    // The first release kept every kind of record in MemoryId 1, and the steps below expect
+    // only patients there
+    if schema_version < 1 {
+        split_shared_storage();
+    }
+    // This reads fields that every older patient layout still stores, so it has to run
+    // before the demographics rewrite below drops them
+    if schema_version < 2 {
+        migrate_next_of_kin_to_contacts();
+    }
+    if schema_version < 1 {
+        migrate_patient_demographics();
+        migrate_record_versions();
+    }
+    if schema_version < 3 {
+        assign_missing_mrns();
+    }
+
+    mark_schema_current();
+}
+
+pub(crate) fn mark_schema_current() {
+    SCHEMA_VERSION
+        .with(|version| version.borrow_mut().set(CURRENT_SCHEMA_VERSION))
+        .expect("Cannot set the schema version");
+}
+
+fn memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
+
+//A stored record read as its Candid bytes, before its shape is known
+struct RawRecord(Vec<u8>);
+
+impl Storable for RawRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        RawRecord(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for RawRecord {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Fields that only doctors, rooms and diagnoses have, to tell them apart from patients
+#[derive(candid::CandidType, Deserialize)]
+struct DoctorShape {
+    speciality: String,
+    current_patient: u64,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct RoomShape {
+    location: String,
+    current_doctor_id: u64,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct DiagnosisShape {
+    treatment: String,
+    medication: String,
+}
+
+//The MemoryId a record from the shared map belongs in, going by the fields it has
+fn memory_for(record: &RawRecord) -> u8 {
+    if Decode!(&record.0, DoctorShape).is_ok() {
+        DOCTOR_MEMORY_ID
+    } else if Decode!(&record.0, RoomShape).is_ok() {
+        ROOM_MEMORY_ID
+    } else if Decode!(&record.0, DiagnosisShape).is_ok() {
+        DIAGNOSIS_MEMORY_ID
+    } else {
+        PATIENT_MEMORY_ID
+    }
+}
+
+//Moves the doctors, rooms and diagnoses the first release stored with the patients into their
+//own maps. Their IDs came from one shared counter, so they never collide. The records keep their
+//old shape, which the later steps convert
+fn split_shared_storage() {
+    let records: Vec<(u64, RawRecord)> =
+        StableBTreeMap::<u64, RawRecord, Memory>::init(memory(PATIENT_MEMORY_ID))
+            .iter()
+            .collect();
+    let (patients, others): (Vec<_>, Vec<_>) = records
+        .into_iter()
+        .map(|(id, record)| (memory_for(&record), id, record))
+        .partition(|(memory_id, _, _)| *memory_id == PATIENT_MEMORY_ID);
+    if others.is_empty() {
+        return;
+    }
+
+    for (memory_id, id, record) in others {
+        StableBTreeMap::<u64, RawRecord, Memory>::init(memory(memory_id)).insert(id, record);
+    }
+    let mut storage = StableBTreeMap::<u64, RawRecord, Memory>::new(memory(PATIENT_MEMORY_ID));
+    for (_, id, record) in patients {
+        storage.insert(id, record);
+    }
+}
+
+//Patient as stored before gender, ethnicity and address became typed (schema version 0).
+//The first releases did not store `archived` or `version` yet
+#[derive(CandidType, Deserialize)]
+struct LegacyPatient {
+    id: u64,
+    name: String,
+    date_of_birth: String,
+    age: u32,
+    gender: String,
+    ethncity: String,
+    address: String,
+    phone_number: String,
+    email: String,
+    registered_on: u64,
+    archived: Option<Archive>,
+    version: Option<u64>, //Missing on records saved before versions existed
+}
+
+impl From<LegacyPatient> for Patient {
+    fn from(legacy: LegacyPatient) -> Self {
+        let mut review_notes = Vec::new();
+
+        let mut patient = Patient {
+            id: legacy.id,
+            name: legacy.name,
+            date_of_birth: legacy.date_of_birth,
+            age: legacy.age,
+            gender: map_gender(&legacy.gender, &mut review_notes),
+            ethnicity: map_ethnicity(&legacy.ethncity, &mut review_notes),
+            address: map_address(&legacy.address, &mut review_notes),
+            phone_number: legacy.phone_number,
+            email: legacy.email,
+            registered_on: legacy.registered_on,
+            mrn: None, // Assigned by a later migration step
+            merged_into: None,
+            archived: legacy.archived,
+            version: legacy.version.unwrap_or_default(),
+            review_notes,
+        };
+        cap_review_notes(&mut patient.review_notes);
+        patient
+    }
+}
+
+fn map_gender(text: &str, review_notes: &mut Vec<String>) -> AdministrativeGender {
+    match text.trim().to_lowercase().as_str() {
+        "m" | "male" | "man" => AdministrativeGender::Male,
+        "f" | "female" | "woman" => AdministrativeGender::Female,
+        "o" | "other" | "non-binary" | "nonbinary" | "intersex" => AdministrativeGender::Other,
+        "u" | "unknown" => AdministrativeGender::Unknown,
+        _ => {
+            review_notes.push(format!("gender: could not map '{}'", text));
+            AdministrativeGender::Unknown
+        }
+    }
+}
+
+//Matches the free text against the code or display name of a configured ethnicity code
+pub(crate) fn map_ethnicity(text: &str, review_notes: &mut Vec<String>) -> String {
+    let text = text.trim();
+    let code = ETHNICITY_CODES.with(|codes| {
+        codes
+            .borrow()
+            .get()
+            .0
+            .iter()
+            .find(|ethnicity| {
+                ethnicity.code.eq_ignore_ascii_case(text)
+                    || ethnicity.display.eq_ignore_ascii_case(text)
+            })
+            .map(|ethnicity| ethnicity.code.clone())
+    });
+
+    code.unwrap_or_else(|| {
+        review_notes.push(format!("ethnicity: could not map '{}'", text));
+        String::new()
+    })
+}
+
+//Splits a comma separated address that ends with a two letter country code
+fn map_address(text: &str, review_notes: &mut Vec<String>) -> Address {
+    let parts: Vec<String> = text
+        .split(',')
+        .map(|part| part.trim().to_string())
+        .collect();
+    let is_country =
+        |part: &String| part.len() == 2 && part.chars().all(|c| c.is_ascii_alphabetic());
+
+    match parts.as_slice() {
+        [street, city, country] if is_country(country) => Address {
+            street: street.clone(),
+            city: city.clone(),
+            country: country.to_uppercase(),
+            ..Default::default()
+        },
+        [street, city, region, country] if is_country(country) => Address {
+            street: street.clone(),
+            city: city.clone(),
+            region: region.clone(),
+            postal_code: String::new(),
+            country: country.to_uppercase(),
+        },
+        [street, city, region, postal_code, country] if is_country(country) => Address {
+            street: street.clone(),
+            city: city.clone(),
+            region: region.clone(),
+            postal_code: postal_code.clone(),
+            country: country.to_uppercase(),
+        },
+        _ => {
+            review_notes.push(format!(
+                "address: could not split '{}' into its parts",
+                text
+            ));
+            Address {
+                street: text.trim().to_string(),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+//Rewrites every patient and patient revision with typed demographics
+fn migrate_patient_demographics() {
+    convert_map::<LegacyPatient, _>(PATIENT_MEMORY_ID, Patient::from);
+    convert_history::<LegacyPatient, _>(PATIENT_HISTORY_MEMORY_ID, Patient::from);
+}
+
+//The single next of kin that patients stored before contacts existed (schema version 1)
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegacyNextOfKin {
+    next_of_kin: String,
+    kins_phone_number: String,
+}
+
+impl Storable for LegacyNextOfKin {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LegacyNextOfKin {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Moves each patient's next of kin into their list of contacts
+fn migrate_next_of_kin_to_contacts() {
+    let next_of_kin: Vec<(u64, LegacyNextOfKin)> =
+        StableBTreeMap::<u64, LegacyNextOfKin, Memory>::init(memory(PATIENT_MEMORY_ID))
+            .iter()
+            .filter(|(_, legacy)| !legacy.next_of_kin.is_empty())
+            .collect();
+
+    for (patient_id, legacy) in next_of_kin {
+        let id = next_id(&CONTACT_ID_COUNTER);
+
+        let contact = RelatedPerson {
+            id,
+            patient_id,
+            name: legacy.next_of_kin,
+            relationship: Relationship::NextOfKin,
+            phone_number: legacy.kins_phone_number,
+            email: String::new(),
+            priority: 1,
+            has_medical_consent: false, // Consent was never recorded, so it has to be given again
+        };
+        CONTACT_STORAGE.with(|storage| storage.borrow_mut().insert((patient_id, id), contact));
+    }
+}
+
+//Gives every patient registered before MRNs existed their Medical Record Number
+fn assign_missing_mrns() {
+    let patients: Vec<Patient> = PATIENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, patient)| patient)
+            .filter(|patient| patient.mrn.is_none())
+            .collect()
+    });
+
+    // Stored without a new revision, as the MRN is not an edit of the patient
+    for mut patient in patients {
+        patient.mrn = Some(assign_mrn(patient.id));
+        PATIENT_STORAGE.with(|storage| storage.borrow_mut().insert(patient.id, patient));
+    }
+}
+
+//Rewrites every record of a map, converting it from its legacy shape
+fn convert_map<L, T>(memory_id: u8, convert: impl Fn(L) -> T)
+where
+    L: CandidType + DeserializeOwned,
+    T: Storable + BoundedStorable,
+{
+    let records: Vec<(u64, T)> = StableBTreeMap::<u64, RawRecord, Memory>::init(memory(memory_id))
+        .iter()
+        .map(|(id, record)| {
+            let legacy = Decode!(&record.0, L).expect("Cannot decode a legacy record");
+            (id, convert(legacy))
+        })
+        .collect();
+
+    let mut storage = StableBTreeMap::<u64, T, Memory>::new(memory(memory_id));
+    for (id, record) in records {
+        storage.insert(id, record);
+    }
+}
+
+//Rewrites every revision of a history, converting the records from their legacy shape
+fn convert_history<L, T>(memory_id: u8, convert: impl Fn(L) -> T)
+where
+    L: CandidType + DeserializeOwned,
+    T: CandidType + DeserializeOwned,
+{
+    let revisions: Vec<_> = History::<L>::init(memory(memory_id))
+        .iter()
+        .map(|(key, revision)| (key, revision.map(&convert)))
+        .collect();
+
+    let mut history = History::<T>::new(memory(memory_id));
+    for (key, revision) in revisions {
+        history.insert(key, revision);
+    }
+}
+
+//Doctor as stored before it was archived instead of deleted, or before it had a version
+#[derive(CandidType, Deserialize)]
+struct LegacyDoctor {
+    id: u64,
+    name: String,
+    email: String,
+    phone_number: String,
+    speciality: String,
+    current_patient: u64,
+    archived: Option<Archive>,
+    version: Option<u64>,
+}
+
+impl From<LegacyDoctor> for Doctor {
+    fn from(legacy: LegacyDoctor) -> Self {
+        Doctor {
+            id: legacy.id,
+            name: legacy.name,
+            email: legacy.email,
+            phone_number: legacy.phone_number,
+            speciality: legacy.speciality,
+            current_patient: legacy.current_patient,
+            archived: legacy.archived,
+            version: legacy.version.unwrap_or_default(),
+        }
+    }
+}
+
+//Room as stored before it was archived instead of deleted, or before it had a version
+#[derive(CandidType, Deserialize)]
+struct LegacyRoom {
+    id: u64,
+    name: String,
+    location: String,
+    current_doctor_id: u64,
+    equipment: Vec<String>,
+    archived: Option<Archive>,
+    version: Option<u64>,
+}
+
+impl From<LegacyRoom> for Room {
+    fn from(legacy: LegacyRoom) -> Self {
+        Room {
+            id: legacy.id,
+            name: legacy.name,
+            location: legacy.location,
+            current_doctor_id: legacy.current_doctor_id,
+            equipment: legacy.equipment,
+            archived: legacy.archived,
+            version: legacy.version.unwrap_or_default(),
+        }
+    }
+}
+
+//Diagnosis as stored before it had a version
+#[derive(CandidType, Deserialize)]
+struct LegacyDiagnosis {
+    id: u64,
+    doctor_id: u64,
+    patient_id: u64,
+    treatment: String,
+    medication: String,
+    version: Option<u64>,
+}
+
+impl From<LegacyDiagnosis> for Diagnosis {
+    fn from(legacy: LegacyDiagnosis) -> Self {
+        Diagnosis {
+            id: legacy.id,
+            doctor_id: legacy.doctor_id,
+            patient_id: legacy.patient_id,
+            treatment: legacy.treatment,
+            medication: legacy.medication,
+            version: legacy.version.unwrap_or_default(),
+        }
+    }
+}
+
+//Gives every doctor, room and diagnosis saved before versions existed version 0, the version
+//clients have to send to update them
+fn migrate_record_versions() {
+    convert_map::<LegacyDoctor, _>(DOCTOR_MEMORY_ID, Doctor::from);
+    convert_map::<LegacyRoom, _>(ROOM_MEMORY_ID, Room::from);
+    convert_map::<LegacyDiagnosis, _>(DIAGNOSIS_MEMORY_ID, Diagnosis::from);
+    convert_history::<LegacyDoctor, _>(DOCTOR_HISTORY_MEMORY_ID, Doctor::from);
+    convert_history::<LegacyRoom, _>(ROOM_HISTORY_MEMORY_ID, Room::from);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DIAGNOSIS_STORAGE, DOCTOR_STORAGE, ROOM_STORAGE};
+
+    // The records as the first release stored them, all in MemoryId 1
+
+    #[derive(CandidType, Default)]
+    struct FirstPatient {
+        id: u64,
+        name: String,
+        date_of_birth: String,
+        age: u32,
+        gender: String,
+        ethncity: String,
+        address: String,
+        phone_number: String,
+        email: String,
+        next_of_kin: String,
+        kins_phone_number: String,
+        registered_on: u64,
+    }
+
+    #[derive(CandidType, Default)]
+    struct FirstDoctor {
+        id: u64,
+        name: String,
+        email: String,
+        phone_number: String,
+        speciality: String,
+        current_patient: u64,
+    }
+
+    #[derive(CandidType, Default)]
+    struct FirstRoom {
+        id: u64,
+        name: String,
+        location: String,
+        current_doctor_id: u64,
+        equipment: Vec<String>,
+    }
+
+    #[derive(CandidType, Default)]
+    struct FirstDiagnosis {
+        id: u64,
+        doctor_id: u64,
+        patient_id: u64,
+        treatment: String,
+        medication: String,
+    }
+
+    fn raw(record: &impl CandidType) -> RawRecord {
+        RawRecord(Encode!(record).unwrap())
+    }
+
+    #[test]
+    fn migrates_the_records_of_the_first_release() {
+        let mut shared = StableBTreeMap::<u64, RawRecord, Memory>::init(memory(PATIENT_MEMORY_ID));
+        shared.insert(
+            1,
+            raw(&FirstPatient {
+                id: 1,
+                name: "Jane Doe".to_string(),
+                gender: "F".to_string(),
+                ethncity: "African".to_string(),
+                address: "1 Main St, Nairobi, KE".to_string(),
+                next_of_kin: "John Doe".to_string(),
+                kins_phone_number: "+254700000000".to_string(),
+                ..Default::default()
+            }),
+        );
+        shared.insert(
+            2,
+            raw(&FirstDoctor {
+                id: 2,
+                speciality: "Cardiology".to_string(),
+                ..Default::default()
+            }),
+        );
+        shared.insert(
+            3,
+            raw(&FirstRoom {
+                id: 3,
+                location: "Ward 3".to_string(),
+                ..Default::default()
+            }),
+        );
+        shared.insert(
+            4,
+            raw(&FirstDiagnosis {
+                id: 4,
+                doctor_id: 2,
+                patient_id: 1,
+                ..Default::default()
+            }),
+        );
+        drop(shared);
+
+        // The steps run_migrations takes for schema version 0, but the MRNs, which need ic0
+        split_shared_storage();
+        migrate_next_of_kin_to_contacts();
+        migrate_patient_demographics();
+        migrate_record_versions();
+
+        let patients: Vec<Patient> =
+            PATIENT_STORAGE.with(|storage| storage.borrow().iter().map(|(_, p)| p).collect());
+        assert_eq!(patients.len(), 1);
+        let patient = &patients[0];
+        assert!(matches!(patient.gender, AdministrativeGender::Female));
+        assert_eq!(patient.ethnicity, "african");
+        assert_eq!(patient.address.country, "KE");
+        assert_eq!(patient.version, 0);
+        assert!(patient.archived.is_none() && patient.review_notes.is_empty());
+
+        let doctor = DOCTOR_STORAGE
+            .with(|storage| storage.borrow().get(&2))
+            .unwrap();
+        assert_eq!(
+            (doctor.speciality.as_str(), doctor.version),
+            ("Cardiology", 0)
+        );
+        let room = ROOM_STORAGE
+            .with(|storage| storage.borrow().get(&3))
+            .unwrap();
+        assert_eq!(room.location, "Ward 3");
+        let diagnosis = DIAGNOSIS_STORAGE
+            .with(|storage| storage.borrow().get(&4))
+            .unwrap();
+        assert_eq!((diagnosis.patient_id, diagnosis.doctor_id), (1, 2));
+
+        let contacts: Vec<RelatedPerson> =
+            CONTACT_STORAGE.with(|storage| storage.borrow().iter().map(|(_, c)| c).collect());
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].name, "John Doe");
+        assert_eq!(contacts[0].patient_id, 1);
+    }
+}
@@ -0,0 +1,121 @@
+// Operating mode: lets admins freeze writes during migrations and restores while queries keep working
+use crate::snapshot::ensure_no_snapshot;
+use crate::validation::{check_required, Validator};
+use crate::{ensure_admin, Error, OPERATING_MODE};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, is_controller, time};
+use ic_stable_structures::Storable;
+use std::borrow::Cow;
+
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq)]
+pub(crate) enum OperatingMode {
+    #[default]
+    Normal,
+    ReadOnly,    //Nobody may write
+    Maintenance, //Only admins may write, e.g. to repair data
+}
+
+//Represents payload for changing the operating mode
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct OperatingModePayload {
+    mode: OperatingMode,
+    reason: String,            //Shown to callers whose writes are refused
+    expected_end: Option<u64>, //When normal operation is expected to resume
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct OperatingModeSetting {
+    mode: OperatingMode,
+    reason: String,
+    expected_end: Option<u64>,
+    set_by: Principal,
+    set_on: u64,
+}
+
+impl Default for OperatingModeSetting {
+    fn default() -> Self {
+        OperatingModeSetting {
+            mode: OperatingMode::Normal,
+            reason: String::new(),
+            expected_end: None,
+            set_by: Principal::anonymous(),
+            set_on: 0,
+        }
+    }
+}
+
+impl Storable for OperatingModeSetting {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+fn current_setting() -> OperatingModeSetting {
+    OPERATING_MODE.with(|setting| setting.borrow().get().clone())
+}
+
+//Refuses writes while the canister is read-only, in maintenance or taking or restoring a snapshot.
+//Every update endpoint checks this, so queries keep working
+pub(crate) fn ensure_writes_allowed() -> Result<(), Error> {
+    ensure_no_snapshot()?;
+
+    let setting = current_setting();
+    let refused = match setting.mode {
+        OperatingMode::Normal => false,
+        OperatingMode::ReadOnly => true,
+        OperatingMode::Maintenance => !is_controller(&caller()),
+    };
+    if !refused {
+        return Ok(());
+    }
+
+    let mode = match setting.mode {
+        OperatingMode::Maintenance => "in maintenance",
+        _ => "read-only",
+    };
+    Err(Error::ServiceUnavailable {
+        msg: format!("The canister is {}: {}", mode, setting.reason),
+        expected_end: setting.expected_end,
+    })
+}
+
+//Switches the canister between normal operation, read-only and maintenance
+#[ic_cdk::update]
+fn set_operating_mode(payload: OperatingModePayload) -> Result<OperatingModeSetting, Error> {
+    ensure_admin()?;
+    let mut validator = Validator::default();
+    if payload.mode != OperatingMode::Normal {
+        validator.check("reason", check_required(&payload.reason));
+    }
+    validator
+        .check_provided("expected_end", payload.expected_end.as_ref(), |end| {
+            if *end > time() {
+                Ok(())
+            } else {
+                Err("The expected end must be in the future".to_string())
+            }
+        })
+        .finish()?;
+
+    let setting = OperatingModeSetting {
+        mode: payload.mode,
+        reason: payload.reason.trim().to_string(),
+        expected_end: payload.expected_end,
+        set_by: caller(),
+        set_on: time(),
+    };
+    OPERATING_MODE
+        .with(|current| current.borrow_mut().set(setting.clone()))
+        .expect("Cannot save the operating mode");
+    Ok(setting)
+}
+
+//Shows the operating mode, so that clients can tell when writes will be accepted again
+#[ic_cdk::query]
+fn get_operating_mode() -> OperatingModeSetting {
+    current_setting()
+}
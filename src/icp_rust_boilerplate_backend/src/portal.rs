@@ -0,0 +1,253 @@
+// Endpoints patients use themselves, after linking their Internet Identity to their record
+use crate::access::{ensure_receptionist, PrincipalKey};
+use crate::encounters::{encounters_of, Encounter};
+use crate::metrics::{observe, record_call};
+use crate::operating_mode::ensure_writes_allowed;
+use crate::redaction::{project_for, Audience};
+use crate::validation::{invalid_field, validate_patient_patch};
+use crate::{
+    ensure_version, get_patient, save_patient, Address, Diagnosis, Error, Patient, PatientPatch,
+    Record, DIAGNOSIS_STORAGE, PORTAL_ENROLLMENTS, PORTAL_LINKS,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//How long an enrollment code can be used (24 hours, in nanoseconds)
+const ENROLLMENT_CODE_LIFETIME: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+//Enrollment codes use letters and digits that are hard to mix up when read out
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 10;
+
+//A code issued by reception that lets a patient link their principal to their record
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Enrollment {
+    code: String,
+    patient_id: u64,
+    issued_by: Principal,
+    issued_on: u64,
+    expires_on: u64,
+}
+
+impl Storable for Enrollment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Enrollment {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Represents the contact details a patient may change themselves
+#[derive(CandidType, Serialize, Deserialize, Default)]
+pub(crate) struct ContactDetailsPatch {
+    phone_number: Option<String>,
+    email: Option<String>,
+    address: Option<Address>,
+}
+
+//Unlinks every principal enrolled as the patient and cancels their enrollment code
+pub(crate) fn remove_portal_access(patient_id: u64) {
+    let principals: Vec<PrincipalKey> = PORTAL_LINKS.with(|links| {
+        links
+            .borrow()
+            .iter()
+            .filter(|(_, linked_patient_id)| *linked_patient_id == patient_id)
+            .map(|(principal, _)| principal)
+            .collect()
+    });
+    PORTAL_LINKS.with(|links| {
+        let mut links = links.borrow_mut();
+        for principal in &principals {
+            links.remove(principal);
+        }
+    });
+    PORTAL_ENROLLMENTS.with(|enrollments| enrollments.borrow_mut().remove(&patient_id));
+}
+
+//The patient linked to the caller
+fn my_patient() -> Result<Patient, Error> {
+    let patient_id = PORTAL_LINKS
+        .with(|links| links.borrow().get(&PrincipalKey(caller())))
+        .ok_or(Error::Unauthorized {
+            msg: "Enroll with the code from reception to use the patient portal".to_string(),
+        })?;
+    get_patient(patient_id)
+}
+
+//Issues a new enrollment code for a patient, replacing any code issued before
+#[ic_cdk::update]
+async fn issue_enrollment_code(patient_id: u64) -> Result<Enrollment, Error> {
+    //The instructions are not measured, as the counter starts again after the await
+    let result = issue_code(patient_id).await;
+    record_call("issue_enrollment_code", &result, None);
+    result
+}
+
+async fn issue_code(patient_id: u64) -> Result<Enrollment, Error> {
+    ensure_writes_allowed()?;
+    ensure_receptionist()?;
+    let patient = get_patient(patient_id)?;
+
+    let (random_bytes,) = raw_rand()
+        .await
+        .expect("Cannot generate an enrollment code");
+
+    //Writes may have been paused, or the patient archived or merged, while waiting
+    ensure_writes_allowed()?;
+    let patient = get_patient(patient.id)?;
+    let code: String = random_bytes
+        .iter()
+        .take(CODE_LENGTH)
+        .map(|byte| CODE_ALPHABET[*byte as usize % CODE_ALPHABET.len()] as char)
+        .collect();
+
+    let issued_on = time();
+    let enrollment = Enrollment {
+        code,
+        patient_id: patient.id,
+        issued_by: caller(),
+        issued_on,
+        expires_on: issued_on.saturating_add(ENROLLMENT_CODE_LIFETIME),
+    };
+    PORTAL_ENROLLMENTS.with(|enrollments| {
+        enrollments
+            .borrow_mut()
+            .insert(patient.id, enrollment.clone())
+    });
+    Ok(enrollment)
+}
+
+//Links the caller to the patient the enrollment code was issued for
+#[ic_cdk::update]
+fn enroll(code: String) -> Result<Patient, Error> {
+    observe("enroll", || {
+        ensure_writes_allowed()?;
+
+        let caller = caller();
+        if caller == Principal::anonymous() {
+            return Err(Error::Unauthorized {
+                msg: "Log in with Internet Identity to enroll".to_string(),
+            });
+        }
+        if PORTAL_LINKS.with(|links| links.borrow().contains_key(&PrincipalKey(caller))) {
+            return Err(Error::AlreadyAssigned {
+                msg: "You are already enrolled in the patient portal".to_string(),
+            });
+        }
+
+        let now = time();
+        let code = code.trim().to_uppercase();
+        let enrollment = PORTAL_ENROLLMENTS
+            .with(|enrollments| {
+                enrollments
+                    .borrow()
+                    .iter()
+                    .map(|(_, enrollment)| enrollment)
+                    .find(|enrollment| enrollment.code == code && now < enrollment.expires_on)
+            })
+            .ok_or_else(|| {
+                invalid_field("code", "This enrollment code is not valid or has expired")
+            })?;
+
+        // A code can only be used once
+        PORTAL_ENROLLMENTS
+            .with(|enrollments| enrollments.borrow_mut().remove(&enrollment.patient_id));
+        PORTAL_LINKS.with(|links| {
+            links
+                .borrow_mut()
+                .insert(PrincipalKey(caller), enrollment.patient_id)
+        });
+
+        my_patient().map(|patient| project_for(Audience::Patient, patient))
+    })
+}
+
+//Unlinks the caller from their patient record
+#[ic_cdk::update]
+fn unenroll() -> Result<(), Error> {
+    observe("unenroll", || {
+        ensure_writes_allowed()?;
+
+        PORTAL_LINKS
+            .with(|links| links.borrow_mut().remove(&PrincipalKey(caller())))
+            .map(|_| ())
+            .ok_or(Error::NotFound {
+                msg: "You are not enrolled in the patient portal".to_string(),
+            })
+    })
+}
+
+//Retrieves the caller's own patient record
+#[ic_cdk::query]
+fn get_my_record() -> Result<Patient, Error> {
+    my_patient().map(|patient| project_for(Audience::Patient, patient))
+}
+
+//Retrieves the caller's diagnoses, with the medication prescribed for each
+#[ic_cdk::query]
+fn get_my_diagnoses() -> Result<Vec<Diagnosis>, Error> {
+    let patient = my_patient()?;
+
+    Ok(DIAGNOSIS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, diagnosis)| diagnosis.patient_id == patient.id)
+            .map(|(_, diagnosis)| project_for(Audience::Patient, diagnosis))
+            .collect()
+    }))
+}
+
+//Retrieves the caller's hospital visits, oldest first
+#[ic_cdk::query]
+fn get_my_encounters() -> Result<Vec<Encounter>, Error> {
+    let patient = my_patient()?;
+
+    Ok(encounters_of(patient.id))
+}
+
+//Updates the caller's phone number, email and address
+#[ic_cdk::update]
+fn update_my_contact_details(
+    expected_version: u64,
+    details: ContactDetailsPatch,
+) -> Result<Patient, Error> {
+    observe("update_my_contact_details", || {
+        ensure_writes_allowed()?;
+
+        let patch = PatientPatch {
+            phone_number: details.phone_number,
+            email: details.email,
+            address: details.address,
+            ..Default::default()
+        };
+        //Validation Logic
+        validate_patient_patch(&patch)?;
+
+        let mut patient = my_patient()?;
+        ensure_version(expected_version, Record::Patient(patient.clone()))?;
+
+        if let Some(phone_number) = patch.phone_number {
+            patient.phone_number = phone_number;
+        }
+        if let Some(email) = patch.email {
+            patient.email = email;
+        }
+        if let Some(address) = patch.address {
+            patient.address = address;
+        }
+        save_patient(&mut patient);
+
+        Ok(project_for(Audience::Patient, patient))
+    })
+}
@@ -0,0 +1,291 @@
+// Data subject requests: exporting everything held about a patient, and erasing their personal data
+use crate::break_glass::{accesses_to, BreakGlassAccess};
+use crate::consents::{consents_of, Consent};
+use crate::contacts::{contacts_of, remove_contacts, RelatedPerson};
+use crate::duplicates::{forget_merged_identifiers, merges_of, Merge, PatientRegistration};
+use crate::encounters::{encounters_of, lab_results_of, Encounter, LabResult};
+use crate::history::{revisions_of, rewrite_history, Revision};
+use crate::hl7::remove_dead_letters_about;
+use crate::idempotency::forget_results;
+use crate::identifiers::{
+    identifiers_of, remove_external_identifiers, remove_identifiers, ExternalIdentifier,
+    IdentifierKey,
+};
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::portal::remove_portal_access;
+use crate::validation::{check_required, Validator};
+use crate::{
+    ensure_admin, get_patient, next_id, save_patient, Address, AdministrativeGender, Diagnosis,
+    Doctor, Error, Patient, DIAGNOSIS_STORAGE, DOCTOR_STORAGE, ERASURES, ERASURE_ID_COUNTER,
+    PATIENT_HISTORY, PATIENT_STORAGE,
+};
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::{caller, time};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+//Longest legal basis, in bytes, so that an erasure fits in Erasure::MAX_SIZE
+const MAX_LEGAL_BASIS_LENGTH: usize = 500;
+
+//How much of a patient's personal data an erasure removes
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum ErasureMode {
+    Pseudonymise, //Keeps the MRN, gender, ethnicity, country and year of birth for reporting
+    Erase,        //Keeps nothing that could identify the patient
+}
+
+//Represents payload for erasing a patient's personal data
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct ErasureRequest {
+    mode: ErasureMode,
+    legal_basis: String, //e.g. "GDPR Art. 17(1)(b), request received 2024-03-01"
+}
+
+//Records an erasure, so that it can be shown what was removed, when and on what grounds
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Erasure {
+    patient_id: u64,
+    mode: ErasureMode,
+    legal_basis: String,
+    erased_by: Principal,
+    erased_on: u64,
+}
+
+impl Storable for Erasure {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Erasure {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+//Everything held about a patient, as returned for a subject access request
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct SubjectAccessExport {
+    exported_by: Principal,
+    exported_on: u64,
+    patient: Patient,
+    history: Vec<Revision<Patient>>,
+    diagnoses: Vec<Diagnosis>,
+    assigned_doctors: Vec<Doctor>, //Doctors the patient is assigned to or was diagnosed by
+    encounters: Vec<Encounter>,
+    lab_results: Vec<LabResult>,
+    contacts: Vec<RelatedPerson>,
+    identifiers: Vec<ExternalIdentifier>,
+    consents: Vec<Consent>,
+    merges: Vec<Merge>,
+    emergency_accesses: Vec<BreakGlassAccess>,
+    erasures: Vec<Erasure>,
+}
+
+//The erasures of a patient, oldest first
+fn erasures_of(patient_id: u64) -> Vec<Erasure> {
+    let mut erasures: Vec<Erasure> = ERASURES.with(|erasures| {
+        erasures
+            .borrow()
+            .range((patient_id, 0)..=(patient_id, u64::MAX))
+            .map(|(_, erasure)| erasure)
+            .collect()
+    });
+    // Older erasures are keyed by their time, which sorts after every erasure number
+    erasures.sort_by_key(|erasure| erasure.erased_on);
+    erasures
+}
+
+fn diagnoses_of(patient_id: u64) -> Vec<Diagnosis> {
+    DIAGNOSIS_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, diagnosis)| diagnosis)
+            .filter(|diagnosis| diagnosis.patient_id == patient_id)
+            .collect()
+    })
+}
+
+fn doctors_of(patient_id: u64, diagnoses: &[Diagnosis]) -> Vec<Doctor> {
+    DOCTOR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, doctor)| doctor)
+            .filter(|doctor| {
+                doctor.current_patient == patient_id
+                    || diagnoses
+                        .iter()
+                        .any(|diagnosis| diagnosis.doctor_id == doctor.id)
+            })
+            .collect()
+    })
+}
+
+//Removes the personal data from a version of the patient, keeping what the mode allows
+fn scrub(patient: Patient, mode: ErasureMode) -> Patient {
+    match mode {
+        ErasureMode::Pseudonymise => {
+            let year_of_birth = patient.date_of_birth.rsplit('-').next().unwrap_or_default();
+            Patient {
+                name: "Pseudonymised patient".to_string(),
+                date_of_birth: if year_of_birth.is_empty() {
+                    String::new()
+                } else {
+                    format!("01-07-{}", year_of_birth)
+                },
+                address: Address {
+                    country: patient.address.country.clone(),
+                    ..Default::default()
+                },
+                phone_number: String::new(),
+                email: String::new(),
+                review_notes: Vec::new(),
+                ..patient
+            }
+        }
+        ErasureMode::Erase => Patient {
+            name: "Erased patient".to_string(),
+            date_of_birth: String::new(),
+            age: 0,
+            gender: AdministrativeGender::Unknown,
+            ethnicity: String::new(),
+            address: Address::default(),
+            phone_number: String::new(),
+            email: String::new(),
+            mrn: None,
+            review_notes: Vec::new(),
+            ..patient
+        },
+    }
+}
+
+//Erases one patient record, its past versions and the personal data linked to it
+fn erase(patient_id: u64, mode: ErasureMode) {
+    let Some(mut patient) = PATIENT_STORAGE.with(|storage| storage.borrow().get(&patient_id))
+    else {
+        return;
+    };
+
+    rewrite_history(&PATIENT_HISTORY, patient_id, |version| scrub(version, mode));
+    remove_contacts(patient_id);
+    remove_portal_access(patient_id);
+
+    // Messages that could not be applied are matched by the identifiers they were sent with
+    let keys: Vec<IdentifierKey> = identifiers_of(patient_id)
+        .iter()
+        .map(ExternalIdentifier::key)
+        .collect();
+    remove_dead_letters_about(&keys);
+    match mode {
+        ErasureMode::Pseudonymise => remove_external_identifiers(patient_id),
+        ErasureMode::Erase => remove_identifiers(patient_id),
+    }
+    forget_merged_identifiers(patient_id);
+
+    forget_results("add_patient", |registration: &PatientRegistration| {
+        registration.mentions(patient_id)
+    });
+    forget_results("add_diagnosis", |diagnosis: &Diagnosis| {
+        diagnosis.patient_id == patient_id
+    });
+
+    patient = scrub(patient, mode);
+    save_patient(&mut patient);
+}
+
+//Exports everything held about a patient for a subject access request.
+//This is the patient's own right, so no data sharing consent is needed
+#[ic_cdk::query]
+fn export_patient_data(patient_id: u64) -> Result<SubjectAccessExport, Error> {
+    ensure_admin()?;
+    let patient = get_patient(patient_id)?;
+
+    let diagnoses = diagnoses_of(patient.id);
+    Ok(SubjectAccessExport {
+        exported_by: caller(),
+        exported_on: time(),
+        history: revisions_of(&PATIENT_HISTORY, patient.id),
+        assigned_doctors: doctors_of(patient.id, &diagnoses),
+        diagnoses,
+        encounters: encounters_of(patient.id),
+        lab_results: lab_results_of(patient.id),
+        contacts: contacts_of(patient.id),
+        identifiers: identifiers_of(patient.id),
+        consents: consents_of(patient.id),
+        merges: merges_of(patient.id),
+        emergency_accesses: accesses_to(patient.id),
+        erasures: erasures_of(patient.id),
+        patient,
+    })
+}
+
+//Pseudonymises or erases a patient's personal data, including the duplicates merged into them,
+//their dead letters and the results remembered for idempotency keys. Clinical records, consents,
+//merges (without the identifiers they moved) and emergency access records are kept as the law
+//requires
+#[ic_cdk::update]
+fn erase_patient(patient_id: u64, request: ErasureRequest) -> Result<Patient, Error> {
+    observe("erase_patient", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+        Validator::default()
+            .check("legal_basis", check_required(&request.legal_basis))
+            .limit("legal_basis", &request.legal_basis, MAX_LEGAL_BASIS_LENGTH)
+            .finish()?;
+
+        let patient = get_patient(patient_id)?;
+        let duplicate_ids: Vec<u64> = merges_of(patient.id)
+            .iter()
+            .filter(|merge| merge.survivor_id == patient.id)
+            .map(|merge| merge.duplicate_id)
+            .collect();
+        for id in duplicate_ids.iter().chain([&patient.id]) {
+            erase(*id, request.mode);
+        }
+
+        let erasure = Erasure {
+            patient_id: patient.id,
+            mode: request.mode,
+            legal_basis: request.legal_basis.trim().to_string(),
+            erased_by: caller(),
+            erased_on: time(),
+        };
+        // Numbered, as two erasures in the same round have the same time
+        let number = next_id(&ERASURE_ID_COUNTER);
+        ERASURES.with(|erasures| erasures.borrow_mut().insert((patient.id, number), erasure));
+
+        get_patient(patient.id)
+    })
+}
+
+//Lists the erasures carried out on a patient's record
+#[ic_cdk::query]
+fn get_patient_erasures(patient_id: u64) -> Result<Vec<Erasure>, Error> {
+    ensure_admin()?;
+    let patient = get_patient(patient_id)?;
+
+    Ok(erasures_of(patient.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_erasure_with_the_longest_legal_basis_fits_when_stored() {
+        let erasure = Erasure {
+            patient_id: u64::MAX,
+            mode: ErasureMode::Pseudonymise,
+            legal_basis: "x".repeat(MAX_LEGAL_BASIS_LENGTH),
+            erased_by: Principal::from_slice(&[0xff; 29]),
+            erased_on: u64::MAX,
+        };
+        assert!(erasure.to_bytes().len() <= Erasure::MAX_SIZE as usize);
+    }
+}
@@ -0,0 +1,233 @@
+// Hides the fields of a record that the caller's role may not see, following a policy table
+use crate::access::{staff_role, PrincipalKey, StaffRole};
+use crate::contacts::RelatedPerson;
+use crate::metrics::observe;
+use crate::operating_mode::ensure_writes_allowed;
+use crate::validation::Validator;
+use crate::{ensure_admin, Diagnosis, Error, Patient, PORTAL_LINKS, REDACTION_POLICIES};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::{caller, is_controller};
+use ic_stable_structures::Storable;
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+
+//Who a record is being sent to
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum Audience {
+    Admin,
+    Clinician,
+    Receptionist,
+    BillingClerk,
+    ComplianceOfficer,
+    Patient,      //A patient reading their own record through the portal
+    Unregistered, //Neither an admin, staff nor an enrolled patient
+}
+
+//The kinds of record the policies apply to
+#[derive(CandidType, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub(crate) enum RecordKind {
+    Patient,
+    Diagnosis,
+    Contact,
+}
+
+//The fields of one kind of record that are hidden from one audience
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldPolicy {
+    audience: Audience,
+    record: RecordKind,
+    hidden_fields: Vec<String>,
+}
+
+impl FieldPolicy {
+    fn new(audience: Audience, record: RecordKind, hidden_fields: &[&str]) -> Self {
+        FieldPolicy {
+            audience,
+            record,
+            hidden_fields: hidden_fields
+                .iter()
+                .map(|field| field.to_string())
+                .collect(),
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RedactionPolicies(Vec<FieldPolicy>);
+
+impl Default for RedactionPolicies {
+    fn default() -> Self {
+        use Audience::*;
+        RedactionPolicies(vec![
+            FieldPolicy::new(Receptionist, RecordKind::Patient, &["ethnicity"]),
+            FieldPolicy::new(
+                Receptionist,
+                RecordKind::Diagnosis,
+                &["treatment", "medication"],
+            ),
+            FieldPolicy::new(
+                BillingClerk,
+                RecordKind::Patient,
+                &["ethnicity", "review_notes"],
+            ),
+            FieldPolicy::new(
+                BillingClerk,
+                RecordKind::Diagnosis,
+                &["treatment", "medication"],
+            ),
+            FieldPolicy::new(BillingClerk, RecordKind::Contact, &["has_medical_consent"]),
+            FieldPolicy::new(Patient, RecordKind::Patient, &["review_notes"]),
+            FieldPolicy::new(
+                Unregistered,
+                RecordKind::Patient,
+                &["ethnicity", "review_notes"],
+            ),
+            FieldPolicy::new(
+                Unregistered,
+                RecordKind::Diagnosis,
+                &["treatment", "medication"],
+            ),
+        ])
+    }
+}
+
+impl Storable for RedactionPolicies {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+//A record that can be projected into a role-specific view
+pub(crate) trait Redactable: serde::Serialize + DeserializeOwned + Default {
+    const KIND: RecordKind;
+}
+
+impl Redactable for Patient {
+    const KIND: RecordKind = RecordKind::Patient;
+}
+
+impl Redactable for Diagnosis {
+    const KIND: RecordKind = RecordKind::Diagnosis;
+}
+
+impl Redactable for RelatedPerson {
+    const KIND: RecordKind = RecordKind::Contact;
+}
+
+pub(crate) fn caller_audience() -> Audience {
+    let caller = caller();
+    if is_controller(&caller) {
+        return Audience::Admin;
+    }
+
+    match staff_role(caller) {
+        Some(StaffRole::Clinician { .. }) => Audience::Clinician,
+        Some(StaffRole::Receptionist) => Audience::Receptionist,
+        Some(StaffRole::BillingClerk) => Audience::BillingClerk,
+        Some(StaffRole::ComplianceOfficer) => Audience::ComplianceOfficer,
+        Some(StaffRole::Interface) => Audience::Unregistered,
+        None if PORTAL_LINKS.with(|links| links.borrow().contains_key(&PrincipalKey(caller))) => {
+            Audience::Patient
+        }
+        None => Audience::Unregistered,
+    }
+}
+
+pub(crate) fn hidden_fields(audience: Audience, record: RecordKind) -> Vec<String> {
+    REDACTION_POLICIES.with(|policies| {
+        policies
+            .borrow()
+            .get()
+            .0
+            .iter()
+            .filter(|policy| policy.audience == audience && policy.record == record)
+            .flat_map(|policy| policy.hidden_fields.clone())
+            .collect()
+    })
+}
+
+//Resets the hidden fields of the record to their default (empty) values
+pub(crate) fn project_for<T: Redactable>(audience: Audience, record: T) -> T {
+    let hidden = hidden_fields(audience, T::KIND);
+    if hidden.is_empty() {
+        return record;
+    }
+
+    let mut view = serde_json::to_value(record).expect("Cannot serialize the record");
+    let blank = serde_json::to_value(T::default()).expect("Cannot serialize an empty record");
+    if let (Some(view), Some(blank)) = (view.as_object_mut(), blank.as_object()) {
+        for field in &hidden {
+            if let Some(empty_value) = blank.get(field) {
+                view.insert(field.clone(), empty_value.clone());
+            }
+        }
+    }
+    serde_json::from_value(view).expect("A redacted record keeps its shape")
+}
+
+//Projects the record into the view of the caller's role
+pub(crate) fn project<T: Redactable>(record: T) -> T {
+    project_for(caller_audience(), record)
+}
+
+fn field_names<T: Redactable>() -> Vec<String> {
+    serde_json::to_value(T::default())
+        .expect("Cannot serialize an empty record")
+        .as_object()
+        .map(|fields| fields.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+fn check_fields(policy: &FieldPolicy) -> Result<(), String> {
+    let known = match policy.record {
+        RecordKind::Patient => field_names::<Patient>(),
+        RecordKind::Diagnosis => field_names::<Diagnosis>(),
+        RecordKind::Contact => field_names::<RelatedPerson>(),
+    };
+    let unknown: Vec<&str> = policy
+        .hidden_fields
+        .iter()
+        .filter(|field| !known.contains(field))
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!("Unknown fields: {}", unknown.join(", ")));
+    }
+    Ok(())
+}
+
+//Replaces the policy table that decides which fields each audience can see
+#[ic_cdk::update]
+fn set_redaction_policies(policies: Vec<FieldPolicy>) -> Result<(), Error> {
+    observe("set_redaction_policies", || {
+        ensure_writes_allowed()?;
+        ensure_admin()?;
+
+        let mut validator = Validator::default();
+        for (index, policy) in policies.iter().enumerate() {
+            validator.check(&format!("policies[{}]", index), check_fields(policy));
+        }
+        validator.finish()?;
+
+        REDACTION_POLICIES
+            .with(|cell| cell.borrow_mut().set(RedactionPolicies(policies)))
+            .expect("Cannot set the redaction policies");
+        Ok(())
+    })
+}
+
+//Retrieves the policy table that decides which fields each audience can see
+#[ic_cdk::query]
+fn get_redaction_policies() -> Vec<FieldPolicy> {
+    REDACTION_POLICIES.with(|policies| policies.borrow().get().0.clone())
+}
+
+//Lists the fields of a record kind that the caller can not see
+#[ic_cdk::query]
+fn get_my_hidden_fields(record: RecordKind) -> Vec<String> {
+    hidden_fields(caller_audience(), record)
+}
@@ -0,0 +1,419 @@
+// Backup and restore of the whole stable state, as a versioned snapshot streamed in chunks.
+//
+// A snapshot is the contents of every MemoryId, cut into chunks of at most CHUNK_SIZE bytes.
+// Each chunk carries the SHA-256 of its data. The checksum of the whole snapshot chains the
+// chunk checksums: it starts as 32 zero bytes and becomes SHA-256(checksum || chunk checksum)
+// for each chunk in order. It is computed when the snapshot begins and given in its manifest, so
+// that a restore is checked against the state it was taken from. Writes are refused while a
+// snapshot is taken or restored
+use crate::certification::certify_all;
+use crate::migrations::CURRENT_SCHEMA_VERSION;
+use crate::validation::invalid_field;
+use crate::{
+    ensure_admin, reload_stable_state, reset_stable_structure, Error, Memory, DIAGNOSIS_STORAGE,
+    DOCTOR_STORAGE, MEMORY_IDS, MEMORY_MANAGER, PATIENT_STORAGE, ROOM_STORAGE, SCHEMA_VERSION,
+    SNAPSHOT_STATE, SNAPSHOT_STATE_MEMORY_ID, STAFF,
+};
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::{Memory as _, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+//Bump this whenever the layout of a snapshot changes
+const SNAPSHOT_FORMAT_VERSION: u32 = 2;
+
+//Largest chunk, small enough for a reply with room to spare
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+//The size of one MemoryId in a snapshot
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SnapshotMemory {
+    memory_id: u8,
+    size: u64, //In bytes
+}
+
+//Describes a snapshot. It is needed to restore it
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SnapshotManifest {
+    format_version: u32,
+    schema_version: u32,
+    taken_on: u64,
+    chunk_size: u64,
+    chunk_count: u64,
+    memories: Vec<SnapshotMemory>,
+    checksum: Vec<u8>, //Chained checksum of every chunk
+}
+
+impl SnapshotManifest {
+    //The MemoryId and offset of every chunk, in order
+    fn chunks(&self) -> Vec<(u8, u64, u64)> {
+        let mut chunks = Vec::new();
+        for memory in &self.memories {
+            let mut offset = 0;
+            while offset < memory.size {
+                let length = CHUNK_SIZE.min(memory.size - offset);
+                chunks.push((memory.memory_id, offset, length));
+                offset += length;
+            }
+        }
+        chunks
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize)]
+pub(crate) struct SnapshotChunk {
+    index: u64,
+    memory_id: u8,
+    offset: u64,
+    data: Vec<u8>,
+    checksum: Vec<u8>, //SHA-256 of the data
+}
+
+//Whether a snapshot is being taken or restored
+#[derive(CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) enum SnapshotState {
+    #[default]
+    Idle,
+    Exporting {
+        manifest: SnapshotManifest,
+    },
+    Restoring {
+        manifest: SnapshotManifest,
+        next_chunk: u64,
+        checksum: Vec<u8>, //Chained checksum of the chunks restored so far
+    },
+}
+
+impl Storable for SnapshotState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+fn memory(id: u8) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id)))
+}
+
+fn state() -> SnapshotState {
+    SNAPSHOT_STATE.with(|state| state.borrow().get().clone())
+}
+
+fn set_state(new_state: SnapshotState) {
+    SNAPSHOT_STATE
+        .with(|state| state.borrow_mut().set(new_state))
+        .expect("Cannot save the snapshot state");
+}
+
+fn read_chunk(memory_id: u8, offset: u64, length: u64) -> Vec<u8> {
+    let mut data = vec![0; length as usize];
+    memory(memory_id).read(offset, &mut data);
+    data
+}
+
+fn chain(checksum: &[u8], chunk_checksum: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(checksum);
+    hasher.update(chunk_checksum);
+    hasher.finalize().to_vec()
+}
+
+//Refuses writes while a snapshot is taken, so that it is consistent, or restored
+pub(crate) fn ensure_no_snapshot() -> Result<(), Error> {
+    match state() {
+        SnapshotState::Idle => Ok(()),
+        SnapshotState::Exporting { .. } => Err(Error::ServiceUnavailable {
+            msg: "The canister is read-only while a snapshot is taken".to_string(),
+            expected_end: None,
+        }),
+        SnapshotState::Restoring { .. } => Err(Error::ServiceUnavailable {
+            msg: "The canister is read-only while a snapshot is restored".to_string(),
+            expected_end: None,
+        }),
+    }
+}
+
+//A snapshot can only be restored into a canister that holds no records yet
+fn ensure_fresh() -> Result<(), Error> {
+    let is_fresh = PATIENT_STORAGE.with(|s| s.borrow().is_empty())
+        && DOCTOR_STORAGE.with(|s| s.borrow().is_empty())
+        && ROOM_STORAGE.with(|s| s.borrow().is_empty())
+        && DIAGNOSIS_STORAGE.with(|s| s.borrow().is_empty())
+        && STAFF.with(|s| s.borrow().is_empty());
+    if is_fresh {
+        Ok(())
+    } else {
+        Err(invalid_field(
+            "manifest",
+            "A snapshot can only be restored into a new canister",
+        ))
+    }
+}
+
+//Starts a snapshot and refuses writes until it is finished, so that every chunk is consistent
+#[ic_cdk::update]
+fn begin_snapshot() -> Result<SnapshotManifest, Error> {
+    ensure_admin()?;
+    ensure_no_snapshot()?;
+
+    let memories: Vec<SnapshotMemory> = MEMORY_IDS
+        .iter()
+        .filter(|id| **id != SNAPSHOT_STATE_MEMORY_ID)
+        .map(|id| SnapshotMemory {
+            memory_id: *id,
+            size: memory(*id).size() * WASM_PAGE_SIZE,
+        })
+        .collect();
+    let mut manifest = SnapshotManifest {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        schema_version: SCHEMA_VERSION.with(|version| *version.borrow().get()),
+        taken_on: time(),
+        chunk_size: CHUNK_SIZE,
+        chunk_count: 0,
+        memories,
+        checksum: Vec::new(),
+    };
+    let chunks = manifest.chunks();
+    manifest.chunk_count = chunks.len() as u64;
+    // Writes are refused from here on, so the chunks served later have the same data
+    manifest.checksum =
+        chunks
+            .into_iter()
+            .fold(vec![0; 32], |checksum, (memory_id, offset, length)| {
+                let data = read_chunk(memory_id, offset, length);
+                chain(&checksum, &Sha256::digest(&data))
+            });
+
+    set_state(SnapshotState::Exporting {
+        manifest: manifest.clone(),
+    });
+    Ok(manifest)
+}
+
+//Reads a chunk of the snapshot that is being taken
+#[ic_cdk::query]
+fn get_snapshot_chunk(index: u64) -> Result<SnapshotChunk, Error> {
+    ensure_admin()?;
+    let SnapshotState::Exporting { manifest } = state() else {
+        return Err(Error::NotFound {
+            msg: "No snapshot is being taken".to_string(),
+        });
+    };
+
+    let (memory_id, offset, length) =
+        manifest
+            .chunks()
+            .get(index as usize)
+            .copied()
+            .ok_or_else(|| Error::NotFound {
+                msg: format!("The snapshot has no chunk {}", index),
+            })?;
+    let data = read_chunk(memory_id, offset, length);
+
+    Ok(SnapshotChunk {
+        index,
+        memory_id,
+        offset,
+        checksum: Sha256::digest(&data).to_vec(),
+        data,
+    })
+}
+
+//Ends the snapshot, so that writes are accepted again
+#[ic_cdk::update]
+fn finish_snapshot() -> Result<(), Error> {
+    ensure_admin()?;
+    let SnapshotState::Exporting { .. } = state() else {
+        return Err(Error::NotFound {
+            msg: "No snapshot is being taken".to_string(),
+        });
+    };
+
+    set_state(SnapshotState::Idle);
+    Ok(())
+}
+
+//Starts restoring a snapshot into a new canister. Writes are refused until the restore is finished
+#[ic_cdk::update]
+fn begin_restore(manifest: SnapshotManifest) -> Result<(), Error> {
+    ensure_admin()?;
+    ensure_no_snapshot()?;
+    ensure_fresh()?;
+
+    if manifest.format_version != SNAPSHOT_FORMAT_VERSION {
+        return Err(invalid_field(
+            "format_version",
+            &format!(
+                "Only snapshots of format version {} can be restored",
+                SNAPSHOT_FORMAT_VERSION
+            ),
+        ));
+    }
+    if manifest.schema_version != CURRENT_SCHEMA_VERSION {
+        return Err(invalid_field(
+            "schema_version",
+            &format!(
+                "Only snapshots of schema version {} can be restored. Upgrade the canister the snapshot was taken from first",
+                CURRENT_SCHEMA_VERSION
+            ),
+        ));
+    }
+    if manifest.chunk_size != CHUNK_SIZE || manifest.chunk_count != manifest.chunks().len() as u64 {
+        return Err(invalid_field(
+            "chunk_count",
+            "The chunks do not match the sizes of the memories",
+        ));
+    }
+    if manifest.checksum.len() != 32 {
+        return Err(invalid_field(
+            "checksum",
+            "The checksum must be a SHA-256 hash",
+        ));
+    }
+    if manifest.memories.iter().any(|memory| {
+        !MEMORY_IDS.contains(&memory.memory_id) || memory.memory_id == SNAPSHOT_STATE_MEMORY_ID
+    }) {
+        return Err(invalid_field(
+            "memories",
+            "The snapshot holds a memory this canister does not use",
+        ));
+    }
+
+    set_state(SnapshotState::Restoring {
+        manifest,
+        next_chunk: 0,
+        checksum: vec![0; 32],
+    });
+    Ok(())
+}
+
+//Writes the next chunk of the snapshot being restored, after checking its checksum
+#[ic_cdk::update]
+fn restore_snapshot_chunk(chunk: SnapshotChunk) -> Result<(), Error> {
+    ensure_admin()?;
+    let SnapshotState::Restoring {
+        manifest,
+        next_chunk,
+        checksum,
+    } = state()
+    else {
+        return Err(Error::NotFound {
+            msg: "No snapshot is being restored".to_string(),
+        });
+    };
+
+    if chunk.index != next_chunk {
+        return Err(invalid_field(
+            "index",
+            &format!("Chunk {} is expected next", next_chunk),
+        ));
+    }
+    let expected = manifest.chunks().get(next_chunk as usize).copied();
+    if expected != Some((chunk.memory_id, chunk.offset, chunk.data.len() as u64)) {
+        return Err(invalid_field(
+            "data",
+            "The chunk does not match the manifest",
+        ));
+    }
+    if Sha256::digest(&chunk.data).as_slice() != chunk.checksum.as_slice() {
+        return Err(invalid_field("checksum", "The chunk is corrupted"));
+    }
+
+    let memory = memory(chunk.memory_id);
+    let end = chunk.offset + chunk.data.len() as u64;
+    let pages = end.div_ceil(WASM_PAGE_SIZE);
+    if memory.size() < pages && memory.grow(pages - memory.size()) == -1 {
+        ic_cdk::trap("Cannot grow the stable memory to restore the snapshot");
+    }
+    memory.write(chunk.offset, &chunk.data);
+
+    set_state(SnapshotState::Restoring {
+        checksum: chain(&checksum, &chunk.checksum),
+        manifest,
+        next_chunk: next_chunk + 1,
+    });
+    Ok(())
+}
+
+//Checks the restored chunks against the checksum in the manifest and loads them, accepting writes
+//again
+#[ic_cdk::update]
+fn finish_restore() -> Result<(), Error> {
+    ensure_admin()?;
+    let SnapshotState::Restoring {
+        manifest,
+        next_chunk,
+        checksum,
+    } = state()
+    else {
+        return Err(Error::NotFound {
+            msg: "No snapshot is being restored".to_string(),
+        });
+    };
+
+    if next_chunk != manifest.chunk_count {
+        return Err(invalid_field(
+            "chunk_count",
+            &format!(
+                "Only {} of the {} chunks have been restored",
+                next_chunk, manifest.chunk_count
+            ),
+        ));
+    }
+    if manifest.checksum != checksum {
+        return Err(invalid_field(
+            "checksum",
+            "The restored chunks do not match the snapshot",
+        ));
+    }
+
+    reload_stable_state();
+    certify_all();
+    set_state(SnapshotState::Idle);
+    Ok(())
+}
+
+//Abandons the restore, emptying the stable structures its chunks were written to, so that the
+//canister is fresh again and another snapshot can be restored
+#[ic_cdk::update]
+fn abort_restore() -> Result<(), Error> {
+    ensure_admin()?;
+    let SnapshotState::Restoring {
+        manifest,
+        next_chunk,
+        ..
+    } = state()
+    else {
+        return Err(Error::NotFound {
+            msg: "No snapshot is being restored".to_string(),
+        });
+    };
+
+    let mut written: Vec<u8> = manifest
+        .chunks()
+        .iter()
+        .take(next_chunk as usize)
+        .map(|(memory_id, _, _)| *memory_id)
+        .collect();
+    written.dedup();
+    for memory_id in written {
+        reset_stable_structure(memory_id);
+    }
+    certify_all();
+    set_state(SnapshotState::Idle);
+    Ok(())
+}
+
+//Shows whether a snapshot is being taken or restored, and how far a restore has got
+#[ic_cdk::query]
+fn get_snapshot_state() -> Result<SnapshotState, Error> {
+    ensure_admin()?;
+    Ok(state())
+}
@@ -0,0 +1,746 @@
+// Field validation shared by the add, update and patch endpoints
+use crate::contacts::RelatedPersonPayload;
+use crate::{
+    Address, DoctorPatch, DoctorPayLoad, Error, PatientPatch, PatientPayLoad, RoomPatch,
+    RoomPayload, ETHNICITY_CODES,
+};
+use candid::CandidType;
+use ic_cdk::api::time;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+const EARLIEST_BIRTH_YEAR: i64 = 1900;
+
+//Most review notes kept on a patient, and the longest note in bytes, so that the notes always
+//fit in a stored Patient
+const MAX_REVIEW_NOTES: usize = 8;
+const MAX_REVIEW_NOTE_LENGTH: usize = 80;
+
+//Longest values accepted, in bytes, so that every record fits in the MAX_SIZE of its stable map.
+//Email addresses are bounded by check_email and phone numbers by check_phone_number
+pub(crate) const MAX_NAME_LENGTH: usize = 100;
+pub(crate) const MAX_ADDRESS_LINE_LENGTH: usize = 100; //Street, city and region
+pub(crate) const MAX_POSTAL_CODE_LENGTH: usize = 20;
+pub(crate) const MAX_TEXT_LENGTH: usize = 200; //Speciality and room location
+pub(crate) const MAX_CODE_LENGTH: usize = 32; //Ethnicity codes
+pub(crate) const MAX_EQUIPMENT: usize = 20;
+pub(crate) const MAX_EQUIPMENT_LENGTH: usize = 50;
+pub(crate) const MAX_CLINICAL_TEXT_LENGTH: usize = 500; //Treatment and medication
+
+//Describes why a single field was rejected
+#[derive(CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct FieldError {
+    field: String,
+    msg: String,
+}
+
+impl std::fmt::Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.msg)
+    }
+}
+
+//A calendar date in the proleptic Gregorian calendar
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Date {
+    year: i64,
+    month: u32,
+    day: u32,
+}
+
+impl Date {
+    //Converts a timestamp in nanoseconds since the Unix epoch to a date
+    pub(crate) fn from_timestamp(timestamp: u64) -> Self {
+        // Days-to-civil conversion by Howard Hinnant
+        let days = (timestamp / NANOS_PER_DAY) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        Date { year, month, day }
+    }
+
+    //Parses a date in the DD-MM-YYYY format, checking that the day exists in that month
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = value.split('-').collect();
+        let [day, month, year] = parts.as_slice() else {
+            return Err("Dates must use the DD-MM-YYYY format".to_string());
+        };
+        if day.len() != 2 || month.len() != 2 || year.len() != 4 {
+            return Err("Dates must use the DD-MM-YYYY format".to_string());
+        }
+
+        let parse_number = |part: &str| {
+            if part.chars().all(|c| c.is_ascii_digit()) {
+                part.parse::<u32>().ok()
+            } else {
+                None
+            }
+        };
+        let (Some(day), Some(month), Some(year)) =
+            (parse_number(day), parse_number(month), parse_number(year))
+        else {
+            return Err("Dates must only contain digits and dashes".to_string());
+        };
+
+        let year = i64::from(year);
+        if !(1..=12).contains(&month) {
+            return Err(format!("{} is not a valid month", month));
+        }
+        if day == 0 || day > days_in_month(year, month) {
+            return Err(format!(
+                "{:02}-{:04} does not have a day {}",
+                month, year, day
+            ));
+        }
+
+        Ok(Date { year, month, day })
+    }
+
+    //Number of whole years between this date and a later one
+    pub(crate) fn years_until(&self, later: &Date) -> u32 {
+        let mut years = later.year - self.year;
+        if (later.month, later.day) < (self.month, self.day) {
+            years -= 1;
+        }
+        years.max(0) as u32
+    }
+
+    //Converts the date to the timestamp of its midnight (UTC), in nanoseconds since the Unix epoch
+    pub(crate) fn to_timestamp(self) -> u64 {
+        // Civil-to-days conversion by Howard Hinnant
+        let year = self.year - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let shifted_month = i64::from((self.month + 9) % 12);
+        let day_of_year = (153 * shifted_month + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        (days.max(0) as u64) * NANOS_PER_DAY
+    }
+
+    //Formats the date as YYYY-MM-DD (ISO 8601), as FHIR expects
+    pub(crate) fn to_iso(self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+//Age in whole years today for a DD-MM-YYYY date of birth, or 0 if it cannot be parsed
+pub(crate) fn current_age(date_of_birth: &str) -> u32 {
+    Date::parse(date_of_birth)
+        .map(|date_of_birth| date_of_birth.years_until(&Date::from_timestamp(time())))
+        .unwrap_or_default()
+}
+
+// Per-field validators. Each returns a message describing the problem.
+
+pub(crate) fn check_required(value: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err("This field is required".to_string());
+    }
+    Ok(())
+}
+
+//Lengths are counted in bytes, as that is what the stored record is bounded by
+pub(crate) fn check_length(value: &str, max: usize) -> Result<(), String> {
+    if value.len() > max {
+        return Err(format!(
+            "can not be longer than {} bytes, but is {}",
+            max,
+            value.len()
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_equipment(equipment: &[String]) -> Result<(), String> {
+    if equipment.len() > MAX_EQUIPMENT {
+        return Err(format!(
+            "A room can have at most {} pieces of equipment",
+            MAX_EQUIPMENT
+        ));
+    }
+    for item in equipment {
+        check_required(item)?;
+        check_length(item, MAX_EQUIPMENT_LENGTH).map_err(|msg| format!("Each item {}", msg))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn check_date_of_birth(value: &str) -> Result<(), String> {
+    let date_of_birth = Date::parse(value)?;
+    if date_of_birth > Date::from_timestamp(time()) {
+        return Err("The date of birth can not be in the future".to_string());
+    }
+    if date_of_birth.year < EARLIEST_BIRTH_YEAR {
+        return Err(format!(
+            "The date of birth can not be before {}",
+            EARLIEST_BIRTH_YEAR
+        ));
+    }
+    Ok(())
+}
+
+//Phone numbers must be in E.164 format: a plus sign followed by up to 15 digits
+pub(crate) fn check_phone_number(value: &str) -> Result<(), String> {
+    let Some(digits) = value.strip_prefix('+') else {
+        return Err("Phone numbers must start with + and the country code".to_string());
+    };
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err("Phone numbers must only contain digits after the +".to_string());
+    }
+    if digits.starts_with('0') {
+        return Err("Country codes can not start with 0".to_string());
+    }
+    if !(8..=15).contains(&digits.len()) {
+        return Err("Phone numbers must have between 8 and 15 digits".to_string());
+    }
+    Ok(())
+}
+
+//Checks the address against the dot-atom form of RFC 5322 with a DNS domain name
+pub(crate) fn check_email(value: &str) -> Result<(), String> {
+    let invalid = || Err(format!("{} is not a valid email address", value));
+
+    if value.len() > 254 {
+        return Err("Email addresses can not be longer than 254 characters".to_string());
+    }
+    let Some((local, domain)) = value.rsplit_once('@') else {
+        return invalid();
+    };
+
+    let local_is_valid = !local.is_empty()
+        && local.len() <= 64
+        && local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~-".contains(c))
+        });
+
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_is_valid = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+        && labels
+            .last()
+            .is_some_and(|tld| tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_alphabetic()));
+
+    if !local_is_valid || !domain_is_valid {
+        return invalid();
+    }
+    Ok(())
+}
+
+//Ethnicity must be one of the configured ethnicity codes
+pub(crate) fn check_ethnicity(value: &str) -> Result<(), String> {
+    let is_known = ETHNICITY_CODES.with(|codes| {
+        codes
+            .borrow()
+            .get()
+            .0
+            .iter()
+            .any(|ethnicity| ethnicity.code == value)
+    });
+    if !is_known {
+        return Err(format!("{} is not a known ethnicity code", value));
+    }
+    Ok(())
+}
+
+//Street, city and an ISO 3166-1 alpha-2 country code are required
+pub(crate) fn check_address(address: &Address) -> Result<(), String> {
+    if address.street.trim().is_empty() || address.city.trim().is_empty() {
+        return Err("The street and city of the address are required".to_string());
+    }
+    for (part, value) in [
+        ("street", &address.street),
+        ("city", &address.city),
+        ("region", &address.region),
+    ] {
+        check_length(value, MAX_ADDRESS_LINE_LENGTH)
+            .map_err(|msg| format!("The {} {}", part, msg))?;
+    }
+    check_length(&address.postal_code, MAX_POSTAL_CODE_LENGTH)
+        .map_err(|msg| format!("The postal code {}", msg))?;
+    if address.country.len() != 2 || !address.country.chars().all(|c| c.is_ascii_uppercase()) {
+        return Err("The country must be a two letter ISO 3166-1 code, e.g. KE".to_string());
+    }
+    Ok(())
+}
+
+//Collects the errors of every field so they can be reported together
+#[derive(Default)]
+pub(crate) struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub(crate) fn check(&mut self, field: &str, result: Result<(), String>) -> &mut Self {
+        if let Err(msg) = result {
+            self.errors.push(FieldError {
+                field: field.to_string(),
+                msg,
+            });
+        }
+        self
+    }
+
+    //Reports a value that is too long to be stored
+    pub(crate) fn limit(&mut self, field: &str, value: &str, max: usize) -> &mut Self {
+        self.check(
+            field,
+            check_length(value, max).map_err(|msg| format!("This field {}", msg)),
+        )
+    }
+
+    pub(crate) fn limit_provided(
+        &mut self,
+        field: &str,
+        value: Option<&str>,
+        max: usize,
+    ) -> &mut Self {
+        match value {
+            Some(value) => self.limit(field, value, max),
+            None => self,
+        }
+    }
+
+    //Runs the check only when a value was provided
+    pub(crate) fn check_provided<T: ?Sized>(
+        &mut self,
+        field: &str,
+        value: Option<&T>,
+        check: impl Fn(&T) -> Result<(), String>,
+    ) -> &mut Self {
+        match value {
+            Some(value) => self.check(field, check(value)),
+            None => self,
+        }
+    }
+
+    pub(crate) fn finish(&mut self) -> Result<(), Error> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        Err(Error::ValidationFailed {
+            msg: "Some of the fields are not valid".to_string(),
+            fields: std::mem::take(&mut self.errors),
+        })
+    }
+}
+
+//Places the invalid fields of an error under a parent field, such as an operation of a batch
+pub(crate) fn nest_fields(parent: &str, error: Error) -> Error {
+    match error {
+        Error::ValidationFailed { msg, fields } => Error::ValidationFailed {
+            msg,
+            fields: fields
+                .into_iter()
+                .map(|error| FieldError {
+                    field: format!("{}.{}", parent, error.field),
+                    msg: error.msg,
+                })
+                .collect(),
+        },
+        error => error,
+    }
+}
+
+//Builds the error for a single invalid field
+pub(crate) fn invalid_field(field: &str, msg: &str) -> Error {
+    Error::ValidationFailed {
+        msg: "Some of the fields are not valid".to_string(),
+        fields: vec![FieldError {
+            field: field.to_string(),
+            msg: msg.to_string(),
+        }],
+    }
+}
+
+fn check_optional_email(value: &str) -> Result<(), String> {
+    if value.is_empty() {
+        return Ok(());
+    }
+    check_email(value)
+}
+
+pub(crate) fn validate_patient_payload(payload: &PatientPayLoad) -> Result<(), Error> {
+    Validator::default()
+        .check("name", check_required(&payload.name))
+        .limit("name", &payload.name, MAX_NAME_LENGTH)
+        .check("date_of_birth", check_date_of_birth(&payload.date_of_birth))
+        .check("ethnicity", check_ethnicity(&payload.ethnicity))
+        .check("address", check_address(&payload.address))
+        .check("phone_number", check_phone_number(&payload.phone_number))
+        .check("email", check_optional_email(&payload.email))
+        .finish()
+}
+
+//Imported records often have no ethnicity, so it is flagged for review rather than required
+pub(crate) fn validate_imported_patient(payload: &PatientPayLoad) -> Result<(), Error> {
+    Validator::default()
+        .check("name", check_required(&payload.name))
+        .limit("name", &payload.name, MAX_NAME_LENGTH)
+        .check("date_of_birth", check_date_of_birth(&payload.date_of_birth))
+        .check_provided(
+            "ethnicity",
+            Some(payload.ethnicity.as_str()).filter(|ethnicity| !ethnicity.is_empty()),
+            check_ethnicity,
+        )
+        .check("address", check_address(&payload.address))
+        .check("phone_number", check_phone_number(&payload.phone_number))
+        .check("email", check_optional_email(&payload.email))
+        .finish()
+}
+
+pub(crate) fn validate_patient_patch(patch: &PatientPatch) -> Result<(), Error> {
+    Validator::default()
+        .check_provided("name", patch.name.as_deref(), check_required)
+        .limit_provided("name", patch.name.as_deref(), MAX_NAME_LENGTH)
+        .check_provided(
+            "date_of_birth",
+            patch.date_of_birth.as_deref(),
+            check_date_of_birth,
+        )
+        .check_provided("ethnicity", patch.ethnicity.as_deref(), check_ethnicity)
+        .check_provided("address", patch.address.as_ref(), check_address)
+        .check_provided(
+            "phone_number",
+            patch.phone_number.as_deref(),
+            check_phone_number,
+        )
+        .check_provided("email", patch.email.as_deref(), check_optional_email)
+        .finish()
+}
+
+pub(crate) fn validate_doctor_payload(payload: &DoctorPayLoad) -> Result<(), Error> {
+    Validator::default()
+        .check("name", check_required(&payload.name))
+        .limit("name", &payload.name, MAX_NAME_LENGTH)
+        .check("email", check_email(&payload.email))
+        .check("phone_number", check_phone_number(&payload.phone_number))
+        .check("speciality", check_required(&payload.speciality))
+        .limit("speciality", &payload.speciality, MAX_TEXT_LENGTH)
+        .finish()
+}
+
+pub(crate) fn validate_doctor_patch(patch: &DoctorPatch) -> Result<(), Error> {
+    Validator::default()
+        .check_provided("name", patch.name.as_deref(), check_required)
+        .limit_provided("name", patch.name.as_deref(), MAX_NAME_LENGTH)
+        .check_provided("email", patch.email.as_deref(), check_email)
+        .check_provided(
+            "phone_number",
+            patch.phone_number.as_deref(),
+            check_phone_number,
+        )
+        .check_provided("speciality", patch.speciality.as_deref(), check_required)
+        .limit_provided("speciality", patch.speciality.as_deref(), MAX_TEXT_LENGTH)
+        .finish()
+}
+
+pub(crate) fn validate_room_payload(payload: &RoomPayload) -> Result<(), Error> {
+    Validator::default()
+        .check("name", check_required(&payload.name))
+        .limit("name", &payload.name, MAX_NAME_LENGTH)
+        .check("location", check_required(&payload.location))
+        .limit("location", &payload.location, MAX_TEXT_LENGTH)
+        .finish()
+}
+
+pub(crate) fn validate_room_patch(patch: &RoomPatch) -> Result<(), Error> {
+    Validator::default()
+        .check_provided("name", patch.name.as_deref(), check_required)
+        .limit_provided("name", patch.name.as_deref(), MAX_NAME_LENGTH)
+        .check_provided("location", patch.location.as_deref(), check_required)
+        .limit_provided("location", patch.location.as_deref(), MAX_TEXT_LENGTH)
+        .finish()
+}
+
+pub(crate) fn validate_contact_payload(payload: &RelatedPersonPayload) -> Result<(), Error> {
+    Validator::default()
+        .check("name", check_required(&payload.name))
+        .limit("name", &payload.name, MAX_NAME_LENGTH)
+        .check("phone_number", check_phone_number(&payload.phone_number))
+        .check("email", check_optional_email(&payload.email))
+        .finish()
+}
+
+//Treatment and medication are required, and bounded so that the diagnosis fits when stored
+pub(crate) fn validate_clinical_text(treatment: &str, medication: &str) -> Result<(), Error> {
+    Validator::default()
+        .check("treatment", check_required(treatment))
+        .limit("treatment", treatment, MAX_CLINICAL_TEXT_LENGTH)
+        .check("medication", check_required(medication))
+        .limit("medication", medication, MAX_CLINICAL_TEXT_LENGTH)
+        .finish()
+}
+
+//Shortens long review notes and replaces the notes over the limit with a count of them
+pub(crate) fn cap_review_notes(review_notes: &mut Vec<String>) {
+    for note in review_notes.iter_mut() {
+        if note.len() > MAX_REVIEW_NOTE_LENGTH {
+            let mut end = MAX_REVIEW_NOTE_LENGTH - 3;
+            while !note.is_char_boundary(end) {
+                end -= 1;
+            }
+            note.truncate(end);
+            note.push_str("...");
+        }
+    }
+    if review_notes.len() > MAX_REVIEW_NOTES {
+        let left_out = review_notes.len() - (MAX_REVIEW_NOTES - 1);
+        review_notes.truncate(MAX_REVIEW_NOTES - 1);
+        review_notes.push(format!("{} more values need a review", left_out));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i64, month: u32, day: u32) -> Date {
+        Date { year, month, day }
+    }
+
+    #[test]
+    fn parses_dates_in_the_dd_mm_yyyy_format() {
+        assert!(Date::parse("29-02-2024") == Ok(date(2024, 2, 29)));
+        assert!(Date::parse("31-12-1999") == Ok(date(1999, 12, 31)));
+    }
+
+    #[test]
+    fn rejects_dates_that_do_not_exist() {
+        assert!(Date::parse("29-02-2023").is_err());
+        assert!(Date::parse("29-02-1900").is_err());
+        assert!(Date::parse("31-04-2024").is_err());
+        assert!(Date::parse("00-01-2024").is_err());
+        assert!(Date::parse("01-13-2024").is_err());
+    }
+
+    #[test]
+    fn rejects_dates_in_other_formats() {
+        for value in [
+            "2024-02-29",
+            "1-2-2024",
+            "01/02/2024",
+            "+1-02-2024",
+            "aa-02-2024",
+            "",
+        ] {
+            assert!(Date::parse(value).is_err(), "{} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn converts_between_dates_and_timestamps() {
+        assert!(Date::from_timestamp(0) == date(1970, 1, 1));
+        assert!(Date::from_timestamp(951_782_400 * 1_000_000_000) == date(2000, 2, 29));
+        for day in [date(1970, 1, 1), date(2000, 2, 29), date(2024, 12, 31)] {
+            assert!(Date::from_timestamp(day.to_timestamp()) == day);
+        }
+        assert_eq!(date(2000, 2, 29).to_iso(), "2000-02-29");
+    }
+
+    #[test]
+    fn counts_whole_years() {
+        let birth = date(2000, 6, 15);
+        assert_eq!(birth.years_until(&date(2024, 6, 14)), 23);
+        assert_eq!(birth.years_until(&date(2024, 6, 15)), 24);
+        assert_eq!(birth.years_until(&date(1999, 1, 1)), 0);
+    }
+
+    #[test]
+    fn accepts_e164_phone_numbers() {
+        assert!(check_phone_number("+254712345678").is_ok());
+        assert!(check_phone_number("+14155552671").is_ok());
+    }
+
+    #[test]
+    fn rejects_other_phone_numbers() {
+        for value in [
+            "0712345678",
+            "+0712345678",
+            "+254 712 345 678",
+            "+1234567",
+            "+1234567890123456",
+            "+",
+        ] {
+            assert!(check_phone_number(value).is_err(), "{} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn accepts_valid_email_addresses() {
+        for value in [
+            "jane@example.com",
+            "jane.doe+ward-3@mail.example.co.ke",
+            "a@b.io",
+        ] {
+            assert!(check_email(value).is_ok(), "{} was rejected", value);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_email_addresses() {
+        for value in [
+            "jane",
+            "@example.com",
+            "jane@",
+            "jane@example",
+            "jane..doe@example.com",
+            "jane@-example.com",
+            "jane@example.c0m",
+            "ja ne@example.com",
+        ] {
+            assert!(check_email(value).is_err(), "{} was accepted", value);
+        }
+        assert!(check_email(&format!("{}@example.com", "a".repeat(65))).is_err());
+    }
+
+    #[test]
+    fn requires_a_country_code_in_addresses() {
+        let mut address = Address {
+            street: "1 Main St".to_string(),
+            city: "Nairobi".to_string(),
+            country: "KE".to_string(),
+            ..Default::default()
+        };
+        assert!(check_address(&address).is_ok());
+        address.country = "Kenya".to_string();
+        assert!(check_address(&address).is_err());
+    }
+
+    #[test]
+    fn caps_the_count_and_length_of_review_notes() {
+        let mut review_notes: Vec<String> = (0..20).map(|i| format!("note {}", i)).collect();
+        review_notes[0] = "é".repeat(60);
+        cap_review_notes(&mut review_notes);
+
+        assert_eq!(review_notes.len(), MAX_REVIEW_NOTES);
+        assert!(review_notes[0].len() <= MAX_REVIEW_NOTE_LENGTH);
+        assert!(review_notes[0].ends_with("..."));
+        assert_eq!(review_notes[6], "note 6");
+        assert_eq!(review_notes[7], "13 more values need a review");
+    }
+
+    fn longest(max: usize) -> String {
+        "x".repeat(max)
+    }
+
+    fn fits<T: ic_stable_structures::BoundedStorable>(record: &T) -> bool {
+        record.to_bytes().len() <= T::MAX_SIZE as usize
+    }
+
+    #[test]
+    fn records_with_every_field_at_its_limit_fit_when_stored() {
+        let archive = Some(crate::Archive {
+            archived_by: candid::Principal::anonymous(),
+            archived_on: u64::MAX,
+        });
+        let patient = crate::Patient {
+            id: u64::MAX,
+            name: longest(MAX_NAME_LENGTH),
+            date_of_birth: "01-01-2000".to_string(),
+            ethnicity: longest(MAX_CODE_LENGTH),
+            address: Address {
+                street: longest(MAX_ADDRESS_LINE_LENGTH),
+                city: longest(MAX_ADDRESS_LINE_LENGTH),
+                region: longest(MAX_ADDRESS_LINE_LENGTH),
+                postal_code: longest(MAX_POSTAL_CODE_LENGTH),
+                country: "KE".to_string(),
+            },
+            phone_number: format!("+{}", "9".repeat(15)),
+            email: longest(254),
+            mrn: Some(longest(64)),
+            merged_into: Some(u64::MAX),
+            archived: archive.clone(),
+            version: u64::MAX,
+            review_notes: vec![longest(MAX_REVIEW_NOTE_LENGTH); MAX_REVIEW_NOTES],
+            ..Default::default()
+        };
+        assert!(fits(&patient));
+
+        let doctor = crate::Doctor {
+            name: longest(MAX_NAME_LENGTH),
+            email: longest(254),
+            phone_number: format!("+{}", "9".repeat(15)),
+            speciality: longest(MAX_TEXT_LENGTH),
+            archived: archive.clone(),
+            ..Default::default()
+        };
+        assert!(fits(&doctor));
+
+        let room = crate::Room {
+            name: longest(MAX_NAME_LENGTH),
+            location: longest(MAX_TEXT_LENGTH),
+            equipment: vec![longest(MAX_EQUIPMENT_LENGTH); MAX_EQUIPMENT],
+            archived: archive,
+            ..Default::default()
+        };
+        assert!(fits(&room));
+
+        let diagnosis = crate::Diagnosis {
+            treatment: longest(MAX_CLINICAL_TEXT_LENGTH),
+            medication: longest(MAX_CLINICAL_TEXT_LENGTH),
+            ..Default::default()
+        };
+        assert!(fits(&diagnosis));
+
+        let contact = crate::contacts::RelatedPerson {
+            name: longest(MAX_NAME_LENGTH),
+            phone_number: format!("+{}", "9".repeat(15)),
+            email: longest(254),
+            ..Default::default()
+        };
+        assert!(fits(&contact));
+    }
+
+    #[test]
+    fn reports_values_that_are_too_long() {
+        assert!(check_length(&longest(MAX_NAME_LENGTH), MAX_NAME_LENGTH).is_ok());
+        assert!(check_length(&longest(MAX_NAME_LENGTH + 1), MAX_NAME_LENGTH).is_err());
+        // Counted in bytes, not characters
+        assert!(check_length(&"é".repeat(MAX_NAME_LENGTH), MAX_NAME_LENGTH).is_err());
+        assert!(check_equipment(&vec!["Bed".to_string(); MAX_EQUIPMENT + 1]).is_err());
+        assert!(check_equipment(&[longest(MAX_EQUIPMENT_LENGTH + 1)]).is_err());
+    }
+
+    #[test]
+    fn nests_invalid_fields_under_their_parent() {
+        let error = nest_fields("operations[2]", invalid_field("name", "Required"));
+        assert_eq!(
+            error.message(),
+            "Some of the fields are not valid: operations[2].name: Required"
+        );
+    }
+}